@@ -0,0 +1,153 @@
+//! Latency of MMR proofs with and without appends running concurrently.
+//!
+//! Needs a Postgres database in DB_CONNECTION_STRING. Headers and recorded roots are written to a throwaway
+//! fossil_bench schema, dropped at the end.
+
+use accumulators::store::memory::InMemoryStore;
+use criterion::{criterion_group, criterion_main, Criterion};
+use sqlx::{
+    postgres::{PgConnectOptions, PgPoolOptions},
+    Pool, Postgres,
+};
+use std::str::FromStr;
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+};
+
+use fossil_headers_db::{
+    config::MmrConfig,
+    db,
+    fossil_mmr::{self, MmrSet},
+    metrics::Metrics,
+};
+
+const SCHEMA: &str = "fossil_bench";
+const INITIAL_BLOCKS: i64 = 10_000;
+const BLOCKS_PER_UPDATE: i64 = 1_000;
+const PROVEN_BLOCK: i64 = 5_000;
+
+async fn connect() -> Pool<Postgres> {
+    let connection_string =
+        dotenvy::var("DB_CONNECTION_STRING").expect("DB_CONNECTION_STRING must be set");
+
+    let admin_pool = PgPoolOptions::new()
+        .max_connections(1)
+        .connect(&connection_string)
+        .await
+        .expect("Failed to connect to the database");
+    sqlx::raw_sql(&format!(
+        "DROP SCHEMA IF EXISTS {SCHEMA} CASCADE; CREATE SCHEMA {SCHEMA}"
+    ))
+    .execute(&admin_pool)
+    .await
+    .expect("Failed to create the bench schema");
+
+    let options = PgConnectOptions::from_str(&connection_string)
+        .expect("Invalid DB_CONNECTION_STRING")
+        .options([("search_path", SCHEMA)]);
+    PgPoolOptions::new()
+        .max_connections(10)
+        .connect_with(options)
+        .await
+        .expect("Failed to connect to the database")
+}
+
+/**
+ * Stores synthetic headers for blocks start..end
+ */
+async fn insert_headers(pool: &Pool<Postgres>, start: i64, end: i64) {
+    sqlx::query(
+        r#"
+        INSERT INTO blockheaders (block_hash, number, gas_limit, gas_used, nonce, parent_hash)
+            SELECT '0x' || lpad(to_hex(n), 64, '0'), n, 0, 0, '0x0', '0x' || lpad(to_hex(n - 1), 64, '0')
+            FROM generate_series($1::BIGINT, $2::BIGINT - 1) AS n
+        "#,
+    )
+    .bind(start)
+    .bind(end)
+    .execute(pool)
+    .await
+    .expect("Failed to insert headers");
+}
+
+/**
+ * Keeps storing headers and appending them to the MMR, 50 per lock as in production, until stopped
+ */
+async fn append_until_stopped(set: Arc<MmrSet>, pool: Pool<Postgres>, stop: Arc<AtomicBool>) {
+    let mut next_block = INITIAL_BLOCKS;
+    while !stop.load(Ordering::Relaxed) {
+        insert_headers(&pool, next_block, next_block + BLOCKS_PER_UPDATE).await;
+        fossil_mmr::update_mmr(&set, &pool, &stop)
+            .await
+            .expect("Failed to update the MMR");
+        next_block += BLOCKS_PER_UPDATE;
+    }
+}
+
+fn proof_latency(c: &mut Criterion) {
+    let rt = tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()
+        .expect("Failed to build the runtime");
+
+    let pool = rt.block_on(connect());
+    let config = MmrConfig {
+        bulk_append_min_blocks: 0,
+        ..MmrConfig::default()
+    };
+    let set = rt.block_on(async {
+        db::create_tables(&pool)
+            .await
+            .expect("Failed to create tables");
+        insert_headers(&pool, 0, INITIAL_BLOCKS).await;
+
+        let metrics = Arc::new(Metrics::new().expect("Failed to create metrics"));
+        let store = Arc::new(InMemoryStore::new(Some("bench")));
+        let set = Arc::new(
+            MmrSet::with_store(&config, store, metrics)
+                .await
+                .expect("Failed to open the MMR"),
+        );
+        fossil_mmr::update_mmr(&set, &pool, &AtomicBool::new(false))
+            .await
+            .expect("Failed to build the MMR");
+        set
+    });
+
+    let mut group = c.benchmark_group("proof_latency");
+    group.bench_function("idle", |b| {
+        b.to_async(&rt).iter(|| async {
+            fossil_mmr::get_proof(set.primary(), &pool, PROVEN_BLOCK, None)
+                .await
+                .expect("Failed to get proof")
+                .expect("Block not in the MMR")
+        })
+    });
+
+    let stop = Arc::new(AtomicBool::new(false));
+    let writer = rt.spawn(append_until_stopped(
+        set.clone(),
+        pool.clone(),
+        stop.clone(),
+    ));
+    group.bench_function("concurrent_appends", |b| {
+        b.to_async(&rt).iter(|| async {
+            fossil_mmr::get_proof(set.primary(), &pool, PROVEN_BLOCK, None)
+                .await
+                .expect("Failed to get proof")
+                .expect("Block not in the MMR")
+        })
+    });
+    stop.store(true, Ordering::Relaxed);
+    rt.block_on(writer).expect("Writer task failed");
+    group.finish();
+
+    let appended = rt.block_on(fossil_mmr::get_elements_count(set.primary()));
+    println!("MMR size after the benchmark: {appended:?} elements");
+    rt.block_on(sqlx::raw_sql(&format!("DROP SCHEMA {SCHEMA} CASCADE")).execute(&pool))
+        .expect("Failed to drop the bench schema");
+}
+
+criterion_group!(benches, proof_latency);
+criterion_main!(benches);
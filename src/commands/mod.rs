@@ -1,58 +1,61 @@
-use anyhow::{Context, Result};
+use anyhow::{bail, Context, Result};
 use futures_util::future::join_all;
-use log::{error, info, warn};
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
 use std::time::{self, Duration};
 use tokio::task;
+use tracing::{error, info, info_span, instrument, warn, Instrument};
 
-use crate::{db, endpoints, fossil_mmr};
-
-const MAX_RETRIES: u64 = 10;
-
-// Seconds
-const POLL_INTERVAL: u64 = 60;
-const TIMEOUT: u64 = 300;
+use crate::{
+    db, endpoints, fossil_mmr,
+    indexer::Indexer,
+    types::{BlockHeaderWithFullTransaction, RangeLease},
+};
 
+#[instrument(skip(indexer, should_terminate))]
 pub async fn fill_gaps(
+    indexer: &Indexer,
     start: Option<i64>,
     end: Option<i64>,
     should_terminate: Arc<AtomicBool>,
 ) -> Result<()> {
-    db::create_tables()
+    db::create_tables(indexer.pool())
         .await
         .context("Failed to create tables")?;
 
     let range_start_pointer = start.unwrap_or(0).max(0);
-    let range_end = get_range_end(end).await?;
+    let range_end = get_range_end(indexer, end).await?;
 
     if range_end < 0 || range_start_pointer == range_end {
         info!("Empty database");
         return Ok(());
     }
 
-    fill_missing_blocks_in_range(range_start_pointer, range_end, &should_terminate).await
+    fill_missing_blocks_in_range(indexer, range_start_pointer, range_end, &should_terminate).await
 }
 
 async fn fill_missing_blocks_in_range(
+    indexer: &Indexer,
     mut range_start_pointer: i64,
     search_end: i64,
     should_terminate: &AtomicBool,
 ) -> Result<()> {
     let mut range_end_pointer: i64;
-    for _ in 0..MAX_RETRIES {
+    for _ in 0..indexer.config().indexer.max_retries {
         while !should_terminate.load(Ordering::Relaxed) && range_start_pointer <= search_end {
-            range_end_pointer = search_end.min(range_start_pointer + 100_000 - 1);
-            match db::find_first_gap(range_start_pointer, range_end_pointer).await? {
+            range_end_pointer =
+                search_end.min(range_start_pointer + indexer.config().indexer.gap_scan_window - 1);
+            match db::find_first_gap(indexer.pool(), range_start_pointer, range_end_pointer).await?
+            {
                 Some(block_number) => {
-                    info!("[fill_gaps] Found missing block number: {}", block_number);
-                    if process_missing_block(block_number, &mut range_start_pointer).await? {
+                    info!(block_number, "Found missing block");
+                    if process_missing_block(indexer, block_number).await? {
                         range_start_pointer = block_number + 1;
                     }
                 }
                 None => {
                     info!(
-                        "[fill_gaps] No missing values found from {} to {}",
+                        "No missing blocks from {} to {}",
                         range_start_pointer, range_end_pointer
                     );
                     range_start_pointer = range_end_pointer + 1
@@ -63,56 +66,100 @@ async fn fill_missing_blocks_in_range(
     Ok(())
 }
 
-async fn process_missing_block(block_number: i64, range_start_pointer: &mut i64) -> Result<bool> {
-    for i in 0..MAX_RETRIES {
-        match endpoints::get_full_block_by_number(block_number, Some(TIMEOUT)).await {
+#[instrument(name = "block", skip(indexer))]
+async fn process_missing_block(indexer: &Indexer, block_number: i64) -> Result<bool> {
+    for i in 0..indexer.config().indexer.max_retries {
+        let attempt_span = info_span!("attempt", attempt = i);
+        match endpoints::get_full_block_by_number(
+            indexer.rpc(),
+            block_number,
+            Some(indexer.config().rpc.timeout),
+        )
+        .instrument(attempt_span.clone())
+        .await
+        {
             Ok(block) => {
-                db::write_blockheader(block).await?;
-                *range_start_pointer = block_number + 1;
-                info!("[fill_gaps] Successfully wrote block {block_number} after {i} retries");
+                write_block(indexer, block).instrument(attempt_span).await?;
+                info!(retries = i, "Wrote missing block");
                 return Ok(true);
             }
-            Err(e) => warn!("[fill_gaps] Error retrieving block {block_number}: {e}"),
+            Err(e) => warn!(attempt = i, error = %e, "Error retrieving block"),
         }
-        let backoff: u64 = (i - 0).pow(2) * 5;
+        indexer
+            .metrics()
+            .block_retries
+            .with_label_values(&["fill_gaps"])
+            .inc();
+        let backoff: u64 = i.pow(2) * 5;
         tokio::time::sleep(Duration::from_secs(backoff)).await;
     }
-    error!("[fill_gaps] Error with block number {}", block_number);
+    error!("Giving up on block after max retries");
     Ok(false)
 }
 
-async fn get_range_end(end: Option<i64>) -> Result<i64> {
+/**
+ * Updates the MMRs, and when they stop at missing or unverified blocks, fetches those blocks again (in batches of
+ * size) and updates them again
+ */
+async fn update_mmr(indexer: &Indexer, size: u32, should_terminate: &AtomicBool) -> Result<()> {
+    for _ in 0..indexer.config().indexer.max_retries {
+        fossil_mmr::update_mmr(indexer.mmr(), indexer.pool(), should_terminate).await?;
+        let Some(gap) = indexer.mmr().take_gap().await else {
+            return Ok(());
+        };
+        if should_terminate.load(Ordering::Relaxed) {
+            return Ok(());
+        }
+
+        info!(
+            gap_start = gap.start,
+            gap_end = gap.end,
+            "Fetching blocks again for the MMR"
+        );
+        // Stored blocks without their header fields get them, so they can be verified
+        update_blocks(indexer, gap.start, gap.end, size, should_terminate).await?;
+    }
+    warn!("MMR still stops at missing blocks, they are left for the next update or fix");
+    Ok(())
+}
+
+async fn get_range_end(indexer: &Indexer, end: Option<i64>) -> Result<i64> {
     Ok(match end {
         Some(s) => s,
-        None => db::get_last_stored_blocknumber()
+        None => db::get_last_stored_blocknumber(indexer.pool())
             .await
-            .context("[fill_gaps] Error retrieving last_recorded_block")?,
+            .context("Error retrieving last_recorded_block")?,
     })
 }
 
+#[instrument(skip(indexer, should_terminate))]
 pub async fn update_from(
+    indexer: &Indexer,
     start: Option<i64>,
     end: Option<i64>,
     size: u32,
     should_terminate: Arc<AtomicBool>,
 ) -> Result<()> {
-    db::create_tables()
+    db::create_tables(indexer.pool())
         .await
         .context("Failed to create tables")?;
 
-    let range_start = get_first_missing_block(start).await?;
+    let range_start = get_first_missing_block(indexer, start).await?;
     info!("Range start: {}", range_start);
 
-    let last_block = get_last_block(end).await?;
+    let last_block = get_last_block(indexer, end).await?;
     info!("Range end: {}", last_block);
 
     match end {
-        Some(_) => update_blocks(range_start, last_block, size, &should_terminate).await,
-        None => chain_update_blocks(range_start, last_block, size, &should_terminate).await,
+        Some(_) => update_blocks(indexer, range_start, last_block, size, &should_terminate).await,
+        None => {
+            chain_update_blocks(indexer, range_start, last_block, size, &should_terminate).await
+        }
     }
 }
 
 async fn chain_update_blocks(
+    indexer: &Indexer,
     mut range_start: i64,
     mut last_block: i64,
     size: u32,
@@ -124,16 +171,22 @@ async fn chain_update_blocks(
             break;
         }
 
-        update_blocks(range_start, last_block, size, should_terminate).await?;
-        fossil_mmr::update_mmr(should_terminate).await?;
+        update_blocks(indexer, range_start, last_block, size, should_terminate).await?;
+        update_mmr(indexer, size, should_terminate).await?;
 
         loop {
             if should_terminate.load(Ordering::Relaxed) {
                 break;
             }
 
-            let new_latest_block =
-                endpoints::get_latest_finalized_blocknumber(Some(TIMEOUT)).await?;
+            let new_latest_block = endpoints::get_latest_finalized_blocknumber(
+                indexer.rpc(),
+                Some(indexer.config().rpc.timeout),
+            )
+            .await?;
+            let stored_head = db::get_last_stored_blocknumber(indexer.pool()).await?;
+            indexer.metrics().set_heads(stored_head, new_latest_block);
+
             if new_latest_block > last_block {
                 range_start = last_block + 1;
                 last_block = new_latest_block;
@@ -141,9 +194,13 @@ async fn chain_update_blocks(
             } else {
                 info!(
                     "No new block finalized. Latest: {}. Sleeping for {}s...",
-                    new_latest_block, POLL_INTERVAL
+                    new_latest_block,
+                    indexer.config().indexer.poll_interval
                 );
-                async_std::task::sleep(time::Duration::from_secs(POLL_INTERVAL)).await;
+                async_std::task::sleep(time::Duration::from_secs(
+                    indexer.config().indexer.poll_interval,
+                ))
+                .await;
             }
         }
     }
@@ -152,6 +209,7 @@ async fn chain_update_blocks(
 }
 
 async fn update_blocks(
+    indexer: &Indexer,
     range_start: i64,
     last_block: i64,
     size: u32,
@@ -166,16 +224,7 @@ async fn update_blocks(
 
             let range_end = (last_block + 1).min(n + size as i64);
 
-            let tasks: Vec<_> = (n..range_end)
-                .map(|block_number| task::spawn(process_block(block_number)))
-                .collect();
-
-            let all_res = join_all(tasks).await;
-            let has_err = all_res.iter().any(|join_res| {
-                join_res.is_err() || join_res.as_ref().is_ok_and(|res| res.is_err())
-            });
-
-            if has_err {
+            if !process_batch(indexer, n, range_end).await {
                 error!("Rerun from block: {}", n);
                 break;
             }
@@ -191,48 +240,221 @@ async fn update_blocks(
     Ok(())
 }
 
-async fn process_block(block_number: i64) -> Result<()> {
-    for i in 0..MAX_RETRIES {
-        match endpoints::get_full_block_by_number(block_number, Some(TIMEOUT)).await {
-            Ok(block) => match db::write_blockheader(block).await {
+/**
+ * Fetches and writes blocks range_start..range_end concurrently, returning whether all of them were written
+ */
+async fn process_batch(indexer: &Indexer, range_start: i64, range_end: i64) -> bool {
+    let batch_span = info_span!("batch", range_start, range_end = range_end - 1);
+
+    // Spawned tasks do not inherit the current span, so it is attached to each of them
+    let tasks: Vec<_> = (range_start..range_end)
+        .map(|block_number| {
+            task::spawn(process_block(indexer.clone(), block_number).instrument(batch_span.clone()))
+        })
+        .collect();
+
+    let all_res = join_all(tasks).instrument(batch_span).await;
+    !all_res
+        .iter()
+        .any(|join_res| join_res.is_err() || join_res.as_ref().is_ok_and(|res| res.is_err()))
+}
+
+/**
+ * Backfills blocks by claiming range leases, so that several workers can share the work.
+ * Leases of crashed workers expire and are picked up again by the remaining workers. A lease whose blocks could not
+ * be fetched is released for another worker and fails the update.
+ */
+#[instrument(skip(indexer, should_terminate))]
+pub async fn update_from_leases(
+    indexer: &Indexer,
+    start: Option<i64>,
+    end: Option<i64>,
+    size: u32,
+    worker_id: String,
+    should_terminate: Arc<AtomicBool>,
+) -> Result<()> {
+    db::create_tables(indexer.pool())
+        .await
+        .context("Failed to create tables")?;
+
+    let range_start = start.unwrap_or(0).max(0);
+    let last_block = get_last_block(indexer, end).await?;
+    if range_start <= last_block {
+        let created = db::create_range_leases(
+            indexer.pool(),
+            range_start,
+            last_block,
+            indexer.config().indexer.lease_range_size,
+        )
+        .await?;
+        info!(
+            "Created {} leases from {} to {}",
+            created, range_start, last_block
+        );
+    }
+
+    while !should_terminate.load(Ordering::Relaxed) {
+        let lease = match db::claim_range_lease(
+            indexer.pool(),
+            &worker_id,
+            indexer.config().indexer.lease_duration,
+        )
+        .await?
+        {
+            Some(lease) => lease,
+            None => {
+                info!("No leases left to claim");
+                break;
+            }
+        };
+        let lease_span = info_span!(
+            "lease",
+            range_start = lease.range_start,
+            range_end = lease.range_end
+        );
+        info!(parent: &lease_span, "Claimed lease");
+
+        let renewer = task::spawn(
+            renew_lease(
+                indexer.clone(),
+                lease.range_start,
+                worker_id.clone(),
+                indexer.config().indexer.lease_duration,
+            )
+            .instrument(lease_span.clone()),
+        );
+        let completed = update_leased_range(indexer, &lease, size, &should_terminate)
+            .instrument(lease_span.clone())
+            .await;
+        renewer.abort();
+
+        if !completed {
+            if !db::release_range_lease(indexer.pool(), lease.range_start, &worker_id).await? {
+                warn!(parent: &lease_span, "Lease was lost before completion");
+            }
+            if should_terminate.load(Ordering::Relaxed) {
+                info!(parent: &lease_span, "Released lease");
+                break;
+            }
+            bail!(
+                "Failed to fetch the blocks of lease {} - {}, it was released",
+                lease.range_start,
+                lease.range_end
+            );
+        }
+
+        if db::complete_range_lease(indexer.pool(), lease.range_start, &worker_id).await? {
+            info!(parent: &lease_span, "Completed lease");
+            update_mmr(indexer, size, &should_terminate).await?;
+        } else {
+            warn!(parent: &lease_span, "Lease was lost before completion");
+        }
+    }
+
+    Ok(())
+}
+
+async fn renew_lease(indexer: Indexer, range_start: i64, worker_id: String, lease_duration: u64) {
+    loop {
+        tokio::time::sleep(Duration::from_secs(lease_duration / 3)).await;
+        match db::renew_range_lease(indexer.pool(), range_start, &worker_id, lease_duration).await {
+            Ok(true) => {}
+            Ok(false) => {
+                warn!("Lease is no longer held");
+                return;
+            }
+            Err(e) => warn!(error = %e, "Error renewing lease"),
+        }
+    }
+}
+
+async fn update_leased_range(
+    indexer: &Indexer,
+    lease: &RangeLease,
+    size: u32,
+    should_terminate: &AtomicBool,
+) -> bool {
+    for n in (lease.range_start..=lease.range_end).step_by(size as usize) {
+        if should_terminate.load(Ordering::Relaxed) {
+            info!("Termination requested. Stopping update process.");
+            return false;
+        }
+
+        let range_end = (lease.range_end + 1).min(n + size as i64);
+
+        if !process_batch(indexer, n, range_end).await {
+            error!("Error in blocks {} - {}", n, range_end - 1);
+            return false;
+        }
+    }
+    true
+}
+
+#[instrument(name = "block", skip(indexer))]
+async fn process_block(indexer: Indexer, block_number: i64) -> Result<()> {
+    for i in 0..indexer.config().indexer.max_retries {
+        let attempt_span = info_span!("attempt", attempt = i);
+        match endpoints::get_full_block_by_number(
+            indexer.rpc(),
+            block_number,
+            Some(indexer.config().rpc.timeout),
+        )
+        .instrument(attempt_span.clone())
+        .await
+        {
+            Ok(block) => match write_block(&indexer, block).instrument(attempt_span).await {
                 Ok(_) => {
                     if i > 0 {
-                        info!(
-                            "[update_from] Successfully wrote block {block_number} after {i} retries"
-                        );
+                        info!(retries = i, "Wrote block");
                     }
                     return Ok(());
                 }
-                Err(e) => warn!("[update_from] Error writing block {block_number}: {e}"),
+                Err(e) => warn!(attempt = i, error = %e, "Error writing block"),
             },
-            Err(e) => warn!(
-                "[update_from] Error retrieving block {}: {}",
-                block_number, e
-            ),
+            Err(e) => warn!(attempt = i, error = %e, "Error retrieving block"),
         }
-        let backoff: u64 = (i - 0).pow(2) * 5;
+        indexer
+            .metrics()
+            .block_retries
+            .with_label_values(&["update_from"])
+            .inc();
+        let backoff: u64 = i.pow(2) * 5;
         tokio::time::sleep(Duration::from_secs(backoff)).await;
     }
-    error!("[update_from] Error with block number {}", block_number);
+    error!("Giving up on block after max retries");
     Err(anyhow::anyhow!("Failed to process block {}", block_number))
 }
 
-async fn get_first_missing_block(start: Option<i64>) -> Result<i64> {
+#[instrument(name = "db_write", skip_all)]
+async fn write_block(indexer: &Indexer, block: BlockHeaderWithFullTransaction) -> Result<()> {
+    let timer = indexer.metrics().db_write_duration.start_timer();
+    let written = db::write_blockheader(indexer.pool(), block).await?;
+    timer.observe_duration();
+    if written {
+        indexer.metrics().blocks_written.inc();
+    }
+    Ok(())
+}
+
+async fn get_first_missing_block(indexer: &Indexer, start: Option<i64>) -> Result<i64> {
     Ok(match start {
         Some(s) => s,
         None => {
-            db::get_last_stored_blocknumber()
+            db::get_last_stored_blocknumber(indexer.pool())
                 .await
-                .context("[update_from] Error retrieving first_recorded_block")?
+                .context("Error retrieving first_recorded_block")?
                 + 1
         }
     })
 }
 
-async fn get_last_block(end: Option<i64>) -> Result<i64> {
-    let latest_block: i64 = endpoints::get_latest_finalized_blocknumber(Some(TIMEOUT))
-        .await
-        .context("Failed to get latest block number")?;
+async fn get_last_block(indexer: &Indexer, end: Option<i64>) -> Result<i64> {
+    let latest_block: i64 = endpoints::get_latest_finalized_blocknumber(
+        indexer.rpc(),
+        Some(indexer.config().rpc.timeout),
+    )
+    .await
+    .context("Failed to get latest block number")?;
 
     Ok(match end {
         Some(s) => s.min(latest_block),
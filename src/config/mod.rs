@@ -0,0 +1,474 @@
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+use toml::{Table, Value};
+
+const DEFAULT_CONFIG_PATH: &str = "config.toml";
+const ENV_PREFIX: &str = "FOSSIL";
+
+// Env vars kept from before the config file existed
+const LEGACY_ENV_VARS: [(&str, &str); 3] = [
+    ("DB_CONNECTION_STRING", "db.connection_string"),
+    ("NODE_CONNECTION_STRING", "rpc.connection_string"),
+    ("ROUTER_ENDPOINT", "router.endpoint"),
+];
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub db: DbConfig,
+    pub rpc: RpcConfig,
+    pub router: RouterConfig,
+    pub indexer: IndexerConfig,
+    pub mmr: MmrConfig,
+    pub telemetry: TelemetryConfig,
+    pub health: HealthConfig,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DbConfig {
+    pub connection_string: String,
+    pub max_connections: u32,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RpcConfig {
+    pub connection_string: String,
+    /// Seconds
+    pub timeout: u64,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RouterConfig {
+    pub endpoint: String,
+    /// Largest limit accepted by the listing endpoints
+    pub max_page_size: i64,
+    /// Most blocks accepted by one POST /mmr/proofs request
+    pub max_batch_proofs: usize,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct IndexerConfig {
+    pub max_retries: u64,
+    /// Seconds
+    pub poll_interval: u64,
+    /// How many blocks are checked for gaps per query
+    pub gap_scan_window: i64,
+    pub lease_range_size: i64,
+    /// Seconds. Bounds the recovery from a worker that stops while holding a lease: the MMR cannot pass its range
+    /// until the lease expires and another worker claims it
+    pub lease_duration: u64,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MmrConfig {
+    pub store: MmrStoreKind,
+    /// SQLite file holding the MMR when store is sqlite
+    pub db_file_path: String,
+    pub mmr_id: String,
+    pub max_retries: u64,
+    /// How many (upper limit) block hashes are retrieved for each query
+    pub append_loopsize: i32,
+    /// How many block hashes are appended per MMR lock
+    pub append_chunksize: usize,
+    /// Queries returning at least this many new block hashes append them with the parallel bulk builder (0: never)
+    pub bulk_append_min_blocks: usize,
+    /// One MMR is built per hasher from the same block hashes. The first one serves the /mmr/... routes
+    pub hashers: Vec<MmrHasher>,
+    /// Blocks per shard: each hasher also gets one MMR per window of this many blocks (0: no shards)
+    pub shard_size: i64,
+}
+
+/**
+ * Where the MMR nodes are kept: a local SQLite file, or the headers database (shared by replicas and backed up
+ * with the headers)
+ */
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MmrStoreKind {
+    #[default]
+    Sqlite,
+    Postgres,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MmrHasher {
+    #[default]
+    Keccak,
+    Poseidon,
+    Pedersen,
+}
+
+impl MmrHasher {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            MmrHasher::Keccak => "keccak",
+            MmrHasher::Poseidon => "poseidon",
+            MmrHasher::Pedersen => "pedersen",
+        }
+    }
+
+    pub fn parse(name: &str) -> Option<Self> {
+        [MmrHasher::Keccak, MmrHasher::Poseidon, MmrHasher::Pedersen]
+            .into_iter()
+            .find(|hasher| hasher.as_str() == name)
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TelemetryConfig {
+    pub format: LogFormat,
+    /// Used when RUST_LOG is not set, e.g. "info,fossil_headers_db=debug"
+    pub filter: String,
+    /// OTLP/HTTP traces URL, e.g. http://localhost:4318/v1/traces. Spans are not exported when empty
+    pub otlp_endpoint: String,
+    pub service_name: String,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    #[default]
+    Text,
+    Json,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HealthConfig {
+    /// Blocks the stored head may trail the finalized head before /health/ready fails (0 disables the check)
+    pub max_head_lag: i64,
+    /// Seconds since the last completed MMR update before /health/ready fails (0 disables the check)
+    pub max_mmr_update_age: u64,
+    /// Seconds
+    pub rpc_timeout: u64,
+}
+
+impl Default for DbConfig {
+    fn default() -> Self {
+        Self {
+            connection_string: String::new(),
+            max_connections: 1000,
+        }
+    }
+}
+
+impl Default for RpcConfig {
+    fn default() -> Self {
+        Self {
+            connection_string: String::new(),
+            timeout: 300,
+        }
+    }
+}
+
+impl Default for RouterConfig {
+    fn default() -> Self {
+        Self {
+            endpoint: "0.0.0.0:8080".to_string(),
+            max_page_size: 1000,
+            max_batch_proofs: 10_000,
+        }
+    }
+}
+
+impl Default for IndexerConfig {
+    fn default() -> Self {
+        Self {
+            max_retries: 10,
+            poll_interval: 60,
+            gap_scan_window: 100_000,
+            lease_range_size: 10_000,
+            lease_duration: 300,
+        }
+    }
+}
+
+impl Default for MmrConfig {
+    fn default() -> Self {
+        Self {
+            store: MmrStoreKind::Sqlite,
+            db_file_path: "mmr_db".to_string(),
+            mmr_id: "blockheaders_mmr".to_string(),
+            max_retries: 10,
+            append_loopsize: 10_000,
+            append_chunksize: 50,
+            bulk_append_min_blocks: 1_000,
+            hashers: vec![MmrHasher::Keccak],
+            shard_size: 0,
+        }
+    }
+}
+
+impl MmrConfig {
+    /**
+     * Id of the MMR built with hasher. Keccak keeps mmr_id, so MMRs built before hashers were configurable stay valid
+     */
+    pub fn mmr_id_for(&self, hasher: MmrHasher) -> String {
+        match hasher {
+            MmrHasher::Keccak => self.mmr_id.clone(),
+            hasher => format!("{}_{}", self.mmr_id, hasher.as_str()),
+        }
+    }
+
+    /**
+     * Id of the MMR built with hasher over the blocks of shard
+     */
+    pub fn shard_mmr_id_for(&self, hasher: MmrHasher, shard: u64) -> String {
+        format!("{}_shard_{}", self.mmr_id_for(hasher), shard)
+    }
+}
+
+impl Default for TelemetryConfig {
+    fn default() -> Self {
+        Self {
+            format: LogFormat::Text,
+            filter: "info".to_string(),
+            otlp_endpoint: String::new(),
+            service_name: "fossil-headers-db".to_string(),
+        }
+    }
+}
+
+impl Default for HealthConfig {
+    fn default() -> Self {
+        Self {
+            max_head_lag: 256,
+            max_mmr_update_age: 900,
+            rpc_timeout: 5,
+        }
+    }
+}
+
+impl Config {
+    /**
+     * Builds the config from (in increasing priority) defaults, the TOML file, env vars and `key=value` overrides
+     *
+     * The file is optional when no path is given explicitly. Env vars are named FOSSIL_<SECTION>_<KEY>,
+     * e.g. FOSSIL_MMR_APPEND_CHUNKSIZE, and overrides use dotted keys, e.g. mmr.append_chunksize=100.
+     */
+    pub fn load(path: Option<&Path>, overrides: &[String]) -> Result<Self> {
+        let mut table = Table::try_from(Config::default())?;
+
+        let file_path = path.unwrap_or(Path::new(DEFAULT_CONFIG_PATH));
+        if path.is_some() || file_path.exists() {
+            let contents = std::fs::read_to_string(file_path)
+                .with_context(|| format!("Failed to read config file {}", file_path.display()))?;
+            let file_table: Table = toml::from_str(&contents)
+                .with_context(|| format!("Failed to parse config file {}", file_path.display()))?;
+            merge_tables(&mut table, file_table);
+        }
+
+        for (env_var, key) in LEGACY_ENV_VARS {
+            if let Ok(value) = dotenvy::var(env_var) {
+                set_key(&mut table, key, &value)?;
+            }
+        }
+        for key in leaf_keys(&table, "") {
+            let env_var = format!("{}_{}", ENV_PREFIX, key.replace('.', "_").to_uppercase());
+            if let Ok(value) = dotenvy::var(&env_var) {
+                set_key(&mut table, &key, &value)?;
+            }
+        }
+
+        for entry in overrides {
+            let (key, value) = entry
+                .split_once('=')
+                .with_context(|| format!("Invalid config override {entry}, expected key=value"))?;
+            set_key(&mut table, key.trim(), value.trim())?;
+        }
+
+        let config: Config = table.try_into().context("Invalid config")?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    /**
+     * Checks the tunables. Connection strings are checked where they are used, as embedders may provide their own
+     */
+    pub fn validate(&self) -> Result<()> {
+        if self.router.endpoint.is_empty() {
+            bail!("router.endpoint must be set (or ROUTER_ENDPOINT)");
+        }
+        if self.router.max_page_size <= 0 {
+            bail!("router.max_page_size must be greater than 0");
+        }
+        if self.router.max_batch_proofs == 0 {
+            bail!("router.max_batch_proofs must be greater than 0");
+        }
+        if !(1..=1000).contains(&self.db.max_connections) {
+            bail!("db.max_connections must be between 1 and 1000");
+        }
+        if self.rpc.timeout == 0 {
+            bail!("rpc.timeout must be greater than 0");
+        }
+        if self.indexer.poll_interval == 0 {
+            bail!("indexer.poll_interval must be greater than 0");
+        }
+        if self.indexer.gap_scan_window <= 0 {
+            bail!("indexer.gap_scan_window must be greater than 0");
+        }
+        if self.indexer.lease_range_size <= 0 {
+            bail!("indexer.lease_range_size must be greater than 0");
+        }
+        if self.indexer.lease_duration < 3 {
+            bail!("indexer.lease_duration must be at least 3 seconds");
+        }
+        if self.mmr.mmr_id.is_empty() || self.mmr.mmr_id.contains(':') {
+            bail!("mmr.mmr_id must be non-empty and must not contain ':'");
+        }
+        if self.mmr.append_loopsize <= 0 {
+            bail!("mmr.append_loopsize must be greater than 0");
+        }
+        if self.mmr.append_chunksize == 0 {
+            bail!("mmr.append_chunksize must be greater than 0");
+        }
+        if self.mmr.shard_size < 0 {
+            bail!("mmr.shard_size must not be negative");
+        }
+        if self.mmr.hashers.is_empty() {
+            bail!("mmr.hashers must not be empty");
+        }
+        if (1..self.mmr.hashers.len()).any(|i| self.mmr.hashers[..i].contains(&self.mmr.hashers[i]))
+        {
+            bail!("mmr.hashers must not contain duplicates");
+        }
+        if self.telemetry.service_name.is_empty() {
+            bail!("telemetry.service_name must be set");
+        }
+        if self.health.max_head_lag < 0 {
+            bail!("health.max_head_lag must not be negative");
+        }
+        if self.health.rpc_timeout == 0 {
+            bail!("health.rpc_timeout must be greater than 0");
+        }
+        Ok(())
+    }
+
+    /**
+     * Renders the config as TOML, with credentials removed from connection strings
+     */
+    pub fn to_redacted_string(&self) -> Result<String> {
+        let mut redacted = self.clone();
+        redacted.db.connection_string = redact_connection_string(&self.db.connection_string);
+        redacted.rpc.connection_string = redact_connection_string(&self.rpc.connection_string);
+        redacted.telemetry.otlp_endpoint = redact_connection_string(&self.telemetry.otlp_endpoint);
+        Ok(toml::to_string_pretty(&redacted)?)
+    }
+}
+
+fn merge_tables(base: &mut Table, other: Table) {
+    for (key, value) in other {
+        match (base.get_mut(&key), value) {
+            (Some(Value::Table(base_table)), Value::Table(other_table)) => {
+                merge_tables(base_table, other_table)
+            }
+            (_, value) => {
+                base.insert(key, value);
+            }
+        }
+    }
+}
+
+fn leaf_keys(table: &Table, prefix: &str) -> Vec<String> {
+    table
+        .iter()
+        .flat_map(|(key, value)| {
+            let path = if prefix.is_empty() {
+                key.clone()
+            } else {
+                format!("{prefix}.{key}")
+            };
+            match value {
+                Value::Table(sub_table) => leaf_keys(sub_table, &path),
+                _ => vec![path],
+            }
+        })
+        .collect()
+}
+
+/**
+ * Sets a dotted key. The value is parsed as a TOML value (number, bool, array, ...) falling back to a string, and
+ * string fields keep it as it was written unless it is a quoted string
+ */
+fn set_key(table: &mut Table, key: &str, raw_value: &str) -> Result<()> {
+    let mut current = table;
+    let mut parts = key.split('.').peekable();
+    while let Some(part) = parts.next() {
+        if parts.peek().is_none() {
+            let value = toml::from_str::<Table>(&format!("v = {raw_value}"))
+                .ok()
+                .and_then(|mut parsed| parsed.remove("v"));
+            let value = match (current.get(part), value) {
+                (_, Some(value @ Value::String(_))) => value,
+                (Some(Value::String(_)), _) | (_, None) => Value::String(raw_value.to_string()),
+                (_, Some(value)) => value,
+            };
+            current.insert(part.to_string(), value);
+            return Ok(());
+        }
+        current = match current
+            .entry(part.to_string())
+            .or_insert_with(|| Value::Table(Table::new()))
+        {
+            Value::Table(sub_table) => sub_table,
+            _ => bail!("Invalid config key {key}"),
+        };
+    }
+    bail!("Invalid config key {key}")
+}
+
+/**
+ * Keeps the scheme and host of a connection string, hiding user info, path and query (which may hold api keys)
+ */
+pub(crate) fn redact_connection_string(connection_string: &str) -> String {
+    if connection_string.is_empty() {
+        return String::new();
+    }
+    let Some((scheme, rest)) = connection_string.split_once("://") else {
+        return "***".to_string();
+    };
+    let host_start = rest.find('@').map(|i| i + 1).unwrap_or(0);
+    let host_end = rest[host_start..]
+        .find(['/', '?'])
+        .map(|i| i + host_start)
+        .unwrap_or(rest.len());
+
+    let user_info = if host_start > 0 { "***@" } else { "" };
+    let path = if host_end < rest.len() { "/***" } else { "" };
+    format!(
+        "{scheme}://{user_info}{}{path}",
+        &rest[host_start..host_end]
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn override_values_follow_the_field_type() {
+        let mut table = Table::try_from(Config::default()).unwrap();
+        set_key(&mut table, "mmr.mmr_id", "123").unwrap();
+        set_key(&mut table, "db.connection_string", "true").unwrap();
+        set_key(&mut table, "mmr.store", "postgres").unwrap();
+        set_key(&mut table, "mmr.db_file_path", r#""./db-instance.db""#).unwrap();
+        set_key(&mut table, "mmr.shard_size", "1000").unwrap();
+        set_key(&mut table, "mmr.hashers", r#"["keccak", "poseidon"]"#).unwrap();
+
+        let config: Config = table.try_into().unwrap();
+        assert_eq!(config.mmr.mmr_id, "123");
+        assert_eq!(config.db.connection_string, "true");
+        assert_eq!(config.mmr.store, MmrStoreKind::Postgres);
+        assert_eq!(config.mmr.db_file_path, "./db-instance.db");
+        assert_eq!(config.mmr.shard_size, 1000);
+        assert_eq!(config.mmr.hashers, [MmrHasher::Keccak, MmrHasher::Poseidon]);
+    }
+}
@@ -1,52 +1,73 @@
+use crate::config::DbConfig;
+use crate::types::header_rlp;
 use crate::types::type_utils::convert_hex_string_to_i64;
 use crate::types::BlockDetails;
 use crate::types::BlockHeaderWithFullTransaction;
+use crate::types::BlockVerification;
+use crate::types::MmrRoot;
+use crate::types::RangeLease;
+use crate::types::StoredBlockHeader;
+use crate::types::{StoredTransaction, TransactionDirection};
 use anyhow::{Context, Result};
-use log::{info, warn};
 use sqlx::postgres::PgConnectOptions;
 use sqlx::ConnectOptions;
 use sqlx::QueryBuilder;
-use sqlx::{postgres::PgPoolOptions, Pool, Postgres};
-use std::sync::Arc;
+use sqlx::{postgres::PgPoolOptions, PgConnection, Pool, Postgres};
+use std::collections::HashMap;
 use std::time::Duration;
-use tokio::sync::OnceCell;
-
-static DB_POOL: OnceCell<Arc<Pool<Postgres>>> = OnceCell::const_new();
-pub const DB_MAX_CONNECTIONS: u32 = 1000;
-
-pub async fn get_db_pool() -> Result<Arc<Pool<Postgres>>> {
-    match DB_POOL.get() {
-        Some(pool) => Ok(pool.clone()),
-        None => {
-            let mut conn_options: PgConnectOptions = dotenvy::var("DB_CONNECTION_STRING")
-                .expect("DB_CONNECTION_STRING must be set")
-                .parse()?;
-            conn_options =
-                conn_options.log_slow_statements(log::LevelFilter::Debug, Duration::new(120, 0));
-
-            let pool = PgPoolOptions::new()
-                .max_connections(DB_MAX_CONNECTIONS)
-                .connect_with(conn_options)
-                .await?;
-            let arc_pool = Arc::new(pool);
-            match DB_POOL.set(arc_pool.clone()) {
-                Ok(_) => Ok(arc_pool),
-                Err(_) => Ok(DB_POOL.get().expect("Pool was just set").clone()),
-            }
-        }
-    }
+use tracing::{info, warn};
+
+/// Stored headers hashed per query when verifying blocks
+const VERIFY_CHUNK_SIZE: i64 = 10_000;
+
+/// Roots and leaf indexes of the MMRs being rebuilt, moved into mmr_roots and blockheaders with the new store
+const MMR_ROOTS_REBUILD_TABLE: &str = "mmr_roots_rebuild";
+const MMR_LEAF_INDEXES_REBUILD_TABLE: &str = "mmr_leaf_indexes_rebuild";
+
+pub async fn connect(config: &DbConfig) -> Result<Pool<Postgres>> {
+    let mut conn_options: PgConnectOptions = config
+        .connection_string
+        .parse()
+        .context("Invalid db.connection_string")?;
+    conn_options = conn_options.log_slow_statements(log::LevelFilter::Debug, Duration::new(120, 0));
+
+    let pool = PgPoolOptions::new()
+        .max_connections(config.max_connections)
+        .connect_with(conn_options)
+        .await
+        .context("Failed to connect to database")?;
+    Ok(pool)
 }
 
-pub async fn create_tables() -> Result<()> {
-    let pool = get_db_pool().await?;
+pub async fn create_tables(pool: &Pool<Postgres>) -> Result<()> {
     sqlx::query(include_str!("./sql/blockheaders_table.sql"))
-        .execute(&*pool)
+        .execute(pool)
         .await
         .context("Failed to create blockheaders table")?;
+    sqlx::raw_sql(include_str!("./sql/blockheaders_verification.sql"))
+        .execute(pool)
+        .await
+        .context("Failed to add blockheaders verification columns")?;
+    sqlx::raw_sql(include_str!("./sql/blockheaders_header_fields.sql"))
+        .execute(pool)
+        .await
+        .context("Failed to add blockheaders header columns")?;
     sqlx::query(include_str!("./sql/transactions_table.sql"))
-        .execute(&*pool)
+        .execute(pool)
         .await
         .context("Failed to create transactions table")?;
+    sqlx::raw_sql(include_str!("./sql/transactions_indexes.sql"))
+        .execute(pool)
+        .await
+        .context("Failed to create transactions indexes")?;
+    sqlx::query(include_str!("./sql/range_leases_table.sql"))
+        .execute(pool)
+        .await
+        .context("Failed to create range_leases table")?;
+    sqlx::query(include_str!("./sql/mmr_roots_table.sql"))
+        .execute(pool)
+        .await
+        .context("Failed to create mmr_roots table")?;
     Ok(())
 }
 
@@ -55,10 +76,9 @@ pub async fn create_tables() -> Result<()> {
  *
  * @Returns blocknumber, else -1 if table is empty
  */
-pub async fn get_last_stored_blocknumber() -> Result<i64> {
-    let pool = get_db_pool().await.context("Failed to get database pool")?;
+pub async fn get_last_stored_blocknumber(pool: &Pool<Postgres>) -> Result<i64> {
     let result: (i64,) = sqlx::query_as("SELECT COALESCE(MAX(number), -1) FROM blockheaders")
-        .fetch_one(&*pool)
+        .fetch_one(pool)
         .await
         .context("Failed to get last stored block number")?;
 
@@ -68,8 +88,7 @@ pub async fn get_last_stored_blocknumber() -> Result<i64> {
 /**
  * Returns the first missing blocknumber in between provided numbers (inclusive)
  */
-pub async fn find_first_gap(start: i64, end: i64) -> Result<Option<i64>> {
-    let pool = get_db_pool().await.context("Failed to get database pool")?;
+pub async fn find_first_gap(pool: &Pool<Postgres>, start: i64, end: i64) -> Result<Option<i64>> {
     let result: Option<(i64,)> = sqlx::query_as(
         r#"
         WITH RECURSIVE number_series(n) AS (
@@ -84,30 +103,86 @@ pub async fn find_first_gap(start: i64, end: i64) -> Result<Option<i64>> {
     )
     .bind(start)
     .bind(end)
-    .fetch_optional(&*pool)
+    .fetch_optional(pool)
     .await
     .context("Failed to find first gap")?;
 
     Ok(result.map(|r| r.0))
 }
 
-pub async fn write_blockheader(block_header: BlockHeaderWithFullTransaction) -> Result<()> {
-    let pool = get_db_pool().await?;
+/**
+ * Writes a block and its transactions. A stored block that is not verified is replaced, along with its
+ * transactions if its hash changes. Headers that do not hash to their block hash are refused.
+ *
+ * @Returns false if the block was already stored and left as is
+ */
+pub async fn write_blockheader(
+    pool: &Pool<Postgres>,
+    block_header: BlockHeaderWithFullTransaction,
+) -> Result<bool> {
+    header_rlp::encode_header(&StoredBlockHeader::from(&block_header))?;
+    let number = convert_hex_string_to_i64(&block_header.number);
     let mut tx = pool.begin().await?;
 
+    sqlx::query(
+        r#"
+        DELETE FROM transactions WHERE block_number = (
+            SELECT number FROM blockheaders WHERE number = $1 AND NOT verified AND block_hash <> $2
+        )
+        "#,
+    )
+    .bind(number)
+    .bind(&block_header.hash)
+    .execute(&mut *tx)
+    .await
+    .context("Failed to delete transactions of replaced block")?;
+
     // Insert block header
     let result = sqlx::query(
         r#"
         INSERT INTO blockheaders (
             block_hash, number, gas_limit, gas_used, base_fee_per_gas,
-            nonce, transaction_root, receipts_root, state_root
+            nonce, transaction_root, receipts_root, state_root, parent_hash,
+            sha3_uncles, miner, logs_bloom, difficulty, timestamp, extra_data, mix_hash,
+            withdrawals_root, blob_gas_used, excess_blob_gas, parent_beacon_block_root, requests_hash
         )
-        VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9)
-        ON CONFLICT (number) DO NOTHING
+        VALUES (
+            $1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13, $14, $15, $16, $17, $18, $19, $20,
+            $21, $22
+        )
+        ON CONFLICT (number) DO UPDATE SET
+            block_hash = EXCLUDED.block_hash,
+            gas_limit = EXCLUDED.gas_limit,
+            gas_used = EXCLUDED.gas_used,
+            base_fee_per_gas = EXCLUDED.base_fee_per_gas,
+            nonce = EXCLUDED.nonce,
+            transaction_root = EXCLUDED.transaction_root,
+            receipts_root = EXCLUDED.receipts_root,
+            state_root = EXCLUDED.state_root,
+            parent_hash = EXCLUDED.parent_hash,
+            sha3_uncles = EXCLUDED.sha3_uncles,
+            miner = EXCLUDED.miner,
+            logs_bloom = EXCLUDED.logs_bloom,
+            difficulty = EXCLUDED.difficulty,
+            timestamp = EXCLUDED.timestamp,
+            extra_data = EXCLUDED.extra_data,
+            mix_hash = EXCLUDED.mix_hash,
+            withdrawals_root = EXCLUDED.withdrawals_root,
+            blob_gas_used = EXCLUDED.blob_gas_used,
+            excess_blob_gas = EXCLUDED.excess_blob_gas,
+            parent_beacon_block_root = EXCLUDED.parent_beacon_block_root,
+            requests_hash = EXCLUDED.requests_hash,
+            mmr_leaf_index = CASE WHEN blockheaders.block_hash = EXCLUDED.block_hash
+                THEN blockheaders.mmr_leaf_index END
+            -- Only replaces rows that could not be verified, or repairs those stored before these fields were
+            -- recorded
+            WHERE NOT blockheaders.verified
+                OR ((blockheaders.parent_hash IS NULL OR blockheaders.timestamp IS NULL)
+                    AND blockheaders.block_hash = EXCLUDED.block_hash)
         "#,
     )
     .bind(&block_header.hash)
-    .bind(convert_hex_string_to_i64(&block_header.number))
+    .bind(number)
     .bind(convert_hex_string_to_i64(&block_header.gas_limit))
     .bind(convert_hex_string_to_i64(&block_header.gas_used))
     .bind(&block_header.base_fee_per_gas)
@@ -115,23 +190,40 @@ pub async fn write_blockheader(block_header: BlockHeaderWithFullTransaction) ->
     .bind(&block_header.transactions_root)
     .bind(&block_header.receipts_root)
     .bind(&block_header.state_root)
+    .bind(&block_header.parent_hash)
+    .bind(&block_header.sha3_uncles)
+    .bind(&block_header.miner)
+    .bind(&block_header.logs_bloom)
+    .bind(&block_header.difficulty)
+    .bind(convert_hex_string_to_i64(&block_header.timestamp))
+    .bind(&block_header.extra_data)
+    .bind(&block_header.mix_hash)
+    .bind(&block_header.withdrawals_root)
+    .bind(&block_header.blob_gas_used)
+    .bind(&block_header.excess_blob_gas)
+    .bind(&block_header.parent_beacon_block_root)
+    .bind(&block_header.requests_hash)
     .execute(&mut *tx) // Changed this line
     .await
     .context("Failed to insert block header")?;
 
     if result.rows_affected() == 0 {
-        warn!(
-            "Block already exists: -- block number: {}, block hash: {}",
-            block_header.number, block_header.hash
-        );
-        return Ok(());
+        warn!(block_hash = %block_header.hash, "Block already exists");
+        return Ok(false);
     } else {
-        info!(
-            "Inserted block number: {}, block hash: {}",
-            block_header.number, block_header.hash
-        );
+        info!(block_hash = %block_header.hash, "Inserted block");
     }
 
+    // A child verified against a replaced block is checked again
+    sqlx::query(
+        "UPDATE blockheaders SET verified = FALSE WHERE number = $1 + 1 AND verified AND parent_hash <> $2",
+    )
+    .bind(number)
+    .bind(&block_header.hash)
+    .execute(&mut *tx)
+    .await
+    .context("Failed to unverify child block")?;
+
     // Insert transactions
     if !block_header.transactions.is_empty() {
         let mut query_builder: QueryBuilder<Postgres> = QueryBuilder::new(
@@ -165,36 +257,1173 @@ pub async fn write_blockheader(block_header: BlockHeaderWithFullTransaction) ->
             .context("Failed to insert transactions")?;
 
         info!(
-            "Inserted {} transactions for block {}",
-            result.rows_affected(),
-            block_header.number
+            transactions = result.rows_affected(),
+            "Inserted transactions"
         );
     }
 
     tx.commit().await.context("Failed to commit transaction")?;
-    Ok(())
+    Ok(true)
 }
 
 /**
- * Retrieves next n numbers and hashes after provided blocknumber
+ * Retrieves next n numbers and hashes after provided blocknumber, up to end_blocknumber (inclusive)
  *
  * @Returns blocknumbers and hashes wrapped in a BlockDetails struct
  */
-pub async fn get_blockheaders(start_blocknumber: i64, limit: i32) -> Result<Vec<BlockDetails>> {
-    let pool = get_db_pool().await?;
+pub async fn get_blockheaders(
+    pool: &Pool<Postgres>,
+    start_blocknumber: i64,
+    end_blocknumber: i64,
+    limit: i32,
+) -> Result<Vec<BlockDetails>> {
     let result: Vec<BlockDetails> = sqlx::query_as(
         r#"
-        SELECT block_hash, number FROM blockheaders
-            WHERE number > $1
+        SELECT block_hash, number, parent_hash, verified FROM blockheaders
+            WHERE number > $1 AND number <= $2
             ORDER BY number ASC
-            LIMIT $2
+            LIMIT $3
         "#,
     )
     .bind(start_blocknumber)
+    .bind(end_blocknumber)
     .bind(limit)
-    .fetch_all(&*pool)
+    .fetch_all(pool)
     .await
     .context("Failed to get blockheaders")?;
 
     Ok(result)
 }
+
+/**
+ * Marks the stored blocks from start to end (inclusive) as verified when their parent hash is the hash of the stored
+ * previous block, and their header hashes to their block hash. Blocks stored before the header fields were
+ * recorded cannot be hashed, and stay unverified until they are fetched again. A block that does not link to its
+ * stored parent shows that one of them is wrong, so the parent is unverified as well and both are fetched again.
+ * Finality is not checked here: only blocks up to the finalized head are written.
+ *
+ * @Returns the number of blocks newly verified, and the blocks to fetch again
+ */
+pub async fn verify_blockheaders(
+    pool: &Pool<Postgres>,
+    start: i64,
+    end: i64,
+) -> Result<BlockVerification> {
+    let mut verification = BlockVerification::default();
+    let mut after_blocknumber = start - 1;
+    loop {
+        let headers: Vec<StoredBlockHeader> = sqlx::query_as(
+            r#"
+            SELECT block_hash, number, gas_limit, gas_used, base_fee_per_gas,
+                nonce, transaction_root, receipts_root, state_root, parent_hash, mmr_leaf_index,
+                sha3_uncles, miner, logs_bloom, difficulty, timestamp, extra_data, mix_hash,
+                withdrawals_root, blob_gas_used, excess_blob_gas, parent_beacon_block_root, requests_hash
+            FROM blockheaders
+                WHERE number > $1 AND number <= $2 AND NOT verified
+                ORDER BY number ASC
+                LIMIT $3
+            "#,
+        )
+        .bind(after_blocknumber)
+        .bind(end)
+        .bind(VERIFY_CHUNK_SIZE)
+        .fetch_all(pool)
+        .await
+        .context("Failed to get blockheaders to verify")?;
+        let Some(last_header) = headers.last() else {
+            return Ok(verification);
+        };
+        after_blocknumber = last_header.number;
+
+        let parent_numbers: Vec<i64> = headers.iter().map(|header| header.number - 1).collect();
+        let parent_hashes = get_block_hashes(pool, &parent_numbers).await?;
+
+        let mut numbers = Vec::with_capacity(headers.len());
+        let mut unlinked_parents = Vec::new();
+        for header in &headers {
+            if header.number > 0 {
+                // Verified once the parent is stored, or once the header fields are
+                let (Some(parent_hash), Some(stored_parent_hash)) = (
+                    header.parent_hash.as_ref(),
+                    parent_hashes.get(&(header.number - 1)),
+                ) else {
+                    continue;
+                };
+                if !parent_hash.eq_ignore_ascii_case(stored_parent_hash) {
+                    warn!(
+                        block_number = header.number,
+                        "Block does not link to its stored parent, both are fetched again"
+                    );
+                    unlinked_parents.push(header.number - 1);
+                    continue;
+                }
+            }
+            match header_rlp::encode_header(header) {
+                Ok(Some(_)) => numbers.push(header.number),
+                Ok(None) => {}
+                Err(e) => {
+                    warn!(block_number = header.number, error = %e, "Stored header is not valid, it is fetched again");
+                    verification.invalid.push(header.number);
+                }
+            }
+        }
+        // A parent checked in this chunk is not verified if its child does not link to it
+        numbers.retain(|number| !unlinked_parents.contains(number));
+
+        let result = sqlx::query("UPDATE blockheaders SET verified = TRUE WHERE number = ANY($1)")
+            .bind(&numbers)
+            .execute(pool)
+            .await
+            .context("Failed to verify blockheaders")?;
+        verification.verified_count += result.rows_affected();
+        sqlx::query("UPDATE blockheaders SET verified = FALSE WHERE number = ANY($1)")
+            .bind(&unlinked_parents)
+            .execute(pool)
+            .await
+            .context("Failed to unverify blockheaders")?;
+        verification.unlinked_parents.extend(unlinked_parents);
+    }
+}
+
+/**
+ * First verified stored block from start to end (inclusive)
+ */
+pub async fn find_first_verified(
+    pool: &Pool<Postgres>,
+    start: i64,
+    end: i64,
+) -> Result<Option<i64>> {
+    let result: (Option<i64>,) = sqlx::query_as(
+        "SELECT MIN(number) FROM blockheaders WHERE number BETWEEN $1 AND $2 AND verified",
+    )
+    .bind(start)
+    .bind(end)
+    .fetch_one(pool)
+    .await
+    .context("Failed to find first verified block")?;
+
+    Ok(result.0)
+}
+
+/**
+ * Clears the verified flag of a stored block, so it is checked against its stored parent again
+ */
+pub async fn unverify_blockheader(pool: &Pool<Postgres>, number: i64) -> Result<()> {
+    sqlx::query("UPDATE blockheaders SET verified = FALSE WHERE number = $1")
+        .bind(number)
+        .execute(pool)
+        .await
+        .context("Failed to unverify blockheader")?;
+    Ok(())
+}
+
+/**
+ * Records the MMR leaf index of stored blocks, given as (block number, leaf index)
+ */
+pub async fn set_mmr_leaf_indexes(
+    pool: &Pool<Postgres>,
+    leaf_indexes: &[(i64, i64)],
+) -> Result<()> {
+    if leaf_indexes.is_empty() {
+        return Ok(());
+    }
+
+    let (numbers, indexes): (Vec<i64>, Vec<i64>) = leaf_indexes.iter().copied().unzip();
+    sqlx::query(
+        r#"
+        UPDATE blockheaders SET mmr_leaf_index = leaves.leaf_index
+            FROM UNNEST($1::BIGINT[], $2::BIGINT[]) AS leaves(number, leaf_index)
+            WHERE blockheaders.number = leaves.number
+        "#,
+    )
+    .bind(numbers)
+    .bind(indexes)
+    .execute(pool)
+    .await
+    .context("Failed to set mmr leaf indexes")?;
+    Ok(())
+}
+
+/**
+ * Clears the MMR leaf index of the stored blocks after block_number
+ */
+pub async fn clear_mmr_leaf_indexes_after(pool: &Pool<Postgres>, block_number: i64) -> Result<()> {
+    sqlx::query(
+        "UPDATE blockheaders SET mmr_leaf_index = NULL WHERE number > $1 AND mmr_leaf_index IS NOT NULL",
+    )
+    .bind(block_number)
+    .execute(pool)
+    .await
+    .context("Failed to clear mmr leaf indexes")?;
+    Ok(())
+}
+
+/**
+ * Empty tables for the roots and leaf indexes recorded by an MMR rebuild, dropping any previous ones
+ */
+pub async fn create_mmr_rebuild_tables(pool: &Pool<Postgres>) -> Result<()> {
+    sqlx::raw_sql(&format!(
+        r#"
+        DROP TABLE IF EXISTS {MMR_ROOTS_REBUILD_TABLE}, {MMR_LEAF_INDEXES_REBUILD_TABLE};
+        CREATE TABLE {MMR_ROOTS_REBUILD_TABLE} (LIKE mmr_roots INCLUDING ALL);
+        CREATE TABLE {MMR_LEAF_INDEXES_REBUILD_TABLE} (
+            number BIGINT PRIMARY KEY,
+            leaf_index BIGINT NOT NULL
+        );
+        "#
+    ))
+    .execute(pool)
+    .await
+    .context("Failed to create mmr rebuild tables")?;
+    Ok(())
+}
+
+/**
+ * Records the MMR leaf index of stored blocks rebuilt, given as (block number, leaf index)
+ */
+pub async fn set_rebuilt_mmr_leaf_indexes(
+    pool: &Pool<Postgres>,
+    leaf_indexes: &[(i64, i64)],
+) -> Result<()> {
+    if leaf_indexes.is_empty() {
+        return Ok(());
+    }
+
+    let (numbers, indexes): (Vec<i64>, Vec<i64>) = leaf_indexes.iter().copied().unzip();
+    sqlx::query(&format!(
+        r#"
+        INSERT INTO {MMR_LEAF_INDEXES_REBUILD_TABLE} (number, leaf_index)
+            SELECT * FROM UNNEST($1::BIGINT[], $2::BIGINT[])
+            ON CONFLICT (number) DO UPDATE SET leaf_index = EXCLUDED.leaf_index
+        "#
+    ))
+    .bind(numbers)
+    .bind(indexes)
+    .execute(pool)
+    .await
+    .context("Failed to set rebuilt mmr leaf indexes")?;
+    Ok(())
+}
+
+/**
+ * Removes the rebuilt MMR leaf indexes of the blocks after block_number
+ */
+pub async fn clear_rebuilt_mmr_leaf_indexes_after(
+    pool: &Pool<Postgres>,
+    block_number: i64,
+) -> Result<()> {
+    sqlx::query(&format!(
+        "DELETE FROM {MMR_LEAF_INDEXES_REBUILD_TABLE} WHERE number > $1"
+    ))
+    .bind(block_number)
+    .execute(pool)
+    .await
+    .context("Failed to clear rebuilt mmr leaf indexes")?;
+    Ok(())
+}
+
+/**
+ * Replaces the roots of the MMRs mmr_ids and the leaf indexes of the stored blocks with the rebuilt ones, clearing
+ * the leaf indexes after last_blocknumber, then drops the rebuild tables. Runs on conn, so it can share the
+ * transaction that swaps the MMR nodes.
+ */
+pub async fn move_rebuilt_mmr_records(
+    conn: &mut PgConnection,
+    mmr_ids: &[String],
+    last_blocknumber: i64,
+) -> Result<()> {
+    sqlx::query("DELETE FROM mmr_roots WHERE mmr_id = ANY($1)")
+        .bind(mmr_ids)
+        .execute(&mut *conn)
+        .await
+        .context("Failed to delete replaced mmr roots")?;
+    sqlx::query(&format!(
+        r#"
+        INSERT INTO mmr_roots (mmr_id, block_number, elements_count, root_hash, created_at)
+            SELECT mmr_id, block_number, elements_count, root_hash, created_at FROM {MMR_ROOTS_REBUILD_TABLE}
+        "#
+    ))
+    .execute(&mut *conn)
+    .await
+    .context("Failed to move rebuilt mmr roots")?;
+    sqlx::query(&format!(
+        r#"
+        UPDATE blockheaders SET mmr_leaf_index = leaves.leaf_index
+            FROM {MMR_LEAF_INDEXES_REBUILD_TABLE} AS leaves
+            WHERE blockheaders.number = leaves.number
+                AND blockheaders.mmr_leaf_index IS DISTINCT FROM leaves.leaf_index
+        "#
+    ))
+    .execute(&mut *conn)
+    .await
+    .context("Failed to move rebuilt mmr leaf indexes")?;
+    sqlx::query(
+        "UPDATE blockheaders SET mmr_leaf_index = NULL WHERE number > $1 AND mmr_leaf_index IS NOT NULL",
+    )
+    .bind(last_blocknumber)
+    .execute(&mut *conn)
+    .await
+    .context("Failed to clear mmr leaf indexes")?;
+    sqlx::query(&format!(
+        "DROP TABLE {MMR_ROOTS_REBUILD_TABLE}, {MMR_LEAF_INDEXES_REBUILD_TABLE}"
+    ))
+    .execute(&mut *conn)
+    .await
+    .context("Failed to drop mmr rebuild tables")?;
+    Ok(())
+}
+
+/**
+ * Hashes of the stored blocks among blocknumbers, by block number
+ */
+pub async fn get_block_hashes(
+    pool: &Pool<Postgres>,
+    blocknumbers: &[i64],
+) -> Result<HashMap<i64, String>> {
+    let rows: Vec<(i64, Option<String>)> =
+        sqlx::query_as("SELECT number, block_hash FROM blockheaders WHERE number = ANY($1)")
+            .bind(blocknumbers)
+            .fetch_all(pool)
+            .await
+            .context("Failed to get block hashes")?;
+
+    Ok(rows
+        .into_iter()
+        .filter_map(|(number, block_hash)| Some((number, block_hash?)))
+        .collect())
+}
+
+pub async fn get_blockheader_by_number(
+    pool: &Pool<Postgres>,
+    number: i64,
+) -> Result<Option<StoredBlockHeader>> {
+    sqlx::query_as(
+        r#"
+        SELECT block_hash, number, gas_limit, gas_used, base_fee_per_gas,
+            nonce, transaction_root, receipts_root, state_root, parent_hash, mmr_leaf_index,
+            sha3_uncles, miner, logs_bloom, difficulty, timestamp, extra_data, mix_hash,
+            withdrawals_root, blob_gas_used, excess_blob_gas, parent_beacon_block_root, requests_hash
+        FROM blockheaders
+            WHERE number = $1
+        "#,
+    )
+    .bind(number)
+    .fetch_optional(pool)
+    .await
+    .context("Failed to get blockheader by number")
+}
+
+pub async fn get_blockheader_by_hash(
+    pool: &Pool<Postgres>,
+    block_hash: &str,
+) -> Result<Option<StoredBlockHeader>> {
+    sqlx::query_as(
+        r#"
+        SELECT block_hash, number, gas_limit, gas_used, base_fee_per_gas,
+            nonce, transaction_root, receipts_root, state_root, parent_hash, mmr_leaf_index,
+            sha3_uncles, miner, logs_bloom, difficulty, timestamp, extra_data, mix_hash,
+            withdrawals_root, blob_gas_used, excess_blob_gas, parent_beacon_block_root, requests_hash
+        FROM blockheaders
+            WHERE block_hash = $1
+        "#,
+    )
+    .bind(block_hash)
+    .fetch_optional(pool)
+    .await
+    .context("Failed to get blockheader by hash")
+}
+
+/**
+ * Retrieves the stored blockheaders among blocknumbers, in block order
+ */
+pub async fn get_blockheaders_by_numbers(
+    pool: &Pool<Postgres>,
+    blocknumbers: &[i64],
+) -> Result<Vec<StoredBlockHeader>> {
+    sqlx::query_as(
+        r#"
+        SELECT block_hash, number, gas_limit, gas_used, base_fee_per_gas,
+            nonce, transaction_root, receipts_root, state_root, parent_hash, mmr_leaf_index,
+            sha3_uncles, miner, logs_bloom, difficulty, timestamp, extra_data, mix_hash,
+            withdrawals_root, blob_gas_used, excess_blob_gas, parent_beacon_block_root, requests_hash
+        FROM blockheaders
+            WHERE number = ANY($1)
+            ORDER BY number ASC
+        "#,
+    )
+    .bind(blocknumbers)
+    .fetch_all(pool)
+    .await
+    .context("Failed to get blockheaders by numbers")
+}
+
+/**
+ * Retrieves up to limit full blockheaders from from_blocknumber to to_blocknumber (inclusive), after the cursor
+ * blocknumber (exclusive) if given
+ */
+pub async fn get_blockheaders_in_range(
+    pool: &Pool<Postgres>,
+    from_blocknumber: i64,
+    to_blocknumber: i64,
+    after_blocknumber: Option<i64>,
+    limit: i64,
+) -> Result<Vec<StoredBlockHeader>> {
+    sqlx::query_as(
+        r#"
+        SELECT block_hash, number, gas_limit, gas_used, base_fee_per_gas,
+            nonce, transaction_root, receipts_root, state_root, parent_hash, mmr_leaf_index,
+            sha3_uncles, miner, logs_bloom, difficulty, timestamp, extra_data, mix_hash,
+            withdrawals_root, blob_gas_used, excess_blob_gas, parent_beacon_block_root, requests_hash
+        FROM blockheaders
+            WHERE number >= $1 AND number <= $2 AND ($3::BIGINT IS NULL OR number > $3)
+            ORDER BY number ASC
+            LIMIT $4
+        "#,
+    )
+    .bind(from_blocknumber)
+    .bind(to_blocknumber)
+    .bind(after_blocknumber)
+    .bind(limit)
+    .fetch_all(pool)
+    .await
+    .context("Failed to get blockheaders in range")
+}
+
+pub async fn get_transaction_by_hash(
+    pool: &Pool<Postgres>,
+    transaction_hash: &str,
+) -> Result<Option<StoredTransaction>> {
+    sqlx::query_as(
+        r#"
+        SELECT block_number, transaction_hash, transaction_index, from_addr, to_addr, value,
+            gas_price, max_priority_fee_per_gas, max_fee_per_gas, gas, chain_id
+        FROM transactions
+            WHERE transaction_hash = $1
+        "#,
+    )
+    .bind(transaction_hash)
+    .fetch_optional(pool)
+    .await
+    .context("Failed to get transaction by hash")
+}
+
+/**
+ * Retrieves up to limit transactions of a block ordered by index, after the cursor index (exclusive) if given
+ */
+pub async fn get_block_transactions(
+    pool: &Pool<Postgres>,
+    block_number: i64,
+    after_index: Option<i32>,
+    limit: i64,
+) -> Result<Vec<StoredTransaction>> {
+    sqlx::query_as(
+        r#"
+        SELECT block_number, transaction_hash, transaction_index, from_addr, to_addr, value,
+            gas_price, max_priority_fee_per_gas, max_fee_per_gas, gas, chain_id
+        FROM transactions
+            WHERE block_number = $1 AND ($2::INTEGER IS NULL OR transaction_index > $2)
+            ORDER BY transaction_index ASC
+            LIMIT $3
+        "#,
+    )
+    .bind(block_number)
+    .bind(after_index)
+    .bind(limit)
+    .fetch_all(pool)
+    .await
+    .context("Failed to get block transactions")
+}
+
+/**
+ * Retrieves up to limit transactions sent and/or received by an address in a block range (inclusive), ordered by
+ * block number and index, after the cursor (block number, index) if given
+ */
+pub async fn get_address_transactions(
+    pool: &Pool<Postgres>,
+    address: &str,
+    direction: TransactionDirection,
+    from_blocknumber: i64,
+    to_blocknumber: i64,
+    after: Option<(i64, i32)>,
+    limit: i64,
+) -> Result<Vec<StoredTransaction>> {
+    let mut query_builder: QueryBuilder<Postgres> = QueryBuilder::new(
+        "SELECT block_number, transaction_hash, transaction_index, from_addr, to_addr, value,
+            gas_price, max_priority_fee_per_gas, max_fee_per_gas, gas, chain_id
+        FROM transactions WHERE ",
+    );
+
+    match direction {
+        TransactionDirection::From => {
+            query_builder.push("from_addr = ").push_bind(address);
+        }
+        TransactionDirection::To => {
+            query_builder.push("to_addr = ").push_bind(address);
+        }
+        TransactionDirection::Any => {
+            query_builder
+                .push("(from_addr = ")
+                .push_bind(address)
+                .push(" OR to_addr = ")
+                .push_bind(address)
+                .push(")");
+        }
+    }
+    query_builder
+        .push(" AND block_number BETWEEN ")
+        .push_bind(from_blocknumber)
+        .push(" AND ")
+        .push_bind(to_blocknumber);
+    if let Some((block_number, transaction_index)) = after {
+        query_builder
+            .push(" AND (block_number, transaction_index) > (")
+            .push_bind(block_number)
+            .push(", ")
+            .push_bind(transaction_index)
+            .push(")");
+    }
+    query_builder
+        .push(" ORDER BY block_number ASC, transaction_index ASC LIMIT ")
+        .push_bind(limit);
+
+    query_builder
+        .build_query_as()
+        .fetch_all(pool)
+        .await
+        .context("Failed to get address transactions")
+}
+
+/**
+ * Splits the provided range (inclusive) into leases of range_size blocks, aligned to multiples of range_size.
+ * Existing leases are kept whatever their status: the blocks of a window past its last lease, e.g. when the range
+ * now extends past a tail lease, get a lease of their own.
+ *
+ * @Returns number of leases created
+ */
+pub async fn create_range_leases(
+    pool: &Pool<Postgres>,
+    start: i64,
+    end: i64,
+    range_size: i64,
+) -> Result<u64> {
+    let result = sqlx::query(
+        r#"
+        INSERT INTO range_leases (range_start, range_end)
+            SELECT COALESCE(covered.range_end + 1, s), LEAST(s + $3 - 1, $2)
+            FROM generate_series(($1 / $3) * $3, $2, $3) AS s
+            CROSS JOIN LATERAL (
+                SELECT MAX(range_end) AS range_end FROM range_leases
+                    WHERE range_start BETWEEN s AND s + $3 - 1
+            ) AS covered
+            WHERE covered.range_end IS NULL OR covered.range_end < LEAST(s + $3 - 1, $2)
+        ON CONFLICT (range_start) DO NOTHING
+        "#,
+    )
+    .bind(start)
+    .bind(end)
+    .bind(range_size)
+    .execute(pool)
+    .await
+    .context("Failed to create range leases")?;
+
+    Ok(result.rows_affected())
+}
+
+/**
+ * Claims the lowest range that is pending or whose lease has expired
+ *
+ * @Returns the claimed range, else None if there is no work left
+ */
+pub async fn claim_range_lease(
+    pool: &Pool<Postgres>,
+    worker_id: &str,
+    lease_duration: u64,
+) -> Result<Option<RangeLease>> {
+    let result: Option<RangeLease> = sqlx::query_as(
+        r#"
+        UPDATE range_leases
+            SET status = 'claimed', worker_id = $1,
+                lease_expires_at = NOW() + make_interval(secs => $2), updated_at = NOW()
+            WHERE range_start = (
+                SELECT range_start FROM range_leases
+                    WHERE status = 'pending' OR (status = 'claimed' AND lease_expires_at < NOW())
+                    ORDER BY range_start ASC
+                    LIMIT 1
+                    FOR UPDATE SKIP LOCKED
+            )
+            RETURNING range_start, range_end
+        "#,
+    )
+    .bind(worker_id)
+    .bind(lease_duration as f64)
+    .fetch_optional(pool)
+    .await
+    .context("Failed to claim range lease")?;
+
+    Ok(result)
+}
+
+/**
+ * Extends the lease on a range held by the worker
+ *
+ * @Returns false if the worker no longer holds the lease
+ */
+pub async fn renew_range_lease(
+    pool: &Pool<Postgres>,
+    range_start: i64,
+    worker_id: &str,
+    lease_duration: u64,
+) -> Result<bool> {
+    let result = sqlx::query(
+        r#"
+        UPDATE range_leases
+            SET lease_expires_at = NOW() + make_interval(secs => $3), updated_at = NOW()
+            WHERE range_start = $1 AND worker_id = $2 AND status = 'claimed'
+        "#,
+    )
+    .bind(range_start)
+    .bind(worker_id)
+    .bind(lease_duration as f64)
+    .execute(pool)
+    .await
+    .context("Failed to renew range lease")?;
+
+    Ok(result.rows_affected() == 1)
+}
+
+/**
+ * Marks a range held by the worker as done
+ *
+ * @Returns false if the worker no longer holds the lease
+ */
+pub async fn complete_range_lease(
+    pool: &Pool<Postgres>,
+    range_start: i64,
+    worker_id: &str,
+) -> Result<bool> {
+    let result = sqlx::query(
+        r#"
+        UPDATE range_leases
+            SET status = 'done', lease_expires_at = NULL, updated_at = NOW()
+            WHERE range_start = $1 AND worker_id = $2 AND status = 'claimed'
+        "#,
+    )
+    .bind(range_start)
+    .bind(worker_id)
+    .execute(pool)
+    .await
+    .context("Failed to complete range lease")?;
+
+    Ok(result.rows_affected() == 1)
+}
+
+/**
+ * Hands a range held by the worker back, so that another worker can claim it right away
+ *
+ * @Returns false if the worker no longer holds the lease
+ */
+pub async fn release_range_lease(
+    pool: &Pool<Postgres>,
+    range_start: i64,
+    worker_id: &str,
+) -> Result<bool> {
+    let result = sqlx::query(
+        r#"
+        UPDATE range_leases
+            SET status = 'pending', worker_id = NULL, lease_expires_at = NULL, updated_at = NOW()
+            WHERE range_start = $1 AND worker_id = $2 AND status = 'claimed'
+        "#,
+    )
+    .bind(range_start)
+    .bind(worker_id)
+    .execute(pool)
+    .await
+    .context("Failed to release range lease")?;
+
+    Ok(result.rows_affected() == 1)
+}
+
+/**
+ * Retrieves the last blocknumber of the contiguous prefix of completed leases
+ *
+ * @Returns blocknumber before the first unfinished lease, else None if all leases are done
+ */
+pub async fn get_completed_leases_prefix_end(pool: &Pool<Postgres>) -> Result<Option<i64>> {
+    let result: (Option<i64>,) =
+        sqlx::query_as("SELECT MIN(range_start) - 1 FROM range_leases WHERE status <> 'done'")
+            .fetch_one(pool)
+            .await
+            .context("Failed to get completed leases prefix end")?;
+
+    Ok(result.0)
+}
+
+/**
+ * Records the roots produced by appends to the MMR mmr_id, replacing any recorded for the same blocks
+ */
+pub async fn write_mmr_roots(pool: &Pool<Postgres>, mmr_id: &str, roots: &[MmrRoot]) -> Result<()> {
+    write_mmr_roots_into(pool, "mmr_roots", mmr_id, roots).await
+}
+
+/**
+ * Records the roots produced by appends to the MMR mmr_id being rebuilt
+ */
+pub async fn write_rebuilt_mmr_roots(
+    pool: &Pool<Postgres>,
+    mmr_id: &str,
+    roots: &[MmrRoot],
+) -> Result<()> {
+    write_mmr_roots_into(pool, MMR_ROOTS_REBUILD_TABLE, mmr_id, roots).await
+}
+
+async fn write_mmr_roots_into(
+    pool: &Pool<Postgres>,
+    table: &str,
+    mmr_id: &str,
+    roots: &[MmrRoot],
+) -> Result<()> {
+    if roots.is_empty() {
+        return Ok(());
+    }
+
+    let mut query_builder: QueryBuilder<Postgres> = QueryBuilder::new(format!(
+        "INSERT INTO {table} (mmr_id, block_number, elements_count, root_hash, created_at) "
+    ));
+    query_builder.push_values(roots, |mut b, root| {
+        b.push_bind(mmr_id)
+            .push_bind(root.block_number)
+            .push_bind(root.elements_count)
+            .push_bind(&root.root_hash)
+            .push_bind(root.created_at);
+    });
+    query_builder.push(
+        " ON CONFLICT (mmr_id, block_number) DO UPDATE SET
+            elements_count = EXCLUDED.elements_count,
+            root_hash = EXCLUDED.root_hash,
+            created_at = EXCLUDED.created_at",
+    );
+
+    query_builder
+        .build()
+        .execute(pool)
+        .await
+        .context("Failed to write mmr roots")?;
+    Ok(())
+}
+
+/**
+ * Deletes the roots recorded for the MMR mmr_id after block_number
+ */
+pub async fn delete_mmr_roots_after(
+    pool: &Pool<Postgres>,
+    mmr_id: &str,
+    block_number: i64,
+) -> Result<()> {
+    delete_mmr_roots_after_in(pool, "mmr_roots", mmr_id, block_number).await
+}
+
+/**
+ * Deletes the roots recorded for the MMR mmr_id being rebuilt after block_number
+ */
+pub async fn delete_rebuilt_mmr_roots_after(
+    pool: &Pool<Postgres>,
+    mmr_id: &str,
+    block_number: i64,
+) -> Result<()> {
+    delete_mmr_roots_after_in(pool, MMR_ROOTS_REBUILD_TABLE, mmr_id, block_number).await
+}
+
+async fn delete_mmr_roots_after_in(
+    pool: &Pool<Postgres>,
+    table: &str,
+    mmr_id: &str,
+    block_number: i64,
+) -> Result<()> {
+    sqlx::query(&format!(
+        "DELETE FROM {table} WHERE mmr_id = $1 AND block_number > $2"
+    ))
+    .bind(mmr_id)
+    .bind(block_number)
+    .execute(pool)
+    .await
+    .context("Failed to delete mmr roots")?;
+    Ok(())
+}
+
+pub async fn get_latest_mmr_root(pool: &Pool<Postgres>, mmr_id: &str) -> Result<Option<MmrRoot>> {
+    sqlx::query_as(
+        r#"
+        SELECT block_number, elements_count, root_hash, created_at FROM mmr_roots
+            WHERE mmr_id = $1
+            ORDER BY block_number DESC
+            LIMIT 1
+        "#,
+    )
+    .bind(mmr_id)
+    .fetch_optional(pool)
+    .await
+    .context("Failed to get latest mmr root")
+}
+
+pub async fn get_mmr_root(
+    pool: &Pool<Postgres>,
+    mmr_id: &str,
+    block_number: i64,
+) -> Result<Option<MmrRoot>> {
+    sqlx::query_as(
+        r#"
+        SELECT block_number, elements_count, root_hash, created_at FROM mmr_roots
+            WHERE mmr_id = $1 AND block_number = $2
+        "#,
+    )
+    .bind(mmr_id)
+    .bind(block_number)
+    .fetch_optional(pool)
+    .await
+    .context("Failed to get mmr root")
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::types::Transaction;
+    use chrono::Utc;
+
+    /**
+     * Pool on DB_CONNECTION_STRING with the tables created in a fresh schema of their own, so tests running at
+     * once do not see each other's rows
+     */
+    pub(crate) async fn test_pool(schema: &str) -> Pool<Postgres> {
+        let connection_string = dotenvy::var("DB_CONNECTION_STRING")
+            .expect("DB_CONNECTION_STRING must be set to run the database tests");
+        let conn_options: PgConnectOptions = connection_string.parse().unwrap();
+        let pool = PgPoolOptions::new()
+            .max_connections(2)
+            .connect_with(conn_options.clone())
+            .await
+            .unwrap();
+        sqlx::raw_sql(&format!(
+            "DROP SCHEMA IF EXISTS {schema} CASCADE; CREATE SCHEMA {schema}"
+        ))
+        .execute(&pool)
+        .await
+        .unwrap();
+        pool.close().await;
+
+        let pool = PgPoolOptions::new()
+            .max_connections(2)
+            .connect_with(conn_options.options([("search_path", schema)]))
+            .await
+            .unwrap();
+        create_tables(&pool).await.unwrap();
+        pool
+    }
+
+    /**
+     * Block number of the chain fork, whose parent is parent_hash, with one transaction. Blocks of different forks
+     * differ by miner. Its hash is the keccak256 of its header RLP, so it can be verified.
+     */
+    pub(crate) fn test_header(
+        number: i64,
+        parent_hash: &str,
+        fork: u64,
+    ) -> BlockHeaderWithFullTransaction {
+        let mut header = BlockHeaderWithFullTransaction {
+            gas_limit: "0x1c9c380".to_string(),
+            gas_used: "0x5208".to_string(),
+            base_fee_per_gas: Some("0x7".to_string()),
+            hash: String::new(),
+            nonce: Some("0x0000000000000000".to_string()),
+            number: format!("{number:#x}"),
+            parent_hash: parent_hash.to_string(),
+            receipts_root: format!("0x{}", "11".repeat(32)),
+            state_root: format!("0x{}", "22".repeat(32)),
+            transactions_root: format!("0x{}", "33".repeat(32)),
+            sha3_uncles: format!("0x{}", "44".repeat(32)),
+            miner: format!("0x{fork:040x}"),
+            logs_bloom: format!("0x{}", "00".repeat(256)),
+            difficulty: "0x0".to_string(),
+            timestamp: format!("{:#x}", 1_700_000_000 + 12 * number),
+            extra_data: "0x".to_string(),
+            mix_hash: format!("0x{}", "55".repeat(32)),
+            withdrawals_root: None,
+            blob_gas_used: None,
+            excess_blob_gas: None,
+            parent_beacon_block_root: None,
+            requests_hash: None,
+            transactions: vec![Transaction {
+                hash: format!("0x{:064x}", (fork << 32) + number as u64),
+                block_number: format!("{number:#x}"),
+                transaction_index: "0x0".to_string(),
+                value: "0x1".to_string(),
+                gas_price: "0x2".to_string(),
+                gas: "0x5208".to_string(),
+                from: Some(format!("0x{:040x}", 1)),
+                to: Some(format!("0x{:040x}", 2)),
+                max_priority_fee_per_gas: None,
+                max_fee_per_gas: None,
+                chain_id: Some("0x1".to_string()),
+            }],
+        };
+        let encoded = header_rlp::encode_fields(&StoredBlockHeader::from(&header))
+            .unwrap()
+            .unwrap();
+        header.hash = format!("0x{}", hex::encode(header_rlp::keccak256(&encoded)));
+        header
+    }
+
+    /**
+     * Blocks 0 to len - 1 of the chain fork
+     */
+    pub(crate) fn test_chain(len: i64, fork: u64) -> Vec<BlockHeaderWithFullTransaction> {
+        let mut chain: Vec<BlockHeaderWithFullTransaction> = Vec::new();
+        for number in 0..len {
+            let parent_hash = chain.last().map_or_else(
+                || format!("0x{}", "00".repeat(32)),
+                |parent| parent.hash.clone(),
+            );
+            chain.push(test_header(number, &parent_hash, fork));
+        }
+        chain
+    }
+
+    async fn verified_blocks(pool: &Pool<Postgres>) -> Vec<i64> {
+        sqlx::query_scalar("SELECT number FROM blockheaders WHERE verified ORDER BY number")
+            .fetch_all(pool)
+            .await
+            .unwrap()
+    }
+
+    async fn block_transactions(pool: &Pool<Postgres>, block_number: i64) -> Vec<String> {
+        get_block_transactions(pool, block_number, None, 10)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|transaction| transaction.transaction_hash)
+            .collect()
+    }
+
+    async fn claim(
+        pool: &Pool<Postgres>,
+        worker_id: &str,
+        lease_duration: u64,
+    ) -> Option<(i64, i64)> {
+        claim_range_lease(pool, worker_id, lease_duration)
+            .await
+            .unwrap()
+            .map(|lease| (lease.range_start, lease.range_end))
+    }
+
+    fn root(block_number: i64, root_hash: &str) -> MmrRoot {
+        MmrRoot {
+            block_number,
+            elements_count: block_number + 1,
+            root_hash: root_hash.to_string(),
+            created_at: Utc::now(),
+        }
+    }
+
+    async fn latest_root_hash(pool: &Pool<Postgres>, mmr_id: &str) -> Option<(i64, String)> {
+        get_latest_mmr_root(pool, mmr_id)
+            .await
+            .unwrap()
+            .map(|root| (root.block_number, root.root_hash))
+    }
+
+    #[tokio::test]
+    #[ignore = "needs Postgres at DB_CONNECTION_STRING"]
+    async fn rebuilt_mmr_records_replace_the_live_ones_on_commit() {
+        let pool = test_pool("test_rebuilt_mmr_records").await;
+        let mmr_id = "blockheaders_mmr";
+        let mmr_ids = [mmr_id.to_string()];
+        delete_mmr_roots_after(&pool, mmr_id, -1).await.unwrap();
+        write_mmr_roots(&pool, mmr_id, &[root(0, "0xold"), root(1, "0xold")])
+            .await
+            .unwrap();
+
+        create_mmr_rebuild_tables(&pool).await.unwrap();
+        write_rebuilt_mmr_roots(&pool, mmr_id, &[root(0, "0xnew")])
+            .await
+            .unwrap();
+        assert_eq!(
+            latest_root_hash(&pool, mmr_id).await,
+            Some((1, "0xold".to_string()))
+        );
+
+        let mut tx = pool.begin().await.unwrap();
+        move_rebuilt_mmr_records(&mut tx, &mmr_ids, i64::MAX)
+            .await
+            .unwrap();
+        tx.rollback().await.unwrap();
+        assert_eq!(
+            latest_root_hash(&pool, mmr_id).await,
+            Some((1, "0xold".to_string()))
+        );
+
+        let mut tx = pool.begin().await.unwrap();
+        move_rebuilt_mmr_records(&mut tx, &mmr_ids, i64::MAX)
+            .await
+            .unwrap();
+        tx.commit().await.unwrap();
+        assert_eq!(
+            latest_root_hash(&pool, mmr_id).await,
+            Some((0, "0xnew".to_string()))
+        );
+        assert!(get_mmr_root(&pool, mmr_id, 1).await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    #[ignore = "needs Postgres at DB_CONNECTION_STRING"]
+    async fn expired_lease_is_claimed_by_another_worker() {
+        let pool = test_pool("test_expired_lease").await;
+        assert_eq!(create_range_leases(&pool, 0, 19, 10).await.unwrap(), 2);
+
+        assert_eq!(claim(&pool, "worker-1", 1).await, Some((0, 9)));
+        assert_eq!(claim(&pool, "worker-2", 60).await, Some((10, 19)));
+        // Held leases are not claimed again before they expire
+        assert_eq!(claim(&pool, "worker-3", 60).await, None);
+        assert_eq!(
+            get_completed_leases_prefix_end(&pool).await.unwrap(),
+            Some(-1)
+        );
+
+        // worker-1 stops renewing its lease
+        tokio::time::sleep(Duration::from_millis(1_500)).await;
+        assert_eq!(claim(&pool, "worker-3", 60).await, Some((0, 9)));
+        assert!(!renew_range_lease(&pool, 0, "worker-1", 60).await.unwrap());
+        assert!(!complete_range_lease(&pool, 0, "worker-1").await.unwrap());
+
+        assert!(complete_range_lease(&pool, 0, "worker-3").await.unwrap());
+        assert_eq!(
+            get_completed_leases_prefix_end(&pool).await.unwrap(),
+            Some(9)
+        );
+        assert!(complete_range_lease(&pool, 10, "worker-2").await.unwrap());
+        assert_eq!(get_completed_leases_prefix_end(&pool).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    #[ignore = "needs Postgres at DB_CONNECTION_STRING"]
+    async fn block_not_linking_to_its_parent_gets_both_fetched_again() {
+        let pool = test_pool("test_unlinked_parent").await;
+        let mut chain = test_chain(5, 0).into_iter();
+        let wrong_block = test_header(2, &test_chain(2, 0)[1].hash, 1);
+        let wrong_transactions = wrong_block.transactions[0].hash.clone();
+
+        // A faulty node served another block 2, which links to block 1
+        write_blockheader(&pool, chain.next().unwrap())
+            .await
+            .unwrap();
+        write_blockheader(&pool, chain.next().unwrap())
+            .await
+            .unwrap();
+        write_blockheader(&pool, wrong_block).await.unwrap();
+        let block_2 = chain.next().unwrap();
+        for header in chain {
+            write_blockheader(&pool, header).await.unwrap();
+        }
+
+        let verification = verify_blockheaders(&pool, 0, 4).await.unwrap();
+        assert_eq!(verification.unlinked_parents, vec![2]);
+        assert!(verification.invalid.is_empty());
+        assert_eq!(verified_blocks(&pool).await, vec![0, 1, 4]);
+        assert_eq!(block_transactions(&pool, 2).await, vec![wrong_transactions]);
+
+        // Fetching it again replaces it, with its transactions
+        let block_2_hash = block_2.hash.clone();
+        let block_2_transactions = block_2.transactions[0].hash.clone();
+        write_blockheader(&pool, block_2).await.unwrap();
+        let verification = verify_blockheaders(&pool, 0, 4).await.unwrap();
+        assert_eq!(verification.verified_count, 2);
+        assert!(verification.unlinked_parents.is_empty());
+        assert_eq!(verified_blocks(&pool).await, vec![0, 1, 2, 3, 4]);
+        assert_eq!(
+            get_block_hashes(&pool, &[2]).await.unwrap()[&2],
+            block_2_hash
+        );
+        assert_eq!(
+            block_transactions(&pool, 2).await,
+            vec![block_2_transactions]
+        );
+
+        // Verified blocks are kept
+        let other_block = test_header(2, &test_chain(2, 0)[1].hash, 2);
+        write_blockheader(&pool, other_block).await.unwrap();
+        assert_eq!(
+            get_block_hashes(&pool, &[2]).await.unwrap()[&2],
+            block_2_hash
+        );
+    }
+
+    #[tokio::test]
+    #[ignore = "needs Postgres at DB_CONNECTION_STRING"]
+    async fn invalid_stored_header_gets_fetched_again() {
+        let pool = test_pool("test_invalid_header").await;
+        for header in test_chain(3, 0) {
+            write_blockheader(&pool, header).await.unwrap();
+        }
+        sqlx::query("UPDATE blockheaders SET gas_used = gas_used + 1 WHERE number = 1")
+            .execute(&pool)
+            .await
+            .unwrap();
+
+        let verification = verify_blockheaders(&pool, 0, 2).await.unwrap();
+        assert_eq!(verification.invalid, vec![1]);
+        assert_eq!(verified_blocks(&pool).await, vec![0, 2]);
+
+        // Headers not hashing to their block hash are refused
+        let mut tampered = test_chain(2, 0).pop().unwrap();
+        tampered.gas_used = "0x1".to_string();
+        assert!(write_blockheader(&pool, tampered).await.is_err());
+
+        assert!(write_blockheader(&pool, test_chain(2, 0).pop().unwrap())
+            .await
+            .unwrap());
+        let verification = verify_blockheaders(&pool, 0, 2).await.unwrap();
+        assert!(verification.invalid.is_empty());
+        assert_eq!(verified_blocks(&pool).await, vec![0, 1, 2]);
+
+        // Verified blocks are left as they are
+        assert!(!write_blockheader(&pool, test_chain(3, 0).pop().unwrap())
+            .await
+            .unwrap());
+    }
+
+    #[tokio::test]
+    #[ignore = "needs Postgres at DB_CONNECTION_STRING"]
+    async fn blocks_past_the_tail_lease_get_leased() {
+        let pool = test_pool("test_tail_lease").await;
+        assert_eq!(create_range_leases(&pool, 0, 12, 10).await.unwrap(), 2);
+        assert_eq!(claim(&pool, "worker-1", 60).await, Some((0, 9)));
+        assert_eq!(claim(&pool, "worker-1", 60).await, Some((10, 12)));
+
+        // Claimed tail lease, by a worker started with a larger end
+        assert_eq!(create_range_leases(&pool, 0, 14, 10).await.unwrap(), 1);
+        assert_eq!(claim(&pool, "worker-2", 60).await, Some((13, 14)));
+        assert!(complete_range_lease(&pool, 13, "worker-2").await.unwrap());
+        // Completed tail lease
+        assert_eq!(create_range_leases(&pool, 0, 16, 10).await.unwrap(), 1);
+        assert_eq!(create_range_leases(&pool, 0, 16, 10).await.unwrap(), 0);
+        // Pending tail lease, up to the next window
+        assert_eq!(create_range_leases(&pool, 0, 24, 10).await.unwrap(), 2);
+
+        assert_eq!(claim(&pool, "worker-2", 60).await, Some((15, 16)));
+        assert_eq!(claim(&pool, "worker-2", 60).await, Some((17, 19)));
+        assert_eq!(claim(&pool, "worker-2", 60).await, Some((20, 24)));
+        assert_eq!(claim(&pool, "worker-2", 60).await, None);
+    }
+
+    #[tokio::test]
+    #[ignore = "needs Postgres at DB_CONNECTION_STRING"]
+    async fn released_lease_is_claimed_again_right_away() {
+        let pool = test_pool("test_release_lease").await;
+        create_range_leases(&pool, 0, 9, 10).await.unwrap();
+        assert_eq!(claim(&pool, "worker-1", 300).await, Some((0, 9)));
+        assert!(!release_range_lease(&pool, 0, "worker-2").await.unwrap());
+        assert_eq!(claim(&pool, "worker-2", 300).await, None);
+
+        assert!(release_range_lease(&pool, 0, "worker-1").await.unwrap());
+        assert_eq!(claim(&pool, "worker-2", 300).await, Some((0, 9)));
+        assert!(!complete_range_lease(&pool, 0, "worker-1").await.unwrap());
+    }
+}
@@ -0,0 +1,12 @@
+ALTER TABLE blockheaders ADD COLUMN IF NOT EXISTS sha3_uncles CHAR(66);
+ALTER TABLE blockheaders ADD COLUMN IF NOT EXISTS miner CHAR(42);
+ALTER TABLE blockheaders ADD COLUMN IF NOT EXISTS logs_bloom TEXT;
+ALTER TABLE blockheaders ADD COLUMN IF NOT EXISTS difficulty VARCHAR(78);
+ALTER TABLE blockheaders ADD COLUMN IF NOT EXISTS timestamp BIGINT;
+ALTER TABLE blockheaders ADD COLUMN IF NOT EXISTS extra_data TEXT;
+ALTER TABLE blockheaders ADD COLUMN IF NOT EXISTS mix_hash CHAR(66);
+ALTER TABLE blockheaders ADD COLUMN IF NOT EXISTS withdrawals_root CHAR(66);
+ALTER TABLE blockheaders ADD COLUMN IF NOT EXISTS blob_gas_used VARCHAR(78);
+ALTER TABLE blockheaders ADD COLUMN IF NOT EXISTS excess_blob_gas VARCHAR(78);
+ALTER TABLE blockheaders ADD COLUMN IF NOT EXISTS parent_beacon_block_root CHAR(66);
+ALTER TABLE blockheaders ADD COLUMN IF NOT EXISTS requests_hash CHAR(66);
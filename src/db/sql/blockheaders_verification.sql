@@ -0,0 +1,5 @@
+ALTER TABLE blockheaders ADD COLUMN IF NOT EXISTS parent_hash CHAR(66);
+ALTER TABLE blockheaders ADD COLUMN IF NOT EXISTS verified BOOLEAN NOT NULL DEFAULT FALSE;
+ALTER TABLE blockheaders ADD COLUMN IF NOT EXISTS mmr_leaf_index BIGINT;
+CREATE INDEX IF NOT EXISTS blockheaders_unverified_index
+    ON blockheaders (number) WHERE NOT verified;
@@ -0,0 +1,8 @@
+CREATE TABLE IF NOT EXISTS mmr_roots (
+    mmr_id VARCHAR(255) NOT NULL,
+    block_number BIGINT NOT NULL,
+    elements_count BIGINT NOT NULL,
+    root_hash VARCHAR(66) NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    PRIMARY KEY (mmr_id, block_number)
+    );
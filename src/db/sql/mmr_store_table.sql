@@ -0,0 +1,4 @@
+CREATE TABLE IF NOT EXISTS mmr_store (
+    key TEXT PRIMARY KEY,
+    value TEXT NOT NULL
+    );
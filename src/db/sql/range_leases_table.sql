@@ -0,0 +1,8 @@
+CREATE TABLE IF NOT EXISTS range_leases (
+    range_start BIGINT PRIMARY KEY,
+    range_end BIGINT NOT NULL,
+    status VARCHAR(16) NOT NULL DEFAULT 'pending',
+    worker_id VARCHAR(255),
+    lease_expires_at TIMESTAMPTZ,
+    updated_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+    );
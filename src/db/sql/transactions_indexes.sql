@@ -0,0 +1,6 @@
+CREATE INDEX IF NOT EXISTS transactions_block_number_index
+    ON transactions (block_number, transaction_index);
+CREATE INDEX IF NOT EXISTS transactions_from_addr_index
+    ON transactions (from_addr, block_number, transaction_index);
+CREATE INDEX IF NOT EXISTS transactions_to_addr_index
+    ON transactions (to_addr, block_number, transaction_index);
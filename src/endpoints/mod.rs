@@ -1,18 +1,36 @@
 use anyhow::{Context, Result};
-use once_cell::sync::Lazy;
 use reqwest::Client;
 use serde::{Deserialize, Serialize};
-use std::time::Duration;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tracing::{info_span, Instrument};
 
+use crate::config::{redact_connection_string, RpcConfig};
+use crate::metrics::Metrics;
 use crate::types::{
     type_utils::convert_hex_string_to_i64, BlockHeaderWithEmptyTransaction,
     BlockHeaderWithFullTransaction,
 };
 
-static CLIENT: Lazy<Client> = Lazy::new(Client::new);
-static NODE_CONNECTION_STRING: Lazy<String> = Lazy::new(|| {
-    dotenvy::var("NODE_CONNECTION_STRING").expect("NODE_CONNECTION_STRING must be set")
-});
+#[derive(Clone)]
+pub struct RpcClient {
+    client: Client,
+    node_connection_string: String,
+    /// Connection string without credentials, recorded on spans
+    endpoint: String,
+    metrics: Arc<Metrics>,
+}
+
+impl RpcClient {
+    pub fn new(client: Client, config: &RpcConfig, metrics: Arc<Metrics>) -> Self {
+        Self {
+            client,
+            node_connection_string: config.connection_string.clone(),
+            endpoint: redact_connection_string(&config.connection_string),
+            metrics,
+        }
+    }
+}
 
 #[derive(Deserialize, Debug)]
 pub struct RpcResponse<T> {
@@ -29,7 +47,10 @@ struct RpcRequest<'a, T> {
     params: T,
 }
 
-pub async fn get_latest_finalized_blocknumber(timeout: Option<u64>) -> Result<i64> {
+pub async fn get_latest_finalized_blocknumber(
+    rpc: &RpcClient,
+    timeout: Option<u64>,
+) -> Result<i64> {
     let params = RpcRequest {
         jsonrpc: "2.0",
         id: "0",
@@ -37,9 +58,14 @@ pub async fn get_latest_finalized_blocknumber(timeout: Option<u64>) -> Result<i6
         params: vec!["finalized", "false"],
     };
 
-    match make_rpc_call::<_, BlockHeaderWithEmptyTransaction>(&params, timeout)
-        .await
-        .context("Failed to get latest block number")
+    match make_rpc_call::<_, BlockHeaderWithEmptyTransaction>(
+        rpc,
+        "finalized_block",
+        &params,
+        timeout,
+    )
+    .await
+    .context("Failed to get latest block number")
     {
         Ok(blockheader) => Ok(convert_hex_string_to_i64(&blockheader.number)),
         Err(e) => Err(e),
@@ -47,6 +73,7 @@ pub async fn get_latest_finalized_blocknumber(timeout: Option<u64>) -> Result<i6
 }
 
 pub async fn get_full_block_by_number(
+    rpc: &RpcClient,
     number: i64,
     timeout: Option<u64>,
 ) -> Result<BlockHeaderWithFullTransaction> {
@@ -57,25 +84,51 @@ pub async fn get_full_block_by_number(
         params: vec![format!("0x{:x}", number), true.to_string()],
     };
 
-    make_rpc_call::<_, BlockHeaderWithFullTransaction>(&params, timeout).await
+    make_rpc_call::<_, BlockHeaderWithFullTransaction>(rpc, "full_block", &params, timeout).await
 }
 
 async fn make_rpc_call<T: Serialize, R: for<'de> Deserialize<'de>>(
+    rpc: &RpcClient,
+    label: &str,
+    params: &T,
+    timeout: Option<u64>,
+) -> Result<R> {
+    let started = Instant::now();
+    let result = send_rpc_call(rpc, params, timeout)
+        .instrument(info_span!(
+            "rpc_call",
+            method = label,
+            rpc_endpoint = %rpc.endpoint
+        ))
+        .await;
+
+    rpc.metrics
+        .rpc_request_duration
+        .with_label_values(&[label])
+        .observe(started.elapsed().as_secs_f64());
+    if result.is_err() {
+        rpc.metrics.rpc_errors.with_label_values(&[label]).inc();
+    }
+    result
+}
+
+async fn send_rpc_call<T: Serialize, R: for<'de> Deserialize<'de>>(
+    rpc: &RpcClient,
     params: &T,
     timeout: Option<u64>,
 ) -> Result<R> {
     let raw_response = match timeout {
         Some(seconds) => {
-            CLIENT
-                .post(NODE_CONNECTION_STRING.as_str())
+            rpc.client
+                .post(rpc.node_connection_string.as_str())
                 .timeout(Duration::from_secs(seconds))
                 .json(params)
                 .send()
                 .await
         }
         None => {
-            CLIENT
-                .post(NODE_CONNECTION_STRING.as_str())
+            rpc.client
+                .post(rpc.node_connection_string.as_str())
                 .json(params)
                 .send()
                 .await
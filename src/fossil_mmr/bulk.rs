@@ -0,0 +1,368 @@
+use accumulators::{
+    hasher::Hasher,
+    mmr::{elements_count_to_leaf_count, find_peaks, MMR},
+    store::{InStoreTable, SubKey},
+};
+use anyhow::{bail, Result};
+use rayon::prelude::*;
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use super::{bag_peaks, elements_count_after_block, leaf_hash, node_height};
+use crate::config::MmrHasher;
+
+// Subtrees of up to 2^8 leaves are hashed as one parallel task
+const MAX_SUBTREE_HEIGHT: u32 = 8;
+const WRITE_BATCH_SIZE: usize = 10_000;
+
+/**
+ * Elements count and root hash of the MMR right after a leaf was appended
+ */
+pub(super) struct BulkRoot {
+    pub elements_count: usize,
+    pub root_hash: String,
+}
+
+/**
+ * A perfect subtree over aligned leaves, hashed on its own: its nodes in element order, and the bag of its peaks
+ * after each of its leaves
+ */
+struct Subtree {
+    height: u32,
+    nodes: Vec<String>,
+    local_bags: Vec<String>,
+}
+
+/**
+ * Appends the leaves of block_hashes to the MMR, writing the same nodes, counters and root as appending them one
+ * by one. Aligned perfect subtrees are hashed in parallel, then merged with the peaks in order.
+ *
+ * @Returns the elements count and root after each leaf
+ */
+pub(super) async fn bulk_append(
+    mmr: &MMR,
+    hasher_kind: MmrHasher,
+    hasher: Arc<dyn Hasher>,
+    block_hashes: Vec<String>,
+) -> Result<Vec<BulkRoot>> {
+    if block_hashes.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    let elements_count = mmr.elements_count.get().await?;
+    let leaves_count = mmr.leaves_count.get().await?;
+    let peaks = find_peaks(elements_count);
+    let peaks_hashes = mmr.retrieve_peaks_hashes(peaks.clone(), None).await?;
+    if peaks_hashes.len() != peaks.len() {
+        bail!("Missing peak hashes in the MMR store");
+    }
+    let peaks: Vec<(usize, String)> = peaks
+        .into_iter()
+        .map(node_height)
+        .zip(peaks_hashes)
+        .collect();
+
+    let (hashes, roots) = tokio::task::spawn_blocking(move || {
+        compute_append(
+            hasher_kind,
+            hasher.as_ref(),
+            elements_count,
+            peaks,
+            &block_hashes,
+        )
+    })
+    .await??;
+
+    // Nodes first, so an interrupted write leaves the counters at the previous size
+    let keys: Vec<String> = (elements_count + 1..=elements_count + hashes.len())
+        .map(|element_index| {
+            InStoreTable::get_full_key(&mmr.hashes.key, &element_index.to_string())
+        })
+        .collect();
+    let entries: Vec<(String, String)> = keys.into_iter().zip(hashes).collect();
+    for batch in entries.chunks(WRITE_BATCH_SIZE) {
+        mmr.hashes
+            .store
+            .set_many(batch.iter().cloned().collect::<HashMap<_, _>>())
+            .await?;
+    }
+
+    let Some(last_root) = roots.last() else {
+        bail!("No root computed");
+    };
+    mmr.elements_count.set(last_root.elements_count).await?;
+    mmr.root_hash
+        .set(&last_root.root_hash, SubKey::None)
+        .await?;
+    mmr.leaves_count.set(leaves_count + roots.len()).await?;
+
+    Ok(roots)
+}
+
+/**
+ * Hashes of the elements appended after elements_count, and the elements count and root after each leaf
+ */
+fn compute_append(
+    hasher_kind: MmrHasher,
+    hasher: &dyn Hasher,
+    elements_count: usize,
+    mut peaks: Vec<(usize, String)>,
+    block_hashes: &[String],
+) -> Result<(Vec<String>, Vec<BulkRoot>)> {
+    let first_leaf = elements_count_to_leaf_count(elements_count)?;
+
+    let mut ranges = Vec::new();
+    let mut offset = 0;
+    while offset < block_hashes.len() {
+        let leaf_index = first_leaf + offset;
+        let mut height = leaf_index.trailing_zeros().min(MAX_SUBTREE_HEIGHT);
+        while offset + (1 << height) > block_hashes.len() {
+            height -= 1;
+        }
+        ranges.push((offset, height));
+        offset += 1 << height;
+    }
+
+    let subtrees: Vec<Subtree> = ranges
+        .par_iter()
+        .map(|&(offset, height)| {
+            build_subtree(
+                hasher_kind,
+                hasher,
+                height,
+                &block_hashes[offset..offset + (1 << height)],
+            )
+        })
+        .collect::<Result<_>>()?;
+
+    // Peaks left of each subtree, which all are at least as high as it
+    let mut hashes = Vec::new();
+    let mut left_peaks = Vec::with_capacity(subtrees.len());
+    for subtree in &subtrees {
+        left_peaks.push(
+            peaks
+                .iter()
+                .map(|(_, hash)| hash.clone())
+                .collect::<Vec<_>>(),
+        );
+        hashes.extend_from_slice(&subtree.nodes);
+
+        let mut height = subtree.height as usize;
+        let mut hash = subtree.nodes[subtree.nodes.len() - 1].clone();
+        while let Some((left_height, left_hash)) = peaks.pop() {
+            if left_height != height {
+                peaks.push((left_height, left_hash));
+                break;
+            }
+            hash = hasher.hash(vec![left_hash, hash])?;
+            hashes.push(hash.clone());
+            height += 1;
+        }
+        peaks.push((height, hash));
+    }
+
+    // Merging the last two peaks keeps the bag unchanged, so each root bags the peaks left of the subtree with the
+    // subtree's own peaks
+    let roots: Vec<BulkRoot> = ranges
+        .par_iter()
+        .zip(&subtrees)
+        .zip(&left_peaks)
+        .flat_map_iter(|((&(offset, _), subtree), left_peaks)| {
+            subtree
+                .local_bags
+                .iter()
+                .enumerate()
+                .map(move |(i, local_bag)| {
+                    let mut bag = local_bag.clone();
+                    for peak_hash in left_peaks.iter().rev() {
+                        bag = hasher.hash(vec![peak_hash.clone(), bag])?;
+                    }
+                    let blocknumber = i64::try_from(first_leaf + offset + i)?;
+                    let elements_count = elements_count_after_block(blocknumber)?;
+                    Ok(BulkRoot {
+                        elements_count,
+                        root_hash: hasher.hash(vec![elements_count.to_string(), bag])?,
+                    })
+                })
+        })
+        .collect::<Result<_>>()?;
+
+    Ok((hashes, roots))
+}
+
+fn build_subtree(
+    hasher_kind: MmrHasher,
+    hasher: &dyn Hasher,
+    height: u32,
+    block_hashes: &[String],
+) -> Result<Subtree> {
+    let mut nodes = Vec::with_capacity((2 << height) - 1);
+    let mut local_bags = Vec::with_capacity(block_hashes.len());
+    let mut stack: Vec<(u32, String)> = Vec::new();
+
+    for block_hash in block_hashes {
+        let leaf = leaf_hash(hasher_kind, hasher, block_hash)?;
+        hasher.is_element_size_valid(&leaf)?;
+        nodes.push(leaf.clone());
+
+        let (mut node_height, mut hash) = (0, leaf);
+        while let Some((left_height, left_hash)) = stack.pop() {
+            if left_height != node_height {
+                stack.push((left_height, left_hash));
+                break;
+            }
+            hash = hasher.hash(vec![left_hash, hash])?;
+            nodes.push(hash.clone());
+            node_height += 1;
+        }
+        stack.push((node_height, hash));
+
+        let stack_hashes: Vec<String> = stack.iter().map(|(_, hash)| hash.clone()).collect();
+        local_bags.push(bag_peaks(hasher, &stack_hashes)?);
+    }
+
+    Ok(Subtree {
+        height,
+        nodes,
+        local_bags,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fossil_mmr::{new_hasher, tests::block_hash};
+    use accumulators::{mmr::AppendResult, store::memory::InMemoryStore};
+
+    const STARTING_LEAVES: [usize; 4] = [0, 1, 3, 64];
+    const APPENDED_LEAVES: [usize; 7] = [1, 2, 3, 255, 256, 257, 1000];
+
+    fn new_mmr(hasher: &Arc<dyn Hasher>) -> MMR {
+        MMR::new(
+            Arc::new(InMemoryStore::new(None)),
+            hasher.clone(),
+            Some("bulk".to_string()),
+        )
+    }
+
+    async fn append_one_by_one(
+        mmr: &mut MMR,
+        hasher_kind: MmrHasher,
+        hasher: &dyn Hasher,
+        block_hashes: &[String],
+    ) -> Vec<AppendResult> {
+        let mut append_results = Vec::with_capacity(block_hashes.len());
+        for block_hash in block_hashes {
+            let leaf = leaf_hash(hasher_kind, hasher, block_hash).unwrap();
+            append_results.push(mmr.append(leaf).await.unwrap());
+        }
+        append_results
+    }
+
+    async fn node_hashes(mmr: &MMR, elements_count: usize) -> Vec<Option<String>> {
+        let mut hashes = Vec::with_capacity(elements_count);
+        for element_index in 1..=elements_count {
+            hashes.push(mmr.hashes.get(SubKey::Usize(element_index)).await.unwrap());
+        }
+        hashes
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn bulk_append_matches_appending_one_by_one() {
+        for hasher_kind in [MmrHasher::Keccak, MmrHasher::Poseidon, MmrHasher::Pedersen] {
+            let hasher = new_hasher(hasher_kind);
+            let max_leaves =
+                STARTING_LEAVES.iter().max().unwrap() + APPENDED_LEAVES.iter().max().unwrap();
+            let block_hashes: Vec<String> = (0..max_leaves)
+                .map(|blocknumber| block_hash(blocknumber as i64))
+                .collect();
+            // Reference MMR, with the size and root after each leaf. Its first nodes are those of any smaller MMR
+            let mut reference = new_mmr(&hasher);
+            let append_results =
+                append_one_by_one(&mut reference, hasher_kind, hasher.as_ref(), &block_hashes)
+                    .await;
+
+            for starting_leaves in STARTING_LEAVES {
+                for appended_leaves in APPENDED_LEAVES {
+                    let case = format!(
+                        "{} {starting_leaves} + {appended_leaves}",
+                        hasher_kind.as_str()
+                    );
+                    let leaves_count = starting_leaves + appended_leaves;
+                    let expected_results = &append_results[starting_leaves..leaves_count];
+                    let elements_count = expected_results.last().unwrap().elements_count;
+
+                    let mut mmr = new_mmr(&hasher);
+                    append_one_by_one(
+                        &mut mmr,
+                        hasher_kind,
+                        hasher.as_ref(),
+                        &block_hashes[..starting_leaves],
+                    )
+                    .await;
+                    let roots = bulk_append(
+                        &mmr,
+                        hasher_kind,
+                        hasher.clone(),
+                        block_hashes[starting_leaves..leaves_count].to_vec(),
+                    )
+                    .await
+                    .unwrap();
+
+                    assert_eq!(roots.len(), appended_leaves, "{case}");
+                    for (root, expected) in roots.iter().zip(expected_results) {
+                        assert_eq!(root.elements_count, expected.elements_count, "{case}");
+                        assert_eq!(root.root_hash, expected.root_hash, "{case}");
+                    }
+                    assert_eq!(
+                        mmr.elements_count.get().await.unwrap(),
+                        elements_count,
+                        "{case}"
+                    );
+                    assert_eq!(
+                        mmr.leaves_count.get().await.unwrap(),
+                        leaves_count,
+                        "{case}"
+                    );
+                    assert_eq!(
+                        mmr.root_hash.get(SubKey::None).await.unwrap(),
+                        Some(expected_results.last().unwrap().root_hash.clone()),
+                        "{case}"
+                    );
+                    let hashes = node_hashes(&mmr, elements_count + 1).await;
+                    let mut expected_hashes = node_hashes(&reference, elements_count).await;
+                    expected_hashes.push(None);
+                    assert_eq!(hashes, expected_hashes, "{case}");
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn compute_append_continues_from_the_peaks() {
+        let hasher_kind = MmrHasher::Keccak;
+        let hasher = new_hasher(hasher_kind);
+        let block_hashes: Vec<String> = (0..20).map(block_hash).collect();
+        let (all_hashes, all_roots) =
+            compute_append(hasher_kind, hasher.as_ref(), 0, Vec::new(), &block_hashes).unwrap();
+        assert_eq!(all_hashes.len(), 38);
+
+        // After 11 leaves (19 elements), the peaks are the elements 15, 18 and 19
+        let peaks = [15, 18, 19]
+            .into_iter()
+            .map(|element_index| {
+                (
+                    node_height(element_index),
+                    all_hashes[element_index - 1].clone(),
+                )
+            })
+            .collect();
+        let (hashes, roots) =
+            compute_append(hasher_kind, hasher.as_ref(), 19, peaks, &block_hashes[11..]).unwrap();
+        assert_eq!(hashes, all_hashes[19..]);
+        for (root, expected) in roots.iter().zip(&all_roots[11..]) {
+            assert_eq!(root.elements_count, expected.elements_count);
+            assert_eq!(root.root_hash, expected.root_hash);
+        }
+    }
+}
@@ -0,0 +1,340 @@
+use anyhow::{bail, Context, Result};
+
+use crate::config::MmrHasher;
+use crate::types::{header_rlp::keccak256, Calldata, MmrProof, ProofCalldata, ProofFormat};
+
+/// Solidity verifier entrypoint the evm-abi calldata calls
+pub const EVM_VERIFY_SIGNATURE: &str =
+    "verifyProof(uint256,uint256,bytes32,uint256,bytes32,bytes32[],bytes32[],uint256,bytes32)";
+
+/**
+ * Encodes a proof as the calldata of our verifier contracts. Both take, in order: block number, leaf index, block
+ * hash, element index, element hash, siblings hashes, peaks hashes, elements count and root.
+ */
+pub fn encode_proof(proof: &MmrProof, format: ProofFormat) -> Result<ProofCalldata> {
+    let calldata = match format {
+        ProofFormat::Json => bail!("JSON proofs are not calldata"),
+        ProofFormat::StarknetCalldata => Calldata::Felts(starknet_calldata(proof)?),
+        ProofFormat::EvmAbi => Calldata::Bytes(evm_abi_calldata(proof)?),
+    };
+    Ok(ProofCalldata {
+        format,
+        hasher: proof.hasher,
+        block_number: proof.block_number,
+        elements_count: proof.elements_count,
+        root: proof.root.clone(),
+        calldata,
+        header_rlp: proof.header_rlp.clone(),
+    })
+}
+
+/**
+ * Cairo Serde of the verifier's arguments: integers are single felts, the block hash is a u256 (low then high
+ * u128), arrays are their length then their items. MMR hashes are u256 for keccak MMRs, and felt252 for Poseidon
+ * and Pedersen ones.
+ */
+fn starknet_calldata(proof: &MmrProof) -> Result<Vec<String>> {
+    let push_hash = |calldata: &mut Vec<String>, hash: &str| -> Result<()> {
+        match proof.hasher {
+            MmrHasher::Keccak => push_u256(calldata, hash),
+            MmrHasher::Poseidon | MmrHasher::Pedersen => {
+                calldata.push(felt(&hash_bytes(hash)?));
+                Ok(())
+            }
+        }
+    };
+
+    let mut calldata = vec![
+        integer_felt(proof.block_number.try_into()?),
+        integer_felt(proof.leaf_index.try_into()?),
+    ];
+    push_u256(&mut calldata, &proof.block_hash)?;
+    calldata.push(integer_felt(proof.element_index.try_into()?));
+    push_hash(&mut calldata, &proof.element_hash)?;
+    for hashes in [&proof.siblings_hashes, &proof.peaks_hashes] {
+        calldata.push(integer_felt(hashes.len().try_into()?));
+        for hash in hashes {
+            push_hash(&mut calldata, hash)?;
+        }
+    }
+    calldata.push(integer_felt(proof.elements_count.try_into()?));
+    push_hash(&mut calldata, &proof.root)?;
+    Ok(calldata)
+}
+
+/**
+ * Selector of EVM_VERIFY_SIGNATURE followed by the ABI encoding of its arguments. Only keccak MMRs can be checked
+ * by the Solidity verifier.
+ */
+fn evm_abi_calldata(proof: &MmrProof) -> Result<String> {
+    if proof.hasher != MmrHasher::Keccak {
+        bail!(
+            "evm-abi calldata needs a keccak proof, not a {} one",
+            proof.hasher.as_str()
+        );
+    }
+
+    // Head words, with the offsets of the two arrays (from the start of the arguments) in place of them
+    let head_size = 9 * 32;
+    let siblings_offset = head_size;
+    let peaks_offset = siblings_offset + 32 * (1 + proof.siblings_hashes.len());
+    let mut words = vec![
+        uint_word(proof.block_number.try_into()?),
+        uint_word(proof.leaf_index.try_into()?),
+        hash_bytes(&proof.block_hash)?,
+        uint_word(proof.element_index.try_into()?),
+        hash_bytes(&proof.element_hash)?,
+        uint_word(siblings_offset.try_into()?),
+        uint_word(peaks_offset.try_into()?),
+        uint_word(proof.elements_count.try_into()?),
+        hash_bytes(&proof.root)?,
+    ];
+    for hashes in [&proof.siblings_hashes, &proof.peaks_hashes] {
+        words.push(uint_word(hashes.len().try_into()?));
+        for hash in hashes {
+            words.push(hash_bytes(hash)?);
+        }
+    }
+
+    let mut calldata = format!("0x{}", hex::encode(evm_selector(EVM_VERIFY_SIGNATURE)));
+    for word in words {
+        calldata.push_str(&hex::encode(word));
+    }
+    Ok(calldata)
+}
+
+/**
+ * First 4 bytes of the keccak256 of a function signature
+ */
+fn evm_selector(signature: &str) -> [u8; 4] {
+    let hash = keccak256(signature.as_bytes());
+    [hash[0], hash[1], hash[2], hash[3]]
+}
+
+/**
+ * 0x-prefixed hash of up to 32 bytes, left-padded to a 32 byte word
+ */
+fn hash_bytes(hash: &str) -> Result<[u8; 32]> {
+    let digits = hash.strip_prefix("0x").unwrap_or(hash);
+    if digits.len() > 64 {
+        bail!("Invalid hash {hash}");
+    }
+    let padded = format!("{digits:0>64}");
+    let mut word = [0u8; 32];
+    hex::decode_to_slice(&padded, &mut word).with_context(|| format!("Invalid hash {hash}"))?;
+    Ok(word)
+}
+
+fn push_u256(calldata: &mut Vec<String>, hash: &str) -> Result<()> {
+    let word = hash_bytes(hash)?;
+    let (high, low) = word.split_at(16);
+    calldata.push(felt(low));
+    calldata.push(felt(high));
+    Ok(())
+}
+
+fn uint_word(value: u64) -> [u8; 32] {
+    let mut word = [0u8; 32];
+    word[24..].copy_from_slice(&value.to_be_bytes());
+    word
+}
+
+fn integer_felt(value: u64) -> String {
+    format!("{value:#x}")
+}
+
+/**
+ * Big-endian bytes as a felt hex string, without leading zeros
+ */
+fn felt(bytes: &[u8]) -> String {
+    let digits = hex::encode(bytes);
+    let digits = digits.trim_start_matches('0');
+    if digits.is_empty() {
+        return "0x0".to_string();
+    }
+    format!("0x{digits}")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fossil_mmr::verify_proof;
+    use crate::types::PROOF_SCHEMA_VERSION;
+
+    const BLOCK_HASH: &str = "0x67b176705b46206614219f47a05aee7ae6a3edbe850bbbe214c536b989aea4d2";
+    const SIBLING_HASH: &str = "0xf9e2eaaa42d9fe9e558a9b8ef1bf366f190aacaa83bad2641ee106e9041096e4";
+    const PEAK_HASH: &str = "0x75957027a7ded61890e7b288820ba7f959b79bc761267ef81b65a5f72a637b42";
+    const ROOT: &str = "0xc71021eb00fcbdb832ff4531d81b35e3b802c3854d66ce7918a9df5fe8df0842";
+
+    /**
+     * The README test vector: keccak proof of block 1 against the root after block 1
+     */
+    fn block_1_proof() -> MmrProof {
+        MmrProof {
+            version: PROOF_SCHEMA_VERSION,
+            hasher: MmrHasher::Keccak,
+            block_number: 1,
+            block_hash: BLOCK_HASH.to_string(),
+            leaf_index: 1,
+            element_index: 2,
+            element_hash: BLOCK_HASH.to_string(),
+            siblings_hashes: vec![SIBLING_HASH.to_string()],
+            peaks_hashes: vec![PEAK_HASH.to_string()],
+            elements_count: 3,
+            root: ROOT.to_string(),
+            shard: None,
+            header_rlp: None,
+        }
+    }
+
+    #[test]
+    fn test_vector_is_a_valid_proof() {
+        assert!(verify_proof(&block_1_proof(), ROOT).unwrap());
+    }
+
+    #[test]
+    fn evm_selector_is_the_keccak_of_the_signature() {
+        assert_eq!(
+            evm_selector(EVM_VERIFY_SIGNATURE),
+            keccak256(EVM_VERIFY_SIGNATURE.as_bytes())[..4]
+        );
+        assert_eq!(evm_selector(EVM_VERIFY_SIGNATURE), [0x4e, 0xea, 0xde, 0x65]);
+        assert_eq!(
+            evm_selector("transfer(address,uint256)"),
+            [0xa9, 0x05, 0x9c, 0xbb]
+        );
+    }
+
+    #[test]
+    fn starknet_calldata_of_the_test_vector() {
+        let calldata = encode_proof(&block_1_proof(), ProofFormat::StarknetCalldata).unwrap();
+        assert_eq!(calldata.format, ProofFormat::StarknetCalldata);
+        assert_eq!(calldata.root, ROOT);
+        let Calldata::Felts(felts) = calldata.calldata else {
+            panic!("Expected felts");
+        };
+        assert_eq!(
+            felts,
+            [
+                // Block number, leaf index
+                "0x1",
+                "0x1",
+                // Block hash, low then high u128
+                "0xe6a3edbe850bbbe214c536b989aea4d2",
+                "0x67b176705b46206614219f47a05aee7a",
+                // Element index, element hash
+                "0x2",
+                "0xe6a3edbe850bbbe214c536b989aea4d2",
+                "0x67b176705b46206614219f47a05aee7a",
+                // Siblings hashes
+                "0x1",
+                "0x190aacaa83bad2641ee106e9041096e4",
+                "0xf9e2eaaa42d9fe9e558a9b8ef1bf366f",
+                // Peaks hashes
+                "0x1",
+                "0x59b79bc761267ef81b65a5f72a637b42",
+                "0x75957027a7ded61890e7b288820ba7f9",
+                // Elements count, root
+                "0x3",
+                "0xb802c3854d66ce7918a9df5fe8df0842",
+                "0xc71021eb00fcbdb832ff4531d81b35e3",
+            ]
+        );
+    }
+
+    #[test]
+    fn starknet_calldata_of_stark_proofs_has_felt_hashes() {
+        let proof = MmrProof {
+            hasher: MmrHasher::Poseidon,
+            element_hash: "0x0abc".to_string(),
+            siblings_hashes: vec!["0x01".to_string(), "0x0".to_string()],
+            peaks_hashes: vec![],
+            elements_count: 7,
+            root: "0x1234".to_string(),
+            ..block_1_proof()
+        };
+        let Calldata::Felts(felts) = encode_proof(&proof, ProofFormat::StarknetCalldata)
+            .unwrap()
+            .calldata
+        else {
+            panic!("Expected felts");
+        };
+        assert_eq!(
+            felts,
+            [
+                "0x1",
+                "0x1",
+                "0xe6a3edbe850bbbe214c536b989aea4d2",
+                "0x67b176705b46206614219f47a05aee7a",
+                "0x2",
+                "0xabc",
+                "0x2",
+                "0x1",
+                "0x0",
+                "0x0",
+                "0x7",
+                "0x1234",
+            ]
+        );
+    }
+
+    #[test]
+    fn evm_abi_calldata_of_the_test_vector() {
+        let calldata = encode_proof(&block_1_proof(), ProofFormat::EvmAbi).unwrap();
+        let Calldata::Bytes(bytes) = calldata.calldata else {
+            panic!("Expected bytes");
+        };
+        let words = [
+            // Block number, leaf index, block hash, element index, element hash
+            "0000000000000000000000000000000000000000000000000000000000000001",
+            "0000000000000000000000000000000000000000000000000000000000000001",
+            "67b176705b46206614219f47a05aee7ae6a3edbe850bbbe214c536b989aea4d2",
+            "0000000000000000000000000000000000000000000000000000000000000002",
+            "67b176705b46206614219f47a05aee7ae6a3edbe850bbbe214c536b989aea4d2",
+            // Offsets of the siblings (9 head words) and peaks (then 2 siblings words) arrays
+            "0000000000000000000000000000000000000000000000000000000000000120",
+            "0000000000000000000000000000000000000000000000000000000000000160",
+            // Elements count, root
+            "0000000000000000000000000000000000000000000000000000000000000003",
+            "c71021eb00fcbdb832ff4531d81b35e3b802c3854d66ce7918a9df5fe8df0842",
+            // Siblings hashes
+            "0000000000000000000000000000000000000000000000000000000000000001",
+            "f9e2eaaa42d9fe9e558a9b8ef1bf366f190aacaa83bad2641ee106e9041096e4",
+            // Peaks hashes
+            "0000000000000000000000000000000000000000000000000000000000000001",
+            "75957027a7ded61890e7b288820ba7f959b79bc761267ef81b65a5f72a637b42",
+        ];
+        assert_eq!(bytes, format!("0x4eeade65{}", words.concat()));
+    }
+
+    #[test]
+    fn evm_abi_calldata_offsets_follow_the_siblings() {
+        let proof = MmrProof {
+            siblings_hashes: vec![SIBLING_HASH.to_string(); 3],
+            ..block_1_proof()
+        };
+        let Calldata::Bytes(bytes) = encode_proof(&proof, ProofFormat::EvmAbi).unwrap().calldata
+        else {
+            panic!("Expected bytes");
+        };
+        let word = |index: usize| &bytes[10 + 64 * index..10 + 64 * (index + 1)];
+        assert_eq!(word(5), format!("{:064x}", 9 * 32));
+        assert_eq!(word(6), format!("{:064x}", (9 + 4) * 32));
+        assert_eq!(word(9), format!("{:064x}", 3));
+        assert_eq!(word(13), format!("{:064x}", 1));
+        assert_eq!(bytes.len(), 10 + 64 * 15);
+    }
+
+    #[test]
+    fn evm_abi_needs_a_keccak_proof() {
+        for hasher in [MmrHasher::Poseidon, MmrHasher::Pedersen] {
+            let proof = MmrProof {
+                hasher,
+                ..block_1_proof()
+            };
+            assert!(encode_proof(&proof, ProofFormat::EvmAbi).is_err());
+            assert!(encode_proof(&proof, ProofFormat::StarknetCalldata).is_ok());
+        }
+        assert!(encode_proof(&block_1_proof(), ProofFormat::Json).is_err());
+    }
+}
@@ -0,0 +1,299 @@
+use accumulators::{hasher::Hasher, mmr::find_peaks};
+use anyhow::{bail, Result};
+use std::collections::{BTreeSet, HashMap};
+
+use super::{climb_to_peak, compute_root_hash, get_root_hash, is_valid_elements_count, MmrHandle};
+use crate::types::{MmrConsistencyProof, PROOF_SCHEMA_VERSION};
+
+/**
+ * Proves that the MMR at to_elements_count was obtained by appending to the MMR at from_elements_count: for each
+ * peak at the smaller size, the siblings hashing it up to a peak at the larger size
+ *
+ * @Returns None if the MMR has not reached to_elements_count yet
+ */
+pub async fn get_consistency_proof(
+    handle: &MmrHandle,
+    from_elements_count: usize,
+    to_elements_count: usize,
+) -> Result<Option<MmrConsistencyProof>> {
+    if !is_valid_elements_count(from_elements_count)
+        || !is_valid_elements_count(to_elements_count)
+        || from_elements_count > to_elements_count
+    {
+        bail!("Invalid sizes {from_elements_count} -> {to_elements_count}");
+    }
+
+    if to_elements_count > handle.committed_elements_count() {
+        return Ok(None);
+    }
+    let mmr = &handle.reader;
+
+    let from_peaks = find_peaks(from_elements_count);
+    let to_peaks = find_peaks(to_elements_count);
+    let paths: Vec<Vec<usize>> = from_peaks
+        .iter()
+        .map(|&peak| {
+            climb_to_peak(peak, &to_peaks)
+                .0
+                .into_iter()
+                .map(|(sibling, _)| sibling)
+                .collect()
+        })
+        .collect();
+
+    let positions: BTreeSet<usize> = from_peaks
+        .iter()
+        .chain(&to_peaks)
+        .chain(paths.iter().flatten())
+        .copied()
+        .collect();
+    let hashes: HashMap<usize, String> = positions
+        .iter()
+        .copied()
+        .zip(
+            mmr.retrieve_peaks_hashes(positions.iter().copied().collect(), None)
+                .await?,
+        )
+        .collect();
+    if hashes.len() != positions.len() {
+        bail!("Missing hashes in the MMR store");
+    }
+    let hashes_of = |positions: &[usize]| -> Vec<String> {
+        positions.iter().map(|p| hashes[p].clone()).collect()
+    };
+
+    Ok(Some(MmrConsistencyProof {
+        version: PROOF_SCHEMA_VERSION,
+        hasher: handle.hasher(),
+        from_elements_count,
+        to_elements_count,
+        from_root: get_root_hash(mmr, from_elements_count).await?,
+        to_root: get_root_hash(mmr, to_elements_count).await?,
+        from_peaks_hashes: hashes_of(&from_peaks),
+        to_peaks_hashes: hashes_of(&to_peaks),
+        paths: paths.iter().map(|path| hashes_of(path)).collect(),
+    }))
+}
+
+/**
+ * Checks both roots against the peaks, and that every old peak hashes up to the new peak containing it
+ */
+pub fn verify_consistency_proof(hasher: &dyn Hasher, proof: &MmrConsistencyProof) -> Result<bool> {
+    let (from_elements_count, to_elements_count) =
+        (proof.from_elements_count, proof.to_elements_count);
+    if !is_valid_elements_count(from_elements_count)
+        || !is_valid_elements_count(to_elements_count)
+        || from_elements_count > to_elements_count
+    {
+        return Ok(false);
+    }
+
+    let from_peaks = find_peaks(from_elements_count);
+    let to_peaks = find_peaks(to_elements_count);
+    if proof.from_peaks_hashes.len() != from_peaks.len()
+        || proof.to_peaks_hashes.len() != to_peaks.len()
+        || proof.paths.len() != from_peaks.len()
+    {
+        return Ok(false);
+    }
+    if compute_root_hash(hasher, from_elements_count, &proof.from_peaks_hashes)? != proof.from_root
+        || compute_root_hash(hasher, to_elements_count, &proof.to_peaks_hashes)? != proof.to_root
+    {
+        return Ok(false);
+    }
+
+    for ((&peak, peak_hash), path) in from_peaks
+        .iter()
+        .zip(&proof.from_peaks_hashes)
+        .zip(&proof.paths)
+    {
+        let (steps, new_peak_index) = climb_to_peak(peak, &to_peaks);
+        if steps.len() != path.len() {
+            return Ok(false);
+        }
+
+        let mut hash = peak_hash.clone();
+        for ((_, is_right), sibling_hash) in steps.iter().zip(path) {
+            hash = hasher.hash(if *is_right {
+                vec![sibling_hash.clone(), hash]
+            } else {
+                vec![hash, sibling_hash.clone()]
+            })?;
+        }
+
+        if proof.to_peaks_hashes[new_peak_index] != hash {
+            return Ok(false);
+        }
+    }
+
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::MmrHasher;
+    use crate::fossil_mmr::{
+        get_root_hash_at, new_hasher,
+        tests::{append_blocks, test_handle},
+    };
+
+    /**
+     * Sizes of an MMR of up to 21 leaves
+     */
+    fn sizes() -> Vec<usize> {
+        (1..=39)
+            .filter(|&elements_count| is_valid_elements_count(elements_count))
+            .collect()
+    }
+
+    fn flip(hash: &str) -> String {
+        let last = if hash.ends_with('0') { "1" } else { "0" };
+        format!("{}{last}", &hash[..hash.len() - 1])
+    }
+
+    #[tokio::test]
+    async fn consistency_proofs_verify_between_all_sizes() {
+        let handle = test_handle(MmrHasher::Keccak, (0, None), 21).await;
+        let hasher = new_hasher(MmrHasher::Keccak);
+        let sizes = sizes();
+        assert_eq!(sizes.len(), 21);
+        for &from in &sizes {
+            for &to in sizes.iter().filter(|&&to| to >= from) {
+                let proof = get_consistency_proof(&handle, from, to)
+                    .await
+                    .unwrap()
+                    .unwrap();
+                assert_eq!(
+                    Some(&proof.from_root),
+                    get_root_hash_at(&handle, from).await.unwrap().as_ref()
+                );
+                assert_eq!(
+                    Some(&proof.to_root),
+                    get_root_hash_at(&handle, to).await.unwrap().as_ref()
+                );
+                assert!(
+                    verify_consistency_proof(hasher.as_ref(), &proof).unwrap(),
+                    "{from} -> {to}"
+                );
+
+                // Read backwards, the larger MMR is not an append to the smaller one
+                if from != to {
+                    let reversed = MmrConsistencyProof {
+                        from_elements_count: to,
+                        to_elements_count: from,
+                        from_root: proof.to_root.clone(),
+                        to_root: proof.from_root.clone(),
+                        from_peaks_hashes: proof.to_peaks_hashes.clone(),
+                        to_peaks_hashes: proof.from_peaks_hashes.clone(),
+                        ..proof.clone()
+                    };
+                    assert!(!verify_consistency_proof(hasher.as_ref(), &reversed).unwrap());
+                }
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn consistency_proofs_verify_with_stark_hashers() {
+        for hasher_kind in [MmrHasher::Poseidon, MmrHasher::Pedersen] {
+            let handle = test_handle(hasher_kind, (0, None), 11).await;
+            let hasher = new_hasher(hasher_kind);
+            // Same size, single peaks, and several peaks on either side
+            for (from, to) in [(1, 1), (3, 7), (7, 15), (1, 19), (4, 19), (10, 11), (8, 18)] {
+                let proof = get_consistency_proof(&handle, from, to)
+                    .await
+                    .unwrap()
+                    .unwrap();
+                assert!(
+                    verify_consistency_proof(hasher.as_ref(), &proof).unwrap(),
+                    "{} {from} -> {to}",
+                    hasher_kind.as_str()
+                );
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn consistency_proofs_survive_appends() {
+        let handle = test_handle(MmrHasher::Keccak, (0, None), 6).await;
+        let hasher = new_hasher(MmrHasher::Keccak);
+        let before = get_consistency_proof(&handle, 4, 10)
+            .await
+            .unwrap()
+            .unwrap();
+        append_blocks(&handle, 15).await;
+        let after = get_consistency_proof(&handle, 10, 39)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(before.to_root, after.from_root);
+        assert!(verify_consistency_proof(hasher.as_ref(), &after).unwrap());
+    }
+
+    #[tokio::test]
+    async fn tampered_consistency_proofs_are_rejected() {
+        let handle = test_handle(MmrHasher::Keccak, (0, None), 21).await;
+        let hasher = new_hasher(MmrHasher::Keccak);
+        let proof = get_consistency_proof(&handle, 11, 39)
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(proof.paths.iter().all(|path| !path.is_empty()));
+
+        let mut tampered = Vec::new();
+        for index in 0..proof.from_peaks_hashes.len() {
+            let mut proof = proof.clone();
+            proof.from_peaks_hashes[index] = flip(&proof.from_peaks_hashes[index]);
+            tampered.push(proof);
+        }
+        for index in 0..proof.to_peaks_hashes.len() {
+            let mut proof = proof.clone();
+            proof.to_peaks_hashes[index] = flip(&proof.to_peaks_hashes[index]);
+            tampered.push(proof);
+        }
+        for index in 0..proof.paths.len() {
+            let mut proof = proof.clone();
+            proof.paths[index][0] = flip(&proof.paths[index][0]);
+            tampered.push(proof);
+        }
+        let mut other_from_root = proof.clone();
+        other_from_root.from_root = flip(&proof.from_root);
+        tampered.push(other_from_root);
+        let mut other_to_root = proof.clone();
+        other_to_root.to_root = flip(&proof.to_root);
+        tampered.push(other_to_root);
+        let mut dropped_path = proof.clone();
+        dropped_path.paths.pop();
+        tampered.push(dropped_path);
+        let mut dropped_peak = proof.clone();
+        dropped_peak.to_peaks_hashes.pop();
+        tampered.push(dropped_peak);
+        for (from, to) in [(0, 39), (2, 39), (11, 38), (11, usize::MAX), (39, 11)] {
+            let mut proof = proof.clone();
+            proof.from_elements_count = from;
+            proof.to_elements_count = to;
+            tampered.push(proof);
+        }
+
+        for (index, proof) in tampered.iter().enumerate() {
+            assert!(
+                !verify_consistency_proof(hasher.as_ref(), proof).unwrap(),
+                "tampering {index}"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn invalid_sizes_are_rejected() {
+        let handle = test_handle(MmrHasher::Keccak, (0, None), 21).await;
+        for (from, to) in [(0, 3), (2, 3), (3, 5), (7, 3), (3, u64::MAX as usize)] {
+            assert!(get_consistency_proof(&handle, from, to).await.is_err());
+        }
+        // Valid, but not reached yet
+        assert!(get_consistency_proof(&handle, 3, 41)
+            .await
+            .unwrap()
+            .is_none());
+    }
+}
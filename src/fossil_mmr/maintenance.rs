@@ -0,0 +1,444 @@
+use accumulators::{
+    mmr::{elements_count_to_leaf_count, map_leaf_index_to_element_index},
+    store::{sqlite::SQLiteStore, InStoreTable, Store},
+};
+use anyhow::{bail, Context, Result};
+use sqlx::{Pool, Postgres};
+use std::collections::HashMap;
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+};
+use std::time::Duration;
+use tracing::{info, warn};
+
+use super::{
+    element_count_to_blocknumber, elements_count_after_block, get_last_added_blocknumber,
+    get_last_appendable_blocknumber, get_root_hash, update_mmr, MmrHandle, MmrSet, PostgresStore,
+    Records,
+};
+use crate::{
+    config::{MmrConfig, MmrStoreKind},
+    db,
+    metrics::Metrics,
+    types::{LeafMismatch, MmrAuditReport},
+};
+
+const REBUILD_TABLE: &str = "mmr_store_rebuild";
+const AUDIT_CHUNK_SIZE: usize = 1_000;
+const AUDIT_REPORTED_MISMATCHES: usize = 100;
+const TRUNCATE_BATCH_SIZE: usize = 1_000;
+
+/**
+ * Rebuilds the MMRs of every configured hasher from the stored headers into a fresh store, which replaces the
+ * current one only once every MMR has caught up. MMRs opened before keep reading the nodes they had. Roots and
+ * leaf indexes are recorded aside meanwhile, and replace the current ones along with the store.
+ *
+ * @Returns the last block appended
+ */
+pub async fn rebuild_mmr(
+    config: &MmrConfig,
+    pool: &Pool<Postgres>,
+    metrics: Arc<Metrics>,
+    should_terminate: &AtomicBool,
+) -> Result<i64> {
+    let range_end = get_last_appendable_blocknumber(pool).await?;
+    info!("Rebuilding MMR up to block {}", range_end);
+    db::create_mmr_rebuild_tables(pool).await?;
+
+    let last_blocknumber = match config.store {
+        MmrStoreKind::Sqlite => {
+            let rebuild_path = format!("{}.rebuild", config.db_file_path);
+            if let Err(e) = std::fs::remove_file(&rebuild_path) {
+                if e.kind() != std::io::ErrorKind::NotFound {
+                    return Err(e).context(format!("Failed to remove {rebuild_path}"));
+                }
+            }
+            let store = SQLiteStore::new(&rebuild_path, Some(true), Some(&config.mmr_id)).await?;
+            let (last_blocknumber, mmr_ids) = build_mmr(
+                config,
+                Arc::new(store),
+                pool,
+                metrics,
+                range_end,
+                should_terminate,
+            )
+            .await?;
+            // Rolled back if the file cannot be moved
+            let mut tx = pool.begin().await?;
+            db::move_rebuilt_mmr_records(&mut tx, &mmr_ids, last_blocknumber).await?;
+            std::fs::rename(&rebuild_path, &config.db_file_path).with_context(|| {
+                format!("Failed to move {rebuild_path} to {}", config.db_file_path)
+            })?;
+            tx.commit().await?;
+            last_blocknumber
+        }
+        MmrStoreKind::Postgres => {
+            let store = Arc::new(
+                PostgresStore::new_scratch(pool.clone(), &config.mmr_id, REBUILD_TABLE).await?,
+            );
+            let (last_blocknumber, mmr_ids) = build_mmr(
+                config,
+                store.clone(),
+                pool,
+                metrics,
+                range_end,
+                should_terminate,
+            )
+            .await?;
+            let mut tx = pool.begin().await?;
+            PostgresStore::replace_mmrs(&mut tx, &store, &mmr_ids).await?;
+            db::move_rebuilt_mmr_records(&mut tx, &mmr_ids, last_blocknumber).await?;
+            tx.commit().await?;
+            last_blocknumber
+        }
+    };
+
+    info!("MMR rebuilt up to block {}", last_blocknumber);
+    Ok(last_blocknumber)
+}
+
+/**
+ * Appends the stored blocks up to range_end (or further, if more were stored meanwhile) to empty MMRs in store
+ *
+ * @Returns the last block appended to all of them, and the ids of the MMRs built
+ */
+async fn build_mmr(
+    config: &MmrConfig,
+    store: Arc<dyn Store>,
+    pool: &Pool<Postgres>,
+    metrics: Arc<Metrics>,
+    range_end: i64,
+    should_terminate: &AtomicBool,
+) -> Result<(i64, Vec<String>)> {
+    let set = MmrSet::for_rebuild(config, store, metrics).await?;
+    update_mmr(&set, pool, should_terminate).await?;
+    if should_terminate.load(Ordering::Relaxed) {
+        bail!("Rebuild interrupted, the current MMR store was left unchanged");
+    }
+    if let Some(gap) = set.take_gap().await {
+        bail!(
+            "Blocks {} to {} are not stored, run fix before rebuilding. The current MMR store was left unchanged",
+            gap.start,
+            gap.end
+        );
+    }
+
+    let mut last_blocknumber = i64::MAX;
+    for handle in set.handles() {
+        last_blocknumber = last_blocknumber.min(get_last_added_blocknumber(handle).await?);
+    }
+    if last_blocknumber < range_end {
+        bail!(
+            "Rebuild stopped at block {last_blocknumber} instead of {range_end}, the current MMR store was left unchanged"
+        );
+    }
+    Ok((last_blocknumber, set.mmr_ids().await))
+}
+
+/**
+ * Rolls every MMR of the set back to right after block to_block (-1 empties them): removes the later leaves and
+ * the nodes above them, resets the counters and root, and deletes the roots and leaf indexes recorded after
+ * to_block. Waits for a running update to finish, the next one appends the stored blocks again.
+ */
+pub async fn truncate_mmr(set: &MmrSet, pool: &Pool<Postgres>, to_block: i64) -> Result<()> {
+    if to_block < -1 {
+        bail!("Invalid block {to_block}");
+    }
+
+    while set
+        .is_updating
+        .compare_exchange(false, true, Ordering::SeqCst, Ordering::SeqCst)
+        .is_err()
+    {
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    let shards = set.shards().await;
+    let mut result = Ok(());
+    for handle in set
+        .handles()
+        .iter()
+        .chain(shards.iter().flat_map(|shard| shard.handles.iter()))
+    {
+        result = truncate_handle(handle, pool, to_block).await;
+        if result.is_err() {
+            break;
+        }
+    }
+    if result.is_ok() {
+        result = match set.records {
+            Records::Live => db::clear_mmr_leaf_indexes_after(pool, to_block).await,
+            Records::Rebuild => db::clear_rebuilt_mmr_leaf_indexes_after(pool, to_block).await,
+        };
+    }
+    set.is_updating.store(false, Ordering::SeqCst);
+    result
+}
+
+/**
+ * Handles a block whose parent is not the last MMR leaf: marks it unverified, so it is checked against its stored
+ * parent again, and truncates the MMRs after the last leaf that matches its stored block
+ */
+pub(super) async fn roll_back_diverged(
+    set: &MmrSet,
+    pool: &Pool<Postgres>,
+    block_number: i64,
+) -> Result<()> {
+    db::unverify_blockheader(pool, block_number).await?;
+
+    let mut to_block = block_number - 1;
+    for handle in set.handles() {
+        to_block = to_block.min(last_matching_block(handle, pool, block_number - 1).await?);
+    }
+    if to_block < block_number - 1 {
+        warn!(
+            block_number,
+            to_block, "MMR leaves diverge from the stored blocks, truncating"
+        );
+        truncate_mmr(set, pool, to_block).await?;
+    }
+    Ok(())
+}
+
+/**
+ * Last block up to from_block whose MMR leaf is the leaf hash of the stored block, -1 if none
+ */
+async fn last_matching_block(
+    handle: &MmrHandle,
+    pool: &Pool<Postgres>,
+    from_block: i64,
+) -> Result<i64> {
+    let mut chunk_end = from_block;
+    while chunk_end >= 0 {
+        let chunk_start = (chunk_end + 1 - AUDIT_CHUNK_SIZE as i64).max(0);
+        let blocknumbers: Vec<i64> = (chunk_start..=chunk_end).collect();
+        let leaves = get_leaves(handle, usize::try_from(chunk_start)?, blocknumbers.len()).await?;
+        let block_hashes = db::get_block_hashes(pool, &blocknumbers).await?;
+
+        for (blocknumber, leaf) in blocknumbers.into_iter().zip(leaves).rev() {
+            let (Some(leaf), Some(block_hash)) = (leaf, block_hashes.get(&blocknumber)) else {
+                continue;
+            };
+            if leaf.eq_ignore_ascii_case(&handle.leaf_hash(block_hash)?) {
+                return Ok(blocknumber);
+            }
+        }
+        chunk_end = chunk_start - 1;
+    }
+    Ok(-1)
+}
+
+async fn truncate_handle(handle: &MmrHandle, pool: &Pool<Postgres>, to_block: i64) -> Result<()> {
+    let mmr_guard = handle.mmr.lock().await;
+    let elements_count = mmr_guard.elements_count.get().await?;
+    let new_leaves_count = handle.leaf_count_through(to_block)?;
+    let new_elements_count = elements_count_after_block(i64::try_from(new_leaves_count)? - 1)?;
+
+    if new_elements_count < elements_count {
+        // Reads stop at the new size before its nodes go away
+        handle.commit(new_elements_count);
+
+        let root_hash_key = InStoreTable::get_full_key(&mmr_guard.root_hash.key, "");
+        if new_elements_count > 0 {
+            let root_hash = get_root_hash(&mmr_guard, new_elements_count).await?;
+            mmr_guard
+                .root_hash
+                .store
+                .set(&root_hash_key, &root_hash)
+                .await?;
+        } else {
+            mmr_guard.root_hash.store.delete(&root_hash_key).await?;
+        }
+        mmr_guard.elements_count.set(new_elements_count).await?;
+        mmr_guard.leaves_count.set(new_leaves_count).await?;
+
+        let keys: Vec<String> = (new_elements_count + 1..=elements_count)
+            .map(|element_index| {
+                InStoreTable::get_full_key(&mmr_guard.hashes.key, &element_index.to_string())
+            })
+            .collect();
+        for batch in keys.chunks(TRUNCATE_BATCH_SIZE) {
+            mmr_guard
+                .hashes
+                .store
+                .delete_many(batch.iter().map(String::as_str).collect())
+                .await?;
+        }
+        info!(
+            mmr_id = handle.mmr_id,
+            "Truncated MMR from {} to {} elements (block {})",
+            elements_count,
+            new_elements_count,
+            to_block
+        );
+    }
+
+    match handle.records {
+        Records::Live => db::delete_mmr_roots_after(pool, &handle.mmr_id, to_block).await?,
+        Records::Rebuild => {
+            db::delete_rebuilt_mmr_roots_after(pool, &handle.mmr_id, to_block).await?
+        }
+    }
+    if handle.is_shard() {
+        return Ok(());
+    }
+    let elements_count = new_elements_count.min(elements_count);
+    let hasher = handle.hasher().as_str();
+    handle
+        .metrics
+        .mmr_elements_count
+        .with_label_values(&[hasher])
+        .set(elements_count.try_into()?);
+    handle
+        .metrics
+        .mmr_latest_blocknumber
+        .with_label_values(&[hasher])
+        .set(element_count_to_blocknumber(elements_count)?);
+    Ok(())
+}
+
+/**
+ * Compares every leaf of the MMR with the leaf hash of the stored block it was appended for
+ */
+pub async fn audit_mmr(handle: &MmrHandle, pool: &Pool<Postgres>) -> Result<MmrAuditReport> {
+    let leaves_count = elements_count_to_leaf_count(handle.committed_elements_count())?;
+
+    let mut report = MmrAuditReport {
+        hasher: handle.hasher(),
+        shard: handle.proof_shard().map(|shard| shard.index),
+        leaves_count,
+        mismatches_count: 0,
+        first_divergent_leaf: None,
+        mismatches: Vec::new(),
+    };
+    for chunk_start in (0..leaves_count).step_by(AUDIT_CHUNK_SIZE) {
+        let blocknumbers: Vec<i64> = (chunk_start
+            ..leaves_count.min(chunk_start + AUDIT_CHUNK_SIZE))
+            .map(|leaf_index| Ok(handle.first_block + i64::try_from(leaf_index)?))
+            .collect::<Result<_>>()?;
+        let block_hashes = db::get_block_hashes(pool, &blocknumbers).await?;
+        audit_leaves(
+            handle,
+            chunk_start,
+            blocknumbers,
+            &block_hashes,
+            &mut report,
+        )
+        .await?;
+    }
+
+    Ok(report)
+}
+
+/**
+ * Adds to the report the leaves from leaf index first_leaf, of blocks blocknumbers, that do not match block_hashes
+ */
+async fn audit_leaves(
+    handle: &MmrHandle,
+    first_leaf: usize,
+    blocknumbers: Vec<i64>,
+    block_hashes: &HashMap<i64, String>,
+    report: &mut MmrAuditReport,
+) -> Result<()> {
+    let leaves = get_leaves(handle, first_leaf, blocknumbers.len()).await?;
+    for (blocknumber, leaf) in blocknumbers.into_iter().zip(leaves) {
+        let expected_leaf = match block_hashes.get(&blocknumber) {
+            Some(block_hash) => Some(handle.leaf_hash(block_hash)?),
+            None => None,
+        };
+        let matches = match (&leaf, &expected_leaf) {
+            (Some(leaf), Some(expected_leaf)) => leaf.eq_ignore_ascii_case(expected_leaf),
+            _ => false,
+        };
+        if matches {
+            continue;
+        }
+
+        warn!(
+            hasher = handle.hasher().as_str(),
+            shard = report.shard,
+            block_number = blocknumber,
+            ?leaf,
+            ?expected_leaf,
+            "MMR leaf does not match the stored block"
+        );
+        report.mismatches_count += 1;
+        report.first_divergent_leaf.get_or_insert(blocknumber);
+        if report.mismatches.len() < AUDIT_REPORTED_MISMATCHES {
+            report.mismatches.push(LeafMismatch {
+                block_number: blocknumber,
+                leaf,
+                expected_leaf,
+            });
+        }
+    }
+    Ok(())
+}
+
+/**
+ * Leaves count leaves from leaf index first_leaf, None for the ones missing from the store
+ */
+async fn get_leaves(
+    handle: &MmrHandle,
+    first_leaf: usize,
+    count: usize,
+) -> Result<Vec<Option<String>>> {
+    let mmr = &handle.reader;
+    let keys: Vec<String> = (first_leaf..first_leaf + count)
+        .map(|leaf_index| {
+            InStoreTable::get_full_key(
+                &mmr.hashes.key,
+                &map_leaf_index_to_element_index(leaf_index).to_string(),
+            )
+        })
+        .collect();
+    let mut values: HashMap<String, String> = mmr
+        .hashes
+        .store
+        .get_many(keys.iter().map(String::as_str).collect())
+        .await?;
+
+    Ok(keys.iter().map(|key| values.remove(key)).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fossil_mmr::{
+        tests::{block_hash, test_handle},
+        MmrHasher,
+    };
+
+    #[tokio::test]
+    async fn shard_leaves_are_audited_against_their_own_blocks() {
+        // Shard 2 of 4 blocks, holding blocks 8 to 10
+        let handle = test_handle(MmrHasher::Poseidon, (8, Some(11)), 3).await;
+        let mut block_hashes: HashMap<i64, String> = (8..11)
+            .map(|blocknumber| (blocknumber, block_hash(blocknumber)))
+            .collect();
+        let mut report = MmrAuditReport {
+            hasher: handle.hasher(),
+            shard: handle.proof_shard().map(|shard| shard.index),
+            leaves_count: 3,
+            mismatches_count: 0,
+            first_divergent_leaf: None,
+            mismatches: Vec::new(),
+        };
+        audit_leaves(&handle, 0, vec![8, 9, 10], &block_hashes, &mut report)
+            .await
+            .unwrap();
+        assert_eq!(report.shard, Some(2));
+        assert_eq!(report.mismatches_count, 0);
+
+        block_hashes.insert(9, block_hash(0));
+        audit_leaves(&handle, 0, vec![8, 9, 10], &block_hashes, &mut report)
+            .await
+            .unwrap();
+        assert_eq!(report.mismatches_count, 1);
+        assert_eq!(report.first_divergent_leaf, Some(9));
+        assert_eq!(
+            report.mismatches[0].expected_leaf,
+            Some(handle.leaf_hash(&block_hash(0)).unwrap())
+        );
+    }
+}
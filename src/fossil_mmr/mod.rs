@@ -1,135 +1,710 @@
+mod bulk;
+mod calldata;
+mod consistency;
+mod maintenance;
+mod postgres_store;
+
+pub use calldata::{encode_proof, EVM_VERIFY_SIGNATURE};
+pub use consistency::{get_consistency_proof, verify_consistency_proof};
+pub use maintenance::{audit_mmr, rebuild_mmr, truncate_mmr};
+pub use postgres_store::PostgresStore;
+
 use accumulators::{
-    hasher::keccak::KeccakHasher,
-    mmr::{element_index_to_leaf_index, elements_count_to_leaf_count, map_leaf_index_to_element_index, AppendResult, Proof, MMR},
-    store::sqlite::SQLiteStore,
+    hasher::{
+        keccak::KeccakHasher, stark_pedersen::StarkPedersenHasher,
+        stark_poseidon::StarkPoseidonHasher, Hasher,
+    },
+    mmr::{
+        elements_count_to_leaf_count, find_peaks, map_leaf_index_to_element_index, AppendResult,
+        Proof, ProofOptions, MMR,
+    },
+    store::{sqlite::SQLiteStore, Store, SubKey},
 };
-use anyhow::Result;
-use chrono::{TimeZone, Utc};
-use lazy_static::lazy_static;
-use log::{debug, error, info, warn};
-use once_cell::sync::Lazy;
+use anyhow::{bail, Context, Result};
+use chrono::{DateTime, Utc};
+use futures::future::join_all;
+use sqlx::{Pool, Postgres};
+use std::collections::{BTreeSet, HashMap};
 use std::sync::{
-    atomic::{AtomicBool, Ordering},
+    atomic::{AtomicBool, AtomicUsize, Ordering},
     Arc,
 };
-use tokio::sync::{Mutex, MutexGuard, OnceCell};
+use tokio::sync::{Mutex, RwLock};
+use tracing::{debug, error, info, instrument, warn};
 
 use crate::{
+    config::{MmrConfig, MmrHasher, MmrStoreKind},
     db,
-    types::{BlockDetails, Update},
+    metrics::Metrics,
+    types::{
+        header_rlp::keccak256, BlockDetails, MmrBatchProof, MmrBatchProofItem, MmrProof, MmrRoot,
+        MmrShardInfo, ProofShard, Update, PROOF_SCHEMA_VERSION,
+    },
 };
 
-const MAX_RETRIES: u64 = 10;
-const DB_FILE_PATH: &str = "mmr_db";
-const MMR_ID: &str = "blockheaders_mmr";
-const MMR_APPEND_LOOPSIZE: i32 = 10_000; // How many (upper limit) block hashes are retrieved at for each query (limit for performance)
-const MMR_APPEND_CHUNKSIZE: usize = 50;
-static HASHES_MMR: OnceCell<Arc<Mutex<MMR>>> = OnceCell::const_new();
-static IS_UPDATING: Lazy<Arc<AtomicBool>> = Lazy::new(|| Arc::new(AtomicBool::new(false)));
-
-lazy_static! {
-    static ref LATEST_UPDATE: Arc<Mutex<Update>> = Arc::new(Mutex::new(Update {
-        latest_blocknumber: 0,
-        latest_roothash: "Unset".to_string(),
-        update_timestamp: Utc.with_ymd_and_hms(1700, 1, 1, 0, 0, 0).unwrap()
-    }));
-}
-
-async fn get_mmr() -> Result<Arc<Mutex<MMR>>> {
-    match HASHES_MMR.get() {
-        Some(mmr) => Ok(mmr.clone()),
-        None => {
-            let store = SQLiteStore::new(DB_FILE_PATH, Some(true), Some(MMR_ID)).await?;
-            let store_rc = Arc::new(store);
-            let hasher = Arc::new(KeccakHasher::new());
-            let mmr = MMR::new(store_rc.clone(), hasher.clone(), Some(MMR_ID.to_string()));
+/// Largest size is_valid_elements_count accepts, far beyond any MMR of the chain
+const MAX_ELEMENTS_COUNT: usize = usize::MAX >> 2;
+
+/**
+ * The MMRs built from the block hashes, one per configured hasher (and per shard, with mmr.shard_size), updated
+ * together
+ */
+pub struct MmrSet {
+    config: MmrConfig,
+    handles: Vec<MmrHandle>,
+    /// Opened up to the shard of the last appendable block, in shard order
+    shards: RwLock<Vec<Arc<MmrShard>>>,
+    store: Arc<dyn Store>,
+    is_updating: AtomicBool,
+    /// Stored blocks missing (or not verified) where the last update stopped, until gap repair takes them
+    gap: Mutex<Option<BlockGap>>,
+    records: Records,
+    metrics: Arc<Metrics>,
+}
+
+/**
+ * The MMRs over the blocks of one window of mmr.shard_size blocks, one per configured hasher
+ */
+pub struct MmrShard {
+    index: u64,
+    first_block: i64,
+    last_block: i64,
+    handles: Vec<MmrHandle>,
+}
+
+/**
+ * Blocks start..=end, missing from the stored headers or not verified, so the MMR cannot be appended past them
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BlockGap {
+    pub start: i64,
+    pub end: i64,
+}
+
+/**
+ * Where appends record the MMR roots and leaf indexes
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Records {
+    /// mmr_roots and blockheaders.mmr_leaf_index, served by the API
+    Live,
+    /// The rebuild tables, moved into the live ones when the rebuilt store replaces the current one
+    Rebuild,
+}
+
+/**
+ * Why the stored blocks could not be appended to the MMR
+ */
+#[derive(Debug, thiserror::Error)]
+pub enum MmrAppendError {
+    #[error("Blocks {} to {} are not stored", .0.start, .0.end)]
+    Gap(BlockGap),
+    #[error("Block {received} is already in the MMR, expected block {expected}")]
+    OutOfOrder { expected: i64, received: i64 },
+    #[error("Block {block_number} is not verified")]
+    Unverified { block_number: i64 },
+    #[error("Parent of block {block_number} is not the previous MMR leaf")]
+    ParentMismatch { block_number: i64 },
+    #[error("Block {block_number} is in the MMR, but the next stored block does not link to it")]
+    Unlinked { block_number: i64 },
+}
 
-            let arc_mutex_mmr = Arc::new(Mutex::new(mmr));
+pub struct MmrHandle {
+    config: MmrConfig,
+    hasher_kind: MmrHasher,
+    mmr_id: String,
+    /// Block of leaf 0
+    first_block: i64,
+    /// Last block of a shard MMR, None for the MMRs over the whole chain
+    last_block: Option<i64>,
+    hasher: Arc<dyn Hasher>,
+    /// Appends go through this lock
+    mmr: Mutex<MMR>,
+    /// Reads the same store without the lock. Appends only write nodes past committed_elements_count, and
+    /// truncations lower it before removing the nodes past it, so reads bounded by it see a consistent MMR while
+    /// appends continue. A read racing a truncation may fail, or return a proof that does not verify.
+    reader: MMR,
+    /// Size of the MMR once the last append chunk (and its recorded roots) was written
+    committed_elements_count: AtomicUsize,
+    /// When an update last caught up with the stored blocks (or when the handle was opened)
+    last_completed_update: Mutex<DateTime<Utc>>,
+    records: Records,
+    metrics: Arc<Metrics>,
+}
 
-            match HASHES_MMR.set(arc_mutex_mmr.clone()) {
-                Ok(_) => Ok(arc_mutex_mmr),
-                Err(_) => Ok(HASHES_MMR.get().expect("MMR was just set").clone()),
+impl MmrSet {
+    /**
+     * Opens the MMRs in the store selected by config.store: the SQLite file at config.db_file_path, or the
+     * mmr_store table of the headers database
+     */
+    pub async fn open(
+        config: &MmrConfig,
+        pool: &Pool<Postgres>,
+        metrics: Arc<Metrics>,
+    ) -> Result<Self> {
+        let store: Arc<dyn Store> = match config.store {
+            MmrStoreKind::Sqlite => Arc::new(
+                SQLiteStore::new(&config.db_file_path, Some(true), Some(&config.mmr_id)).await?,
+            ),
+            MmrStoreKind::Postgres => {
+                Arc::new(PostgresStore::new(pool.clone(), &config.mmr_id).await?)
             }
+        };
+        Self::with_store(config, store, metrics).await
+    }
+
+    pub async fn with_store(
+        config: &MmrConfig,
+        store: Arc<dyn Store>,
+        metrics: Arc<Metrics>,
+    ) -> Result<Self> {
+        Self::with_records(config, store, metrics, Records::Live).await
+    }
+
+    /**
+     * Opens the MMRs in store for a rebuild, recording their roots and leaf indexes in the rebuild tables
+     */
+    pub(super) async fn for_rebuild(
+        config: &MmrConfig,
+        store: Arc<dyn Store>,
+        metrics: Arc<Metrics>,
+    ) -> Result<Self> {
+        Self::with_records(config, store, metrics, Records::Rebuild).await
+    }
+
+    async fn with_records(
+        config: &MmrConfig,
+        store: Arc<dyn Store>,
+        metrics: Arc<Metrics>,
+        records: Records,
+    ) -> Result<Self> {
+        let mut handles = Vec::with_capacity(config.hashers.len());
+        for &hasher in &config.hashers {
+            let mmr_id = config.mmr_id_for(hasher);
+            handles.push(
+                MmrHandle::with_store(
+                    config,
+                    hasher,
+                    mmr_id,
+                    (0, None),
+                    store.clone(),
+                    records,
+                    metrics.clone(),
+                )
+                .await?,
+            );
+        }
+
+        let set = Self {
+            config: config.clone(),
+            handles,
+            shards: RwLock::new(Vec::new()),
+            store,
+            is_updating: AtomicBool::new(false),
+            gap: Mutex::new(None),
+            records,
+            metrics,
+        };
+        let mut last_added_blocknumber = -1;
+        for handle in &set.handles {
+            last_added_blocknumber =
+                last_added_blocknumber.max(get_last_added_blocknumber(handle).await?);
+        }
+        set.open_shards_through(last_added_blocknumber).await?;
+        Ok(set)
+    }
+
+    pub fn get(&self, hasher: MmrHasher) -> Option<&MmrHandle> {
+        self.handles
+            .iter()
+            .find(|handle| handle.hasher_kind == hasher)
+    }
+
+    /**
+     * MMR of the first configured hasher, served by the /mmr/... routes
+     */
+    pub fn primary(&self) -> &MmrHandle {
+        &self.handles[0]
+    }
+
+    pub fn handles(&self) -> &[MmrHandle] {
+        &self.handles
+    }
+
+    /**
+     * Blocks per shard, 0 if the MMRs are not sharded
+     */
+    pub fn shard_size(&self) -> i64 {
+        self.config.shard_size
+    }
+
+    pub async fn shards(&self) -> Vec<Arc<MmrShard>> {
+        self.shards.read().await.clone()
+    }
+
+    pub async fn shard(&self, index: u64) -> Option<Arc<MmrShard>> {
+        let index = usize::try_from(index).ok()?;
+        self.shards.read().await.get(index).cloned()
+    }
+
+    /**
+     * Shard holding blocknumber, None if the MMRs are not sharded or it was not opened yet
+     */
+    pub async fn shard_of(&self, blocknumber: i64) -> Option<Arc<MmrShard>> {
+        if self.config.shard_size == 0 || blocknumber < 0 {
+            return None;
+        }
+        self.shard((blocknumber / self.config.shard_size).try_into().ok()?)
+            .await
+    }
+
+    /**
+     * Opens the shards up to the one holding blocknumber, if the MMRs are sharded
+     */
+    async fn open_shards_through(&self, blocknumber: i64) -> Result<()> {
+        if self.config.shard_size == 0 {
+            return Ok(());
+        }
+
+        let last_index = u64::try_from(blocknumber.max(0) / self.config.shard_size)?;
+        let mut shards = self.shards.write().await;
+        for index in u64::try_from(shards.len())?..=last_index {
+            let shard = MmrShard::open(
+                &self.config,
+                index,
+                &self.store,
+                self.records,
+                &self.metrics,
+            )
+            .await?;
+            shards.push(Arc::new(shard));
+        }
+        Ok(())
+    }
+
+    /**
+     * Ids of every MMR of the set, shards included
+     */
+    pub async fn mmr_ids(&self) -> Vec<String> {
+        let shards = self.shards.read().await;
+        self.handles
+            .iter()
+            .chain(shards.iter().flat_map(|shard| shard.handles.iter()))
+            .map(|handle| handle.mmr_id.clone())
+            .collect()
+    }
+
+    /**
+     * Gap the last update stopped at, for gap repair to fill. The update after it carries on from there.
+     */
+    pub async fn take_gap(&self) -> Option<BlockGap> {
+        self.gap.lock().await.take()
+    }
+
+    async fn record_gap(&self, gap: Option<BlockGap>) {
+        let gap_blocks = gap.map_or(0, |gap| gap.end - gap.start + 1);
+        self.metrics.mmr_gap_blocks.set(gap_blocks);
+        *self.gap.lock().await = gap;
+    }
+}
+
+impl MmrShard {
+    async fn open(
+        config: &MmrConfig,
+        index: u64,
+        store: &Arc<dyn Store>,
+        records: Records,
+        metrics: &Arc<Metrics>,
+    ) -> Result<Self> {
+        let first_block = i64::try_from(index)? * config.shard_size;
+        let last_block = first_block + config.shard_size - 1;
+        let mut handles = Vec::with_capacity(config.hashers.len());
+        for &hasher in &config.hashers {
+            handles.push(
+                MmrHandle::with_store(
+                    config,
+                    hasher,
+                    config.shard_mmr_id_for(hasher, index),
+                    (first_block, Some(last_block)),
+                    store.clone(),
+                    records,
+                    metrics.clone(),
+                )
+                .await?,
+            );
         }
+
+        Ok(Self {
+            index,
+            first_block,
+            last_block,
+            handles,
+        })
+    }
+
+    pub fn index(&self) -> u64 {
+        self.index
+    }
+
+    pub fn first_block(&self) -> i64 {
+        self.first_block
+    }
+
+    pub fn last_block(&self) -> i64 {
+        self.last_block
+    }
+
+    pub fn handles(&self) -> &[MmrHandle] {
+        &self.handles
+    }
+
+    pub fn get(&self, hasher: MmrHasher) -> Option<&MmrHandle> {
+        self.handles
+            .iter()
+            .find(|handle| handle.hasher_kind == hasher)
+    }
+}
+
+impl MmrHandle {
+    /**
+     * Opens the MMR mmr_id over blocks (first block, last block if it is a shard)
+     */
+    async fn with_store(
+        config: &MmrConfig,
+        hasher_kind: MmrHasher,
+        mmr_id: String,
+        (first_block, last_block): (i64, Option<i64>),
+        store: Arc<dyn Store>,
+        records: Records,
+        metrics: Arc<Metrics>,
+    ) -> Result<Self> {
+        let hasher = new_hasher(hasher_kind);
+        let mmr = MMR::new(store.clone(), hasher.clone(), Some(mmr_id.clone()));
+        let reader = MMR::new(store, hasher.clone(), Some(mmr_id.clone()));
+        let elements_count = reader.elements_count.get().await?;
+
+        Ok(Self {
+            config: config.clone(),
+            hasher_kind,
+            mmr_id,
+            first_block,
+            last_block,
+            hasher,
+            mmr: Mutex::new(mmr),
+            reader,
+            committed_elements_count: AtomicUsize::new(elements_count),
+            last_completed_update: Mutex::new(Utc::now()),
+            records,
+            metrics,
+        })
+    }
+
+    pub fn hasher(&self) -> MmrHasher {
+        self.hasher_kind
+    }
+
+    pub fn mmr_id(&self) -> &str {
+        &self.mmr_id
+    }
+
+    fn is_shard(&self) -> bool {
+        self.last_block.is_some()
+    }
+
+    /**
+     * Leaf index of blocknumber, None if the MMR does not span it
+     */
+    fn leaf_index(&self, blocknumber: i64) -> Option<usize> {
+        if self
+            .last_block
+            .is_some_and(|last_block| blocknumber > last_block)
+        {
+            return None;
+        }
+        usize::try_from(blocknumber - self.first_block).ok()
+    }
+
+    fn proof_shard(&self) -> Option<ProofShard> {
+        self.last_block.map(|_| ProofShard {
+            index: (self.first_block / self.config.shard_size) as u64,
+            first_block: self.first_block,
+        })
+    }
+
+    /**
+     * Leaves of the MMR once it holds the blocks up to blocknumber
+     */
+    fn leaf_count_through(&self, blocknumber: i64) -> Result<usize> {
+        let blocknumber = self
+            .last_block
+            .map_or(blocknumber, |last_block| blocknumber.min(last_block));
+        Ok(usize::try_from(
+            (blocknumber - self.first_block + 1).max(0),
+        )?)
+    }
+
+    /**
+     * Size of the MMR reads are served at: the last appends that were completely written
+     */
+    pub fn committed_elements_count(&self) -> usize {
+        self.committed_elements_count.load(Ordering::Acquire)
+    }
+
+    fn commit(&self, elements_count: usize) {
+        self.committed_elements_count
+            .store(elements_count, Ordering::Release);
+    }
+
+    fn leaf_hash(&self, block_hash: &str) -> Result<String> {
+        leaf_hash(self.hasher_kind, self.hasher.as_ref(), block_hash)
+    }
+}
+
+pub fn new_hasher(hasher_kind: MmrHasher) -> Arc<dyn Hasher> {
+    match hasher_kind {
+        MmrHasher::Keccak => Arc::new(KeccakHasher::new()),
+        MmrHasher::Poseidon => Arc::new(StarkPoseidonHasher::new(None)),
+        MmrHasher::Pedersen => Arc::new(StarkPedersenHasher::new()),
+    }
+}
+
+/**
+ * Leaf appended for a block hash. Keccak leaves are the block hashes themselves. A 256 bit hash does not fit in a
+ * Stark field element, so Poseidon and Pedersen leaves are the hash of its (low 128 bits, high 128 bits) halves.
+ */
+pub fn leaf_hash(hasher_kind: MmrHasher, hasher: &dyn Hasher, block_hash: &str) -> Result<String> {
+    if hasher_kind == MmrHasher::Keccak {
+        return Ok(block_hash.to_string());
     }
+
+    let digits = block_hash.strip_prefix("0x").unwrap_or(block_hash);
+    if digits.len() != 64 || !digits.chars().all(|c| c.is_ascii_hexdigit()) {
+        bail!("Invalid block hash {block_hash}");
+    }
+    let (high, low) = digits.split_at(32);
+    Ok(hasher.hash(vec![format!("0x{low}"), format!("0x{high}")])?)
 }
 
-pub async fn update_mmr(should_terminate: &AtomicBool) -> Result<()> {
-    for _ in 0..MAX_RETRIES {
-        if IS_UPDATING.load(Ordering::Relaxed) {
+/**
+ * Appends the stored blocks to every MMR of the set. Each chunk of block hashes is read once and appended to the
+ * MMRs concurrently, each starting after the last block it already holds.
+ */
+#[instrument(name = "mmr_update", skip_all)]
+pub async fn update_mmr(
+    set: &MmrSet,
+    pool: &Pool<Postgres>,
+    should_terminate: &AtomicBool,
+) -> Result<()> {
+    for _ in 0..set.config.max_retries {
+        if set
+            .is_updating
+            .compare_exchange(false, true, Ordering::SeqCst, Ordering::SeqCst)
+            .is_err()
+        {
             error!("Currently updating MMR");
             return Ok(());
         }
-        IS_UPDATING.store(true, Ordering::SeqCst);
 
-        let update_result = perform_mmr_update(should_terminate).await;
+        let update_result = perform_mmr_update(set, pool, should_terminate).await;
 
-        IS_UPDATING.store(false, Ordering::SeqCst);
+        set.is_updating.store(false, Ordering::SeqCst);
 
         match update_result {
-            Ok(_) => return Ok(()),
-            Err(e) => warn!("[update_mmr] Error with updating MMR: {}", e),
+            Ok(_) => {
+                set.record_gap(None).await;
+                let now = Utc::now();
+                for handle in &set.handles {
+                    *handle.last_completed_update.lock().await = now;
+                }
+                return Ok(());
+            }
+            // Retrying does not help until the missing blocks are stored (or verified)
+            Err(e) => match e.downcast_ref::<MmrAppendError>() {
+                Some(MmrAppendError::Gap(gap)) => {
+                    warn!(
+                        gap_start = gap.start,
+                        gap_end = gap.end,
+                        "MMR update stopped at missing blocks"
+                    );
+                    set.record_gap(Some(*gap)).await;
+                    return Ok(());
+                }
+                Some(&MmrAppendError::Unverified { block_number }) => {
+                    warn!(block_number, "MMR update stopped at an unverified block");
+                    let gap = get_unverified_blocks(set, pool, block_number).await?;
+                    set.record_gap(Some(gap)).await;
+                    return Ok(());
+                }
+                // Either block is wrong: the MMR drops the one it holds, and both are fetched again
+                Some(&MmrAppendError::Unlinked { block_number }) => {
+                    warn!(
+                        block_number,
+                        "Next stored block does not link to an MMR leaf, truncating"
+                    );
+                    maintenance::truncate_mmr(set, pool, block_number - 1).await?;
+                    let gap = get_unverified_blocks(set, pool, block_number).await?;
+                    set.record_gap(Some(gap)).await;
+                    return Ok(());
+                }
+                // The MMR holds blocks the stored chain no longer links to, they are appended again
+                Some(&MmrAppendError::ParentMismatch { block_number }) => {
+                    warn!(block_number, "Block does not link to the previous MMR leaf");
+                    maintenance::roll_back_diverged(set, pool, block_number).await?;
+                }
+                _ => warn!(error = %e, "Error with updating MMR"),
+            },
         }
     }
-    error!("[update_mmr] Max retries reached. Failed to update MMR.");
+    error!("Max retries reached. Failed to update MMR.");
     Ok(())
 }
 
-async fn perform_mmr_update(should_terminate: &AtomicBool) -> Result<()> {
-    let last_added_blocknumber = get_last_added_blocknumber().await?;
-    info!("Last added block number: {}", last_added_blocknumber);
+async fn perform_mmr_update(
+    set: &MmrSet,
+    pool: &Pool<Postgres>,
+    should_terminate: &AtomicBool,
+) -> Result<()> {
+    let range_end = get_last_appendable_blocknumber(pool).await?;
+    set.open_shards_through(range_end).await?;
+    let shards = set.shards().await;
+
+    let mut handles = Vec::new();
+    let mut last_added_blocknumber = i64::MAX;
+    let mut highest_added_blocknumber = -1;
+    for handle in set
+        .handles
+        .iter()
+        .chain(shards.iter().flat_map(|shard| shard.handles.iter()))
+    {
+        let handle_last_added = get_last_added_blocknumber(handle).await?;
+        highest_added_blocknumber = highest_added_blocknumber.max(handle_last_added);
+        // Full shards are never appended again
+        if handle
+            .last_block
+            .is_some_and(|last_block| handle_last_added >= last_block)
+        {
+            continue;
+        }
+        info!(
+            mmr_id = handle.mmr_id,
+            "Last added block number: {}", handle_last_added
+        );
+        last_added_blocknumber = last_added_blocknumber.min(handle_last_added);
+        handles.push(handle);
+    }
 
-    let range_end = db::get_last_stored_blocknumber().await?;
+    let verification = db::verify_blockheaders(pool, last_added_blocknumber + 1, range_end).await?;
+    info!("Verified {} blocks", verification.verified_count);
+    // Appends stop at the other unverified blocks, but not at those the MMRs already hold
+    if let Some(&block_number) = verification.unlinked_parents.iter().min() {
+        if block_number <= highest_added_blocknumber {
+            return Err(MmrAppendError::Unlinked { block_number }.into());
+        }
+    }
 
-    for start_block in (last_added_blocknumber..=range_end).step_by(MMR_APPEND_LOOPSIZE as usize) {
+    for start_block in
+        (last_added_blocknumber..=range_end).step_by(set.config.append_loopsize as usize)
+    {
         if should_terminate.load(Ordering::Relaxed) {
             info!("Termination requested. Stopping MMR update process.");
             return Ok(());
         }
 
-        update_mmr_chunk(start_block, should_terminate).await?;
+        update_mmr_chunk(
+            set,
+            &handles,
+            pool,
+            start_block,
+            range_end,
+            should_terminate,
+        )
+        .await?;
     }
 
     Ok(())
 }
 
-async fn get_last_added_blocknumber() -> Result<i64> {
+/**
+ * Last block the MMRs can be appended up to
+ */
+async fn get_last_appendable_blocknumber(pool: &Pool<Postgres>) -> Result<i64> {
+    let last_stored_blocknumber = db::get_last_stored_blocknumber(pool).await?;
+    // Leased workers may write ranges out of order, only follow the completed prefix
+    match db::get_completed_leases_prefix_end(pool).await? {
+        Some(prefix_end) => Ok(last_stored_blocknumber.min(prefix_end)),
+        None => Ok(last_stored_blocknumber),
+    }
+}
+
+/**
+ * Unverified blocks from block_number on, up to the next verified one (at most mmr.append_loopsize of them). The
+ * blocks following an unverified one, e.g. all those stored before headers were verified, are usually unverified
+ * as well, so they are fetched again together.
+ */
+async fn get_unverified_blocks(
+    set: &MmrSet,
+    pool: &Pool<Postgres>,
+    block_number: i64,
+) -> Result<BlockGap> {
+    let last_appendable_blocknumber = get_last_appendable_blocknumber(pool).await?;
+    let end = last_appendable_blocknumber
+        .min(block_number + i64::from(set.config.append_loopsize) - 1)
+        .max(block_number);
+    let end = match db::find_first_verified(pool, block_number + 1, end).await? {
+        Some(verified_blocknumber) => verified_blocknumber - 1,
+        None => end,
+    };
+    Ok(BlockGap {
+        start: block_number,
+        end,
+    })
+}
+
+async fn get_last_added_blocknumber(handle: &MmrHandle) -> Result<i64> {
     // Retrieves the blocknumber for the next blockhash
-    let mmr = get_mmr().await?;
     let element_count = {
-        let mmr_guard = mmr.lock().await;
+        let mmr_guard = handle.mmr.lock().await;
         mmr_guard.elements_count.get().await?
     };
-    element_count_to_blocknumber(element_count)
+    Ok(element_count_to_blocknumber(element_count)? + handle.first_block)
 }
 
-async fn update_mmr_chunk(start_block: i64, should_terminate: &AtomicBool) -> Result<()> {
-    for _ in 0..MAX_RETRIES {
+#[instrument(name = "mmr_chunk", skip(set, handles, pool, should_terminate))]
+async fn update_mmr_chunk(
+    set: &MmrSet,
+    handles: &[&MmrHandle],
+    pool: &Pool<Postgres>,
+    start_block: i64,
+    range_end: i64,
+    should_terminate: &AtomicBool,
+) -> Result<()> {
+    for attempt in 0..set.config.max_retries {
         if should_terminate.load(Ordering::Relaxed) {
             info!("Termination requested. Stopping MMR update process.");
             return Ok(());
         }
 
-        match db::get_blockheaders(start_block, MMR_APPEND_LOOPSIZE).await {
+        match db::get_blockheaders(pool, start_block, range_end, set.config.append_loopsize).await {
             Ok(hashes) => {
                 info!(
                     "Successfully retrieved {} blockheaders. Adding hashes to MMR...",
                     hashes.len()
                 );
-                match append_to_mmr(hashes, should_terminate).await {
+                let appends = handles
+                    .iter()
+                    .map(|handle| append_new_blocks(handle, pool, &hashes, should_terminate));
+                // Every MMR appends up to the gap, if any, before it is reported
+                match join_all(appends)
+                    .await
+                    .into_iter()
+                    .collect::<Result<Vec<_>>>()
+                {
                     Ok(_) => return Ok(()),
-                    Err(e) => warn!(
-                        "[update_mmr_chunk] Error appending to MMR, blockheaders from block {}: {}",
-                        start_block, e
-                    ),
+                    Err(e) if e.is::<MmrAppendError>() => return Err(e),
+                    Err(e) => warn!(attempt, error = %e, "Error appending to MMR"),
                 }
             }
-            Err(e) => {
-                warn!(
-                    "[update_mmr_chunk] Error getting blockheaders from block {}: {}",
-                    start_block, e
-                )
-            }
+            Err(e) => warn!(attempt, error = %e, "Error getting blockheaders"),
         }
     }
     Err(anyhow::anyhow!(
@@ -138,54 +713,95 @@ async fn update_mmr_chunk(start_block: i64, should_terminate: &AtomicBool) -> Re
     ))
 }
 
+/**
+ * Appends the blocks the MMR does not hold yet, so MMRs at different sizes (or a retried chunk) can share the
+ * same block hashes
+ */
+#[instrument(skip_all, fields(hasher = handle.hasher_kind.as_str()))]
+async fn append_new_blocks(
+    handle: &MmrHandle,
+    pool: &Pool<Postgres>,
+    block_details: &[BlockDetails],
+    should_terminate: &AtomicBool,
+) -> Result<()> {
+    let last_added_blocknumber = get_last_added_blocknumber(handle).await?;
+    let new_block_details = block_details
+        .iter()
+        .filter(|block_detail| {
+            block_detail.number > last_added_blocknumber
+                && handle.leaf_index(block_detail.number).is_some()
+        })
+        .cloned()
+        .collect();
+    append_to_mmr(handle, pool, new_block_details, should_terminate).await
+}
+
 async fn append_to_mmr(
+    handle: &MmrHandle,
+    pool: &Pool<Postgres>,
     block_details: Vec<BlockDetails>,
     should_terminate: &AtomicBool,
 ) -> Result<()> {
-    let mmr = get_mmr().await?;
     // verify next in seq
-    let first_block = block_details.first();
-    let mut prev_blocknumber = match first_block {
-        None => return Ok(()),
-        Some(first_block_details) => {
-            info!("Verifing block: {}", first_block_details.number);
-            verify_first_new_block_sequence(&mmr, first_block_details).await?;
-            first_block_details.number
-        }
+    let Some(first_block_details) = block_details.first() else {
+        return Ok(());
     };
+    info!("Verifing block: {}", first_block_details.number);
+    verify_first_new_block_sequence(handle, pool, first_block_details).await?;
     info!("First block verified");
 
-    for block_detail_chunk in block_details[1..].chunks(MMR_APPEND_CHUNKSIZE) {
-        let mut mmr_guard = mmr.lock().await;
+    // Only the blocks up to the first gap (or unverified block) are appended
+    let mut appendable_count = 1;
+    let mut stop = None;
+    for (previous, block_detail) in block_details.iter().zip(&block_details[1..]) {
+        if let Err(e) = check_next_block(previous.number + 1, Some(previous), block_detail) {
+            stop = Some(e);
+            break;
+        }
+        appendable_count += 1;
+    }
+    let block_details = &block_details[..appendable_count];
+
+    let bulk_append_min_blocks = handle.config.bulk_append_min_blocks;
+    // The first block was already appended by verify_first_new_block_sequence
+    if bulk_append_min_blocks > 0 && block_details.len() > bulk_append_min_blocks {
+        if should_terminate.load(Ordering::Relaxed) {
+            info!("Termination requested. Stopping MMR update process.");
+            return Ok(());
+        }
+        bulk_append_to_mmr(handle, pool, &block_details[1..]).await?;
+        return stop.map_or(Ok(()), |e| Err(e.into()));
+    }
+
+    for block_detail_chunk in block_details[1..].chunks(handle.config.append_chunksize) {
+        let mut mmr_guard = handle.mmr.lock().await;
+        let mut roots = Vec::with_capacity(block_detail_chunk.len());
         for block_detail in block_detail_chunk {
-            
             if should_terminate.load(Ordering::Relaxed) {
                 info!("Termination requested. Stopping MMR update process.");
+                record_appends(handle, pool, &roots).await?;
                 let element_count = mmr_guard.elements_count.get().await?;
-                let last_blocknumber_added: i64 = element_count_to_blocknumber(element_count)?;
+                let last_blocknumber_added =
+                    element_count_to_blocknumber(element_count)? + handle.first_block;
 
                 info!("Last block added to MMR: {}", last_blocknumber_added);
                 return Ok(());
             }
 
-            // Checks the next hash to be added is what is expected (ensures hashes are added in order and without gaps)
-            assert_eq!(
-                block_detail.number,
-                prev_blocknumber + 1,
-                "Blockdetails not added in order. Expected blocknumber: {}, received blocknumber: {}",
-                prev_blocknumber + 1,
-                block_detail.number
-            );
-
-            let append_result: AppendResult = mmr_guard
-                .append(block_detail.block_hash.to_string())
-                .await?;
+            let leaf = handle.leaf_hash(&block_detail.block_hash)?;
+            let append_result: AppendResult = mmr_guard.append(leaf).await?;
 
-            update_mmr_stats(block_detail.number, append_result.root_hash).await?;
+            roots.push(update_mmr_stats(
+                handle,
+                block_detail.number,
+                append_result.elements_count,
+                append_result.root_hash,
+            )?);
 
-            debug!("Block {} added", prev_blocknumber);
-            prev_blocknumber = block_detail.number;
+            debug!(block_number = block_detail.number, "Block appended to MMR");
         }
+        // Written before releasing the lock, so the recorded roots keep up with the MMR
+        record_appends(handle, pool, &roots).await?;
     }
 
     match block_details.last() {
@@ -196,65 +812,990 @@ async fn append_to_mmr(
             warn!("Can't retrieve last block added. Error unwrapping.")
         }
     }
+    stop.map_or(Ok(()), |e| Err(e.into()))
+}
+
+/**
+ * Checks block_detail can be appended as block expected: it is verified, and its parent is previous when given
+ */
+fn check_next_block(
+    expected: i64,
+    previous: Option<&BlockDetails>,
+    block_detail: &BlockDetails,
+) -> Result<(), MmrAppendError> {
+    let received = block_detail.number;
+    match received.cmp(&expected) {
+        std::cmp::Ordering::Equal => {}
+        std::cmp::Ordering::Greater => {
+            return Err(MmrAppendError::Gap(BlockGap {
+                start: expected,
+                end: received - 1,
+            }))
+        }
+        std::cmp::Ordering::Less => return Err(MmrAppendError::OutOfOrder { expected, received }),
+    }
+
+    if !block_detail.verified {
+        return Err(MmrAppendError::Unverified {
+            block_number: received,
+        });
+    }
+    if let Some(previous) = previous {
+        let links = block_detail
+            .parent_hash
+            .as_ref()
+            .is_some_and(|parent_hash| parent_hash.eq_ignore_ascii_case(&previous.block_hash));
+        if !links {
+            return Err(MmrAppendError::ParentMismatch {
+                block_number: received,
+            });
+        }
+    }
     Ok(())
 }
 
 /**
- * Verifies that the first hash to be added is the next one that is missing from the MMR
+ * Checks the parent of block_detail is the last leaf of the MMR
  */
-async fn verify_first_new_block_sequence(
-    mmr: &Arc<Mutex<MMR>>,
-    first_block_details: &BlockDetails,
+async fn check_previous_leaf(
+    handle: &MmrHandle,
+    mmr: &MMR,
+    block_detail: &BlockDetails,
 ) -> Result<()> {
-    let mut mmr_guard = mmr.lock().await;
+    let Some(previous_leaf_index) = handle
+        .leaf_index(block_detail.number)
+        .and_then(|leaf_index| leaf_index.checked_sub(1))
+    else {
+        return Ok(());
+    };
 
-    let mut draft = mmr_guard.start_draft().await?;
-    let append_result = draft
-        .mmr
-        .append(first_block_details.block_hash.to_string())
+    let previous_element_index = map_leaf_index_to_element_index(previous_leaf_index);
+    let previous_leaf = mmr
+        .hashes
+        .get(SubKey::Usize(previous_element_index))
         .await?;
+    let parent_leaf = match &block_detail.parent_hash {
+        Some(parent_hash) => Some(handle.leaf_hash(parent_hash)?),
+        None => None,
+    };
+    match (previous_leaf, parent_leaf) {
+        (Some(previous_leaf), Some(parent_leaf))
+            if previous_leaf.eq_ignore_ascii_case(&parent_leaf) =>
+        {
+            Ok(())
+        }
+        _ => Err(MmrAppendError::ParentMismatch {
+            block_number: block_detail.number,
+        }
+        .into()),
+    }
+}
 
-    let expected_number: i64 =
-        element_index_to_leaf_index(append_result.element_index)?.try_into()?;
+/**
+ * Records the roots and leaf indexes of appended blocks, then serves reads at the new size
+ */
+async fn record_appends(
+    handle: &MmrHandle,
+    pool: &Pool<Postgres>,
+    roots: &[MmrRoot],
+) -> Result<()> {
+    match handle.records {
+        Records::Live => db::write_mmr_roots(pool, &handle.mmr_id, roots).await?,
+        Records::Rebuild => db::write_rebuilt_mmr_roots(pool, &handle.mmr_id, roots).await?,
+    }
+    // Header rows are joined with the MMRs over the whole chain
+    if !handle.is_shard() {
+        let leaf_indexes = roots
+            .iter()
+            .map(|root| {
+                let leaf_count = elements_count_to_leaf_count(root.elements_count.try_into()?)?;
+                Ok((root.block_number, i64::try_from(leaf_count)? - 1))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        match handle.records {
+            Records::Live => db::set_mmr_leaf_indexes(pool, &leaf_indexes).await?,
+            Records::Rebuild => db::set_rebuilt_mmr_leaf_indexes(pool, &leaf_indexes).await?,
+        }
+    }
 
-    assert_eq!(
-        first_block_details.number, expected_number,
-        "Blockdetail not added in order. Expected blocknumber: {}, received blocknumber: {}",
-        expected_number, first_block_details.number
-    );
+    if let Some(root) = roots.last() {
+        handle.commit(root.elements_count.try_into()?);
+    }
+    Ok(())
+}
+
+/**
+ * Appends consecutive blocks following the last one in the MMR with the bulk builder, under a single lock
+ */
+async fn bulk_append_to_mmr(
+    handle: &MmrHandle,
+    pool: &Pool<Postgres>,
+    block_details: &[BlockDetails],
+) -> Result<()> {
+    let block_hashes = block_details
+        .iter()
+        .map(|block_detail| block_detail.block_hash.clone())
+        .collect();
+    let mmr_guard = handle.mmr.lock().await;
+    let bulk_roots = bulk::bulk_append(
+        &mmr_guard,
+        handle.hasher_kind,
+        handle.hasher.clone(),
+        block_hashes,
+    )
+    .await?;
+
+    let roots = block_details
+        .iter()
+        .zip(bulk_roots)
+        .map(|(block_detail, root)| {
+            update_mmr_stats(
+                handle,
+                block_detail.number,
+                root.elements_count,
+                root.root_hash,
+            )
+        })
+        .collect::<Result<Vec<_>>>()?;
+    record_appends(handle, pool, &roots).await?;
+
+    if let Some(detail) = block_details.last() {
+        info!("Last block added to MMR: {}", detail.number);
+    }
+    Ok(())
+}
+
+/**
+ * Verifies that the first hash to be added is the next one that is missing from the MMR, and that its parent is
+ * the last leaf
+ */
+async fn verify_first_new_block_sequence(
+    handle: &MmrHandle,
+    pool: &Pool<Postgres>,
+    first_block_details: &BlockDetails,
+) -> Result<()> {
+    let mut mmr_guard = handle.mmr.lock().await;
+
+    let leaf_count = elements_count_to_leaf_count(mmr_guard.elements_count.get().await?)?;
+    let expected_number = i64::try_from(leaf_count)? + handle.first_block;
+    check_next_block(expected_number, None, first_block_details)?;
+    check_previous_leaf(handle, &mmr_guard, first_block_details).await?;
 
-    draft.commit().await?;
-    update_mmr_stats(first_block_details.number, append_result.root_hash).await?;
+    let leaf = handle.leaf_hash(&first_block_details.block_hash)?;
+    let append_result = mmr_guard.append(leaf).await?;
+    let root = update_mmr_stats(
+        handle,
+        first_block_details.number,
+        append_result.elements_count,
+        append_result.root_hash,
+    )?;
+    record_appends(handle, pool, &[root]).await?;
+    debug!(
+        block_number = first_block_details.number,
+        "Block appended to MMR"
+    );
 
     Ok(())
 }
 
-pub async fn get_proof(blocknumber: i64) -> Result<Proof> {
-    let leaf_index: usize = (blocknumber + 1).try_into()?;
+/**
+ * Proves the hash of block blocknumber (leaf blocknumber) against the root of the MMR when it had elements_count
+ * elements (Default: current size)
+ *
+ * @Returns None if the block was not appended at that size, or the MMR has not reached that size yet
+ */
+pub async fn get_proof(
+    handle: &MmrHandle,
+    pool: &Pool<Postgres>,
+    blocknumber: i64,
+    elements_count: Option<usize>,
+) -> Result<Option<MmrProof>> {
+    let Some(leaf_index) = handle.leaf_index(blocknumber) else {
+        return Ok(None);
+    };
+    let Some(elements_count) = resolve_elements_count(handle, elements_count) else {
+        return Ok(None);
+    };
+    if leaf_index >= elements_count_to_leaf_count(elements_count)? {
+        return Ok(None);
+    }
+
     let element_index: usize = map_leaf_index_to_element_index(leaf_index);
-    let mmr: Arc<Mutex<MMR>> = get_mmr().await?;
-    let mmr_guard: MutexGuard<MMR> = mmr.lock().await;
-    let res: Proof = mmr_guard.get_proof(element_index, None).await?;
+    let proof = handle
+        .reader
+        .get_proof(
+            element_index,
+            Some(ProofOptions {
+                elements_count: Some(elements_count),
+                formatting_opts: None,
+            }),
+        )
+        .await?;
+    let root = get_root_hash(&handle.reader, elements_count).await?;
 
-    Ok(res)
+    let mut block_hashes =
+        get_block_hashes(handle, pool, &[(blocknumber, proof.element_hash.as_str())]).await?;
+    Ok(Some(MmrProof {
+        version: PROOF_SCHEMA_VERSION,
+        hasher: handle.hasher_kind,
+        block_number: blocknumber,
+        block_hash: block_hashes.remove(&blocknumber).unwrap_or_default(),
+        leaf_index,
+        element_index: proof.element_index,
+        element_hash: proof.element_hash,
+        siblings_hashes: proof.siblings_hashes,
+        peaks_hashes: proof.peaks_hashes,
+        elements_count: proof.elements_count,
+        root,
+        shard: handle.proof_shard(),
+        header_rlp: None,
+    }))
 }
 
-async fn update_mmr_stats(latest_blocknumber: i64, latest_roothash: String) -> Result<()> {
-    let mut update_guard = LATEST_UPDATE.lock().await;
-    *update_guard = Update {
-        latest_blocknumber,
-        latest_roothash,
-        update_timestamp: Utc::now(),
+/**
+ * Proves the hashes of several blocks against the same MMR size (Default: current size)
+ *
+ * @Returns None if any of the blocks was not appended at that size, or the MMR has not reached that size yet
+ */
+pub async fn get_proofs(
+    handle: &MmrHandle,
+    pool: &Pool<Postgres>,
+    blocknumbers: &[i64],
+    elements_count: Option<usize>,
+) -> Result<Option<MmrBatchProof>> {
+    let Some(leaf_indexes) = blocknumbers
+        .iter()
+        .map(|&blocknumber| handle.leaf_index(blocknumber))
+        .collect::<Option<Vec<usize>>>()
+    else {
+        return Ok(None);
     };
-    Ok(())
+    let Some(elements_count) = resolve_elements_count(handle, elements_count) else {
+        return Ok(None);
+    };
+    let leaf_count = elements_count_to_leaf_count(elements_count)?;
+    if leaf_indexes
+        .iter()
+        .any(|&leaf_index| leaf_index >= leaf_count)
+    {
+        return Ok(None);
+    }
+
+    // The store fails on repeated keys, so each element is proven once
+    let element_indexes: BTreeSet<usize> = leaf_indexes
+        .iter()
+        .map(|&leaf_index| map_leaf_index_to_element_index(leaf_index))
+        .collect();
+    let proofs: HashMap<usize, Proof> = handle
+        .reader
+        .get_proofs(
+            element_indexes.into_iter().collect(),
+            Some(ProofOptions {
+                elements_count: Some(elements_count),
+                formatting_opts: None,
+            }),
+        )
+        .await?
+        .into_iter()
+        .map(|proof| (proof.element_index, proof))
+        .collect();
+    let root = get_root_hash(&handle.reader, elements_count).await?;
+
+    let leaves: Vec<(i64, &str)> = blocknumbers
+        .iter()
+        .zip(&leaf_indexes)
+        .map(|(&blocknumber, &leaf_index)| {
+            let element_index = map_leaf_index_to_element_index(leaf_index);
+            (blocknumber, proofs[&element_index].element_hash.as_str())
+        })
+        .collect();
+    let block_hashes = get_block_hashes(handle, pool, &leaves).await?;
+
+    let peaks_hashes = proofs
+        .values()
+        .next()
+        .map(|proof| proof.peaks_hashes.clone())
+        .unwrap_or_default();
+    let proofs = blocknumbers
+        .iter()
+        .zip(leaf_indexes)
+        .map(|(&blocknumber, leaf_index)| {
+            let proof = &proofs[&map_leaf_index_to_element_index(leaf_index)];
+            MmrBatchProofItem {
+                block_number: blocknumber,
+                block_hash: block_hashes[&blocknumber].clone(),
+                leaf_index,
+                element_index: proof.element_index,
+                element_hash: proof.element_hash.clone(),
+                siblings_hashes: proof.siblings_hashes.clone(),
+                header_rlp: None,
+            }
+        })
+        .collect();
+
+    Ok(Some(MmrBatchProof {
+        version: PROOF_SCHEMA_VERSION,
+        hasher: handle.hasher_kind,
+        elements_count,
+        root,
+        peaks_hashes,
+        proofs,
+    }))
+}
+
+/**
+ * Block hashes of the proven (block number, leaf) pairs. Keccak leaves are the block hashes themselves, other
+ * leaves are derived from the stored block hashes, which are checked against them
+ */
+async fn get_block_hashes(
+    handle: &MmrHandle,
+    pool: &Pool<Postgres>,
+    leaves: &[(i64, &str)],
+) -> Result<HashMap<i64, String>> {
+    if handle.hasher_kind == MmrHasher::Keccak {
+        return Ok(leaves
+            .iter()
+            .map(|&(blocknumber, leaf)| (blocknumber, leaf.to_string()))
+            .collect());
+    }
+
+    let blocknumbers: Vec<i64> = leaves.iter().map(|&(blocknumber, _)| blocknumber).collect();
+    let block_hashes = db::get_block_hashes(pool, &blocknumbers).await?;
+    for &(blocknumber, leaf) in leaves {
+        let Some(block_hash) = block_hashes.get(&blocknumber) else {
+            bail!("Block {blocknumber} is in the MMR but not stored");
+        };
+        if handle.leaf_hash(block_hash)? != leaf {
+            bail!("Stored hash of block {blocknumber} does not match its MMR leaf");
+        }
+    }
+    Ok(block_hashes)
 }
 
-pub async fn get_mmr_stats() -> Result<Update> {
-    let update_guard = LATEST_UPDATE.lock().await;
-    Ok(update_guard.clone())
+/**
+ * Size of the MMR right after block blocknumber was appended
+ */
+pub fn elements_count_after_block(blocknumber: i64) -> Result<usize> {
+    let leaf_count: usize = blocknumber
+        .checked_add(1)
+        .with_context(|| format!("Block number {blocknumber} is too large"))?
+        .try_into()?;
+    let elements_count = leaf_count
+        .checked_mul(2)
+        .with_context(|| format!("Block number {blocknumber} is too large"))?;
+    Ok(elements_count - leaf_count.count_ones() as usize)
+}
+
+/**
+ * Whether the MMR can have exactly elements_count elements, i.e. whether it is a size proofs can target
+ */
+pub fn is_valid_elements_count(elements_count: usize) -> bool {
+    // elements_count_to_leaf_count overflows (and panics) on the largest sizes
+    elements_count > 0
+        && elements_count <= MAX_ELEMENTS_COUNT
+        && elements_count_to_leaf_count(elements_count).is_ok()
+}
+
+/**
+ * Returns the requested size, or the committed one, or None if the MMR has not reached the requested size yet
+ */
+fn resolve_elements_count(handle: &MmrHandle, elements_count: Option<usize>) -> Option<usize> {
+    let committed_elements_count = handle.committed_elements_count();
+    match elements_count {
+        Some(elements_count) if elements_count > committed_elements_count => None,
+        Some(elements_count) => Some(elements_count),
+        None => Some(committed_elements_count),
+    }
+}
+
+/**
+ * Root of the MMR when it had elements_count elements, or None if it has not reached that size yet
+ */
+pub async fn get_root_hash_at(handle: &MmrHandle, elements_count: usize) -> Result<Option<String>> {
+    match resolve_elements_count(handle, Some(elements_count)) {
+        Some(elements_count) => Ok(Some(get_root_hash(&handle.reader, elements_count).await?)),
+        None => Ok(None),
+    }
+}
+
+/**
+ * Root of the MMR when it had elements_count elements
+ */
+async fn get_root_hash(mmr: &MMR, elements_count: usize) -> Result<String> {
+    let bag = mmr.bag_the_peaks(Some(elements_count)).await?;
+    Ok(mmr.calculate_root_hash(&bag, elements_count)?)
+}
+
+/**
+ * Bags the peaks hashes right to left the same way the MMR does, without access to a store
+ */
+pub fn bag_peaks(hasher: &dyn Hasher, peaks_hashes: &[String]) -> Result<String> {
+    let Some((last, rest)) = peaks_hashes.split_last() else {
+        return Ok("0x0".to_string());
+    };
+    let mut bag = last.clone();
+    for peak_hash in rest.iter().rev() {
+        bag = hasher.hash(vec![peak_hash.clone(), bag])?;
+    }
+    Ok(bag)
+}
+
+/**
+ * Root of an MMR of elements_count elements with the given peaks hashes
+ */
+pub fn compute_root_hash(
+    hasher: &dyn Hasher,
+    elements_count: usize,
+    peaks_hashes: &[String],
+) -> Result<String> {
+    let bag = bag_peaks(hasher, peaks_hashes)?;
+    Ok(hasher.hash(vec![elements_count.to_string(), bag])?)
+}
+
+/**
+ * Checks an inclusion proof against root with the proof's hasher: the leaf of the block hash must hash up to its
+ * peak through the siblings, and the peaks must bag into root. Needs no store, so proofs can be checked without
+ * access to the MMR.
+ */
+pub fn verify_proof(proof: &MmrProof, root: &str) -> Result<bool> {
+    if proof.version != PROOF_SCHEMA_VERSION {
+        bail!("Unsupported proof version {}", proof.version);
+    }
+    let elements_count = proof.elements_count;
+    let first_block = proof.shard.map_or(0, |shard| shard.first_block);
+    let Some(Ok(leaf_index)) = proof
+        .block_number
+        .checked_sub(first_block)
+        .map(usize::try_from)
+    else {
+        return Ok(false);
+    };
+    if !is_valid_elements_count(elements_count)
+        || proof.leaf_index != leaf_index
+        || proof.element_index != map_leaf_index_to_element_index(leaf_index)
+        || proof.element_index > elements_count
+    {
+        return Ok(false);
+    }
+
+    let hasher = new_hasher(proof.hasher);
+    match leaf_hash(proof.hasher, hasher.as_ref(), &proof.block_hash) {
+        Ok(leaf) if leaf == proof.element_hash => {}
+        _ => return Ok(false),
+    }
+    if let Some(header_rlp) = &proof.header_rlp {
+        let header_rlp = hex::decode(header_rlp.strip_prefix("0x").unwrap_or(header_rlp));
+        match header_rlp {
+            Ok(header_rlp)
+                if format!("0x{}", hex::encode(keccak256(&header_rlp))) == proof.block_hash => {}
+            _ => return Ok(false),
+        }
+    }
+
+    let peaks = find_peaks(elements_count);
+    if proof.peaks_hashes.len() != peaks.len() {
+        return Ok(false);
+    }
+    let (steps, peak_index) = climb_to_peak(proof.element_index, &peaks);
+    if steps.len() != proof.siblings_hashes.len() {
+        return Ok(false);
+    }
+
+    let mut hash = proof.element_hash.clone();
+    for ((_, is_right), sibling_hash) in steps.iter().zip(&proof.siblings_hashes) {
+        hash = hasher.hash(if *is_right {
+            vec![sibling_hash.clone(), hash]
+        } else {
+            vec![hash, sibling_hash.clone()]
+        })?;
+    }
+    if proof.peaks_hashes[peak_index] != hash {
+        return Ok(false);
+    }
+
+    let computed_root = compute_root_hash(hasher.as_ref(), elements_count, &proof.peaks_hashes)?;
+    Ok(computed_root.eq_ignore_ascii_case(root))
+}
+
+/**
+ * Updates the metrics after an append and returns the root to record for the block
+ */
+fn update_mmr_stats(
+    handle: &MmrHandle,
+    latest_blocknumber: i64,
+    elements_count: usize,
+    root_hash: String,
+) -> Result<MmrRoot> {
+    let elements_count: i64 = elements_count.try_into()?;
+    // The metrics follow the MMRs over the whole chain
+    if !handle.is_shard() {
+        let hasher = handle.hasher_kind.as_str();
+        handle
+            .metrics
+            .mmr_appends
+            .with_label_values(&[hasher])
+            .inc();
+        handle
+            .metrics
+            .mmr_elements_count
+            .with_label_values(&[hasher])
+            .set(elements_count);
+        handle
+            .metrics
+            .mmr_latest_blocknumber
+            .with_label_values(&[hasher])
+            .set(latest_blocknumber);
+    }
+
+    Ok(MmrRoot {
+        block_number: latest_blocknumber,
+        elements_count,
+        root_hash,
+        created_at: Utc::now(),
+    })
+}
+
+/**
+ * Reads the latest recorded root, so it survives restarts
+ *
+ * @Returns None if nothing was appended yet
+ */
+pub async fn get_mmr_stats(handle: &MmrHandle, pool: &Pool<Postgres>) -> Result<Option<Update>> {
+    let latest_root = db::get_latest_mmr_root(pool, &handle.mmr_id).await?;
+    Ok(latest_root.map(|root| Update {
+        latest_blocknumber: root.block_number,
+        latest_roothash: root.root_hash,
+        update_timestamp: root.created_at,
+    }))
+}
+
+/**
+ * Root recorded right after block blocknumber was appended
+ */
+pub async fn get_mmr_root(
+    handle: &MmrHandle,
+    pool: &Pool<Postgres>,
+    blocknumber: i64,
+) -> Result<Option<MmrRoot>> {
+    db::get_mmr_root(pool, &handle.mmr_id, blocknumber).await
+}
+
+/**
+ * Range, size and root of the hasher's MMR of shard, at its committed size
+ */
+pub async fn get_shard_info(shard: &MmrShard, hasher: MmrHasher) -> Result<Option<MmrShardInfo>> {
+    let Some(handle) = shard.get(hasher) else {
+        return Ok(None);
+    };
+    let elements_count = handle.committed_elements_count();
+    let root_hash = match elements_count {
+        0 => None,
+        _ => Some(get_root_hash(&handle.reader, elements_count).await?),
+    };
+    let leaf_count = elements_count_to_leaf_count(elements_count)?;
+    Ok(Some(MmrShardInfo {
+        shard: shard.index,
+        first_block: shard.first_block,
+        last_block: shard.last_block,
+        mmr_id: handle.mmr_id.clone(),
+        hasher,
+        elements_count,
+        root_hash,
+        complete: i64::try_from(leaf_count)? == shard.last_block - shard.first_block + 1,
+    }))
+}
+
+pub async fn get_last_completed_update(handle: &MmrHandle) -> DateTime<Utc> {
+    *handle.last_completed_update.lock().await
+}
+
+/**
+ * Reads the element count from the store, which fails when the store is not accessible
+ */
+pub async fn get_elements_count(handle: &MmrHandle) -> Result<usize> {
+    Ok(handle.reader.elements_count.get().await?)
+}
+
+/**
+ * Climbs from position until it is one of peaks, returning each sibling position with whether the climbing node
+ * is the right child, and the index of the peak reached
+ */
+fn climb_to_peak(mut position: usize, peaks: &[usize]) -> (Vec<(usize, bool)>, usize) {
+    let mut steps = Vec::new();
+    let mut height = node_height(position);
+    loop {
+        if let Some(peak_index) = peaks.iter().position(|&peak| peak == position) {
+            return (steps, peak_index);
+        }
+        let offset = (1 << (height + 1)) - 1;
+        if node_height(position + 1) > height {
+            steps.push((position - offset, true));
+            position += 1;
+        } else {
+            steps.push((position + offset, false));
+            position += offset + 1;
+        }
+        height += 1;
+    }
+}
+
+/**
+ * Height of the node at the 1-based position, leaves being at height 0
+ */
+fn node_height(mut position: usize) -> usize {
+    while !(position + 1).is_power_of_two() {
+        let bit_length = usize::BITS - position.leading_zeros();
+        position -= (1 << (bit_length - 1)) - 1;
+    }
+    (usize::BITS - position.leading_zeros() - 1) as usize
 }
 
 fn element_count_to_blocknumber(element_count: usize) -> Result<i64> {
     let leaf_count: i64 = elements_count_to_leaf_count(element_count)?.try_into()?;
     Ok(leaf_count - 1)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use accumulators::store::memory::InMemoryStore;
+    use sqlx::postgres::PgPoolOptions;
+
+    /**
+     * Hash of test block blocknumber
+     */
+    pub(super) fn block_hash(blocknumber: i64) -> String {
+        format!("0x{}", hex::encode(keccak256(&blocknumber.to_be_bytes())))
+    }
+
+    /**
+     * In-memory MMR over blocks (first block, last block if it is a shard), holding the first leaf_count of them
+     */
+    pub(super) async fn test_handle(
+        hasher_kind: MmrHasher,
+        blocks: (i64, Option<i64>),
+        leaf_count: i64,
+    ) -> MmrHandle {
+        let config = MmrConfig {
+            shard_size: blocks.1.map_or(0, |last_block| last_block - blocks.0 + 1),
+            ..MmrConfig::default()
+        };
+        let handle = MmrHandle::with_store(
+            &config,
+            hasher_kind,
+            config.mmr_id_for(hasher_kind),
+            blocks,
+            Arc::new(InMemoryStore::new(None)),
+            Records::Live,
+            Arc::new(Metrics::new().unwrap()),
+        )
+        .await
+        .unwrap();
+        append_blocks(&handle, leaf_count).await;
+        handle
+    }
+
+    /**
+     * Appends the next leaf_count blocks to the MMR, then serves reads at its new size
+     */
+    pub(super) async fn append_blocks(handle: &MmrHandle, leaf_count: i64) {
+        let mut mmr = handle.mmr.lock().await;
+        let first_new_block = get_leaf_count(&mmr).await + handle.first_block;
+        for blocknumber in first_new_block..first_new_block + leaf_count {
+            let leaf = handle.leaf_hash(&block_hash(blocknumber)).unwrap();
+            mmr.append(leaf).await.unwrap();
+        }
+        handle.commit(mmr.elements_count.get().await.unwrap());
+    }
+
+    async fn get_leaf_count(mmr: &MMR) -> i64 {
+        let elements_count = mmr.elements_count.get().await.unwrap();
+        elements_count_to_leaf_count(elements_count).unwrap() as i64
+    }
+
+    /**
+     * Keccak proofs read their block hashes from the MMR, never from the database
+     */
+    fn unused_pool() -> Pool<Postgres> {
+        PgPoolOptions::new()
+            .connect_lazy("postgres://localhost/unused")
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn get_proof_verifies() {
+        let pool = unused_pool();
+        let handle = test_handle(MmrHasher::Keccak, (0, None), 21).await;
+        for blocknumber in 0..21 {
+            for elements_count in [None, Some(elements_count_after_block(blocknumber).unwrap())] {
+                let proof = get_proof(&handle, &pool, blocknumber, elements_count)
+                    .await
+                    .unwrap()
+                    .unwrap();
+                assert_eq!(proof.block_hash, block_hash(blocknumber));
+                let root = get_root_hash_at(&handle, proof.elements_count)
+                    .await
+                    .unwrap()
+                    .unwrap();
+                assert_eq!(proof.root, root);
+                assert!(verify_proof(&proof, &root).unwrap(), "block {blocknumber}");
+            }
+        }
+        assert!(get_proof(&handle, &pool, 21, None).await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn get_proof_maps_blocks_to_leaves_and_elements() {
+        let pool = unused_pool();
+        let handle = test_handle(MmrHasher::Keccak, (0, None), 21).await;
+        for (blocknumber, element_index) in [(0, 1), (1, 2), (2, 4), (3, 5), (4, 8), (20, 39)] {
+            let proof = get_proof(&handle, &pool, blocknumber, None)
+                .await
+                .unwrap()
+                .unwrap();
+            assert_eq!(proof.leaf_index, blocknumber as usize);
+            assert_eq!(proof.element_index, element_index, "block {blocknumber}");
+            assert_eq!(proof.element_hash, block_hash(blocknumber));
+            assert_eq!(proof.elements_count, 39);
+        }
+    }
+
+    #[tokio::test]
+    async fn shard_proof_verifies() {
+        let pool = unused_pool();
+        let handle = test_handle(MmrHasher::Keccak, (16, Some(31)), 10).await;
+        let proof = get_proof(&handle, &pool, 20, None).await.unwrap().unwrap();
+        assert_eq!(proof.leaf_index, 4);
+        assert_eq!(
+            proof.shard,
+            Some(ProofShard {
+                index: 1,
+                first_block: 16
+            })
+        );
+        assert!(verify_proof(&proof, &proof.root.clone()).unwrap());
+        assert!(get_proof(&handle, &pool, 15, None).await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn verify_proof_rejects_altered_proofs() {
+        let pool = unused_pool();
+        let handle = test_handle(MmrHasher::Keccak, (0, None), 21).await;
+        let proof = get_proof(&handle, &pool, 5, None).await.unwrap().unwrap();
+        let root = proof.root.clone();
+        assert!(verify_proof(&proof, &root).unwrap());
+
+        let flip = |hash: &str| {
+            let mut hash = hash.to_string();
+            let last = if hash.ends_with('0') { "1" } else { "0" };
+            hash.replace_range(hash.len() - 1.., last);
+            hash
+        };
+        let mut altered: Vec<MmrProof> = Vec::new();
+        for index in 0..proof.siblings_hashes.len() {
+            let mut flipped_sibling = proof.clone();
+            flipped_sibling.siblings_hashes[index] = flip(&proof.siblings_hashes[index]);
+            altered.push(flipped_sibling);
+        }
+        let mut flipped_peak = proof.clone();
+        flipped_peak.peaks_hashes[0] = flip(&proof.peaks_hashes[0]);
+        altered.push(flipped_peak);
+        let mut other_leaf_index = proof.clone();
+        other_leaf_index.leaf_index = 6;
+        altered.push(other_leaf_index);
+        let mut other_element_index = proof.clone();
+        other_element_index.element_index += 1;
+        altered.push(other_element_index);
+        let mut other_block = proof.clone();
+        other_block.block_number = 6;
+        altered.push(other_block);
+        let mut other_block_hash = proof.clone();
+        other_block_hash.block_hash = block_hash(6);
+        altered.push(other_block_hash);
+        let mut shard_past_block = proof.clone();
+        shard_past_block.shard = Some(ProofShard {
+            index: 1,
+            first_block: 16,
+        });
+        altered.push(shard_past_block);
+        let mut underflowing_shard = proof.clone();
+        underflowing_shard.shard = Some(ProofShard {
+            index: 0,
+            first_block: i64::MIN,
+        });
+        altered.push(underflowing_shard);
+        let mut other_size = proof.clone();
+        other_size.elements_count = 38;
+        altered.push(other_size);
+        let mut invalid_size = proof.clone();
+        invalid_size.elements_count = u64::MAX as usize;
+        altered.push(invalid_size);
+
+        for (index, altered) in altered.iter().enumerate() {
+            assert!(!verify_proof(altered, &root).unwrap(), "alteration {index}");
+        }
+        assert!(!verify_proof(&proof, &flip(&root)).unwrap());
+        let other_root = get_root_hash_at(&handle, 38).await.unwrap().unwrap();
+        assert!(!verify_proof(&proof, &other_root).unwrap());
+    }
+
+    #[tokio::test]
+    async fn verify_proof_checks_the_header_rlp() {
+        let pool = unused_pool();
+        let handle = test_handle(MmrHasher::Keccak, (0, None), 3).await;
+        // Block 3 is the block whose header RLP is an empty list
+        let header_rlp = "0xc0";
+        let header_hash = format!("0x{}", hex::encode(keccak256(&[0xc0])));
+        {
+            let mut mmr = handle.mmr.lock().await;
+            let append_result = mmr.append(header_hash.clone()).await.unwrap();
+            handle.commit(append_result.elements_count);
+        }
+
+        let mut proof = get_proof(&handle, &pool, 3, None).await.unwrap().unwrap();
+        let root = proof.root.clone();
+        assert_eq!(proof.block_hash, header_hash);
+        proof.header_rlp = Some(header_rlp.to_string());
+        assert!(verify_proof(&proof, &root).unwrap());
+
+        for other_header_rlp in ["0xc1", "0x", "0xc0c0", "0xnothex"] {
+            proof.header_rlp = Some(other_header_rlp.to_string());
+            assert!(!verify_proof(&proof, &root).unwrap(), "{other_header_rlp}");
+        }
+    }
+
+    #[test]
+    fn elements_count_after_block_matches_mmr_sizes() {
+        assert_eq!(elements_count_after_block(0).unwrap(), 1);
+        assert_eq!(elements_count_after_block(1).unwrap(), 3);
+        assert_eq!(elements_count_after_block(2).unwrap(), 4);
+        assert_eq!(elements_count_after_block(3).unwrap(), 7);
+        assert_eq!(elements_count_after_block(20).unwrap(), 39);
+    }
+
+    #[test]
+    fn elements_count_after_block_rejects_overflowing_blocks() {
+        assert!(elements_count_after_block(i64::MAX).is_err());
+        assert!(elements_count_after_block(-2).is_err());
+    }
+
+    #[test]
+    fn is_valid_elements_count_bounds_sizes() {
+        for elements_count in [1, 3, 4, 7, 8, 10, 11, 15, 39] {
+            assert!(is_valid_elements_count(elements_count), "{elements_count}");
+        }
+        for elements_count in [0, 2, 5, 6, 9, 12, 13, 14] {
+            assert!(!is_valid_elements_count(elements_count), "{elements_count}");
+        }
+
+        // Would panic in elements_count_to_leaf_count
+        assert!(!is_valid_elements_count(u64::MAX as usize));
+        assert!(!is_valid_elements_count(1 << 63));
+        assert!(!is_valid_elements_count(MAX_ELEMENTS_COUNT + 1));
+        assert!(is_valid_elements_count(MAX_ELEMENTS_COUNT));
+    }
+
+    /**
+     * Root of an in-memory keccak MMR over block_hashes
+     */
+    async fn keccak_root(block_hashes: &[String]) -> String {
+        let mut mmr = MMR::new(
+            Arc::new(InMemoryStore::new(None)),
+            new_hasher(MmrHasher::Keccak),
+            None,
+        );
+        for block_hash in block_hashes {
+            mmr.append(block_hash.clone()).await.unwrap();
+        }
+        get_root_hash(&mmr, mmr.elements_count.get().await.unwrap())
+            .await
+            .unwrap()
+    }
+
+    async fn committed_root(handle: &MmrHandle) -> String {
+        get_root_hash(&handle.reader, handle.committed_elements_count())
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    #[ignore = "needs Postgres at DB_CONNECTION_STRING"]
+    async fn block_replaced_by_another_hash_is_rolled_back() {
+        let pool = db::tests::test_pool("test_mmr_rollback").await;
+        let chain = db::tests::test_chain(5, 0);
+        let block_hashes: Vec<String> = chain.iter().map(|header| header.hash.clone()).collect();
+        let wrong_block = db::tests::test_header(2, &block_hashes[1], 1);
+        let wrong_block_hash = wrong_block.hash.clone();
+        let config = MmrConfig {
+            shard_size: 4,
+            ..MmrConfig::default()
+        };
+        let set = MmrSet::with_store(
+            &config,
+            Arc::new(InMemoryStore::new(None)),
+            Arc::new(Metrics::new().unwrap()),
+        )
+        .await
+        .unwrap();
+        let handle = set.get(MmrHasher::Keccak).unwrap();
+        let should_terminate = AtomicBool::new(false);
+
+        // A faulty node served another block 2, which links to block 1 and is appended
+        let mut chain = chain.into_iter();
+        db::write_blockheader(&pool, chain.next().unwrap())
+            .await
+            .unwrap();
+        db::write_blockheader(&pool, chain.next().unwrap())
+            .await
+            .unwrap();
+        db::write_blockheader(&pool, wrong_block).await.unwrap();
+        update_mmr(&set, &pool, &should_terminate).await.unwrap();
+        assert_eq!(get_last_added_blocknumber(handle).await.unwrap(), 2);
+        let wrong_block_hashes = [
+            block_hashes[0].clone(),
+            block_hashes[1].clone(),
+            wrong_block_hash,
+        ];
+        assert_eq!(
+            committed_root(handle).await,
+            keccak_root(&wrong_block_hashes).await
+        );
+
+        // Block 3 does not link to it: the MMRs drop it, and both blocks are fetched again
+        let block_2 = chain.next().unwrap();
+        for header in chain {
+            db::write_blockheader(&pool, header).await.unwrap();
+        }
+        update_mmr(&set, &pool, &should_terminate).await.unwrap();
+        assert_eq!(set.take_gap().await, Some(BlockGap { start: 2, end: 3 }));
+        assert_eq!(get_last_added_blocknumber(handle).await.unwrap(), 1);
+        let shards = set.shards().await;
+        assert_eq!(
+            get_last_added_blocknumber(&shards[0].handles[0])
+                .await
+                .unwrap(),
+            1
+        );
+        drop(shards);
+
+        db::write_blockheader(&pool, block_2).await.unwrap();
+        update_mmr(&set, &pool, &should_terminate).await.unwrap();
+        assert_eq!(set.take_gap().await, None);
+        assert_eq!(get_last_added_blocknumber(handle).await.unwrap(), 4);
+        let root_hash = keccak_root(&block_hashes).await;
+        assert_eq!(committed_root(handle).await, root_hash);
+        assert_eq!(
+            db::get_latest_mmr_root(&pool, handle.mmr_id())
+                .await
+                .unwrap()
+                .unwrap()
+                .root_hash,
+            root_hash
+        );
+        let shards = set.shards().await;
+        assert_eq!(
+            committed_root(&shards[0].handles[0]).await,
+            keccak_root(&block_hashes[..4]).await
+        );
+    }
+}
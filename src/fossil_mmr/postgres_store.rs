@@ -0,0 +1,265 @@
+use accumulators::store::{Store, StoreError};
+use async_trait::async_trait;
+use sqlx::{PgConnection, Pool, Postgres, QueryBuilder};
+use std::collections::HashMap;
+
+const TABLE: &str = "mmr_store";
+
+// Postgres accepts at most 65535 bind parameters per statement, two per entry
+const SET_MANY_CHUNK_SIZE: usize = 10_000;
+
+/**
+ * Keeps the MMR nodes and counters in the mmr_store table of the headers database, so every replica sees the same
+ * MMR and it is backed up with the headers
+ */
+#[derive(Debug)]
+pub struct PostgresStore {
+    pool: Pool<Postgres>,
+    id: String,
+    table: String,
+}
+
+impl PostgresStore {
+    pub async fn new(pool: Pool<Postgres>, id: &str) -> Result<Self, StoreError> {
+        sqlx::query(include_str!("../db/sql/mmr_store_table.sql"))
+            .execute(&pool)
+            .await?;
+
+        Ok(Self {
+            pool,
+            id: id.to_string(),
+            table: TABLE.to_string(),
+        })
+    }
+
+    /**
+     * Store in a new empty table shaped like mmr_store, dropping any previous one, to build MMRs aside before
+     * moving them in with replace_mmrs
+     */
+    pub(super) async fn new_scratch(
+        pool: Pool<Postgres>,
+        id: &str,
+        table: &str,
+    ) -> Result<Self, StoreError> {
+        let store = Self::new(pool, id).await?;
+        sqlx::query(&format!("DROP TABLE IF EXISTS {table}"))
+            .execute(&store.pool)
+            .await?;
+        sqlx::query(&format!(
+            "CREATE TABLE {table} (LIKE {TABLE} INCLUDING ALL)"
+        ))
+        .execute(&store.pool)
+        .await?;
+
+        Ok(Self {
+            table: table.to_string(),
+            ..store
+        })
+    }
+
+    /**
+     * Replaces the nodes of the MMRs mmr_ids in mmr_store with the ones built in the scratch store, then drops its
+     * table. Atomic when conn is a transaction.
+     */
+    pub(super) async fn replace_mmrs(
+        conn: &mut PgConnection,
+        scratch: &PostgresStore,
+        mmr_ids: &[String],
+    ) -> Result<(), StoreError> {
+        let prefixes: Vec<String> = mmr_ids.iter().map(|mmr_id| format!("{mmr_id}:")).collect();
+
+        sqlx::query(&format!(
+            "DELETE FROM {TABLE} WHERE EXISTS (SELECT 1 FROM UNNEST($1::TEXT[]) AS prefix WHERE starts_with(key, prefix))"
+        ))
+        .bind(&prefixes)
+        .execute(&mut *conn)
+        .await?;
+        sqlx::query(&format!(
+            "INSERT INTO {TABLE} (key, value) SELECT key, value FROM {}",
+            scratch.table
+        ))
+        .execute(&mut *conn)
+        .await?;
+        sqlx::query(&format!("DROP TABLE {}", scratch.table))
+            .execute(&mut *conn)
+            .await?;
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Store for PostgresStore {
+    fn id(&self) -> String {
+        self.id.clone()
+    }
+
+    async fn get(&self, key: &str) -> Result<Option<String>, StoreError> {
+        let value: Option<(String,)> =
+            sqlx::query_as(&format!("SELECT value FROM {} WHERE key = $1", self.table))
+                .bind(key)
+                .fetch_optional(&self.pool)
+                .await?;
+        Ok(value.map(|(value,)| value))
+    }
+
+    async fn get_many(&self, keys: Vec<&str>) -> Result<HashMap<String, String>, StoreError> {
+        let rows: Vec<(String, String)> = sqlx::query_as(&format!(
+            "SELECT key, value FROM {} WHERE key = ANY($1)",
+            self.table
+        ))
+        .bind(keys)
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(rows.into_iter().collect())
+    }
+
+    async fn set(&self, key: &str, value: &str) -> Result<(), StoreError> {
+        sqlx::query(&format!(
+            "INSERT INTO {} (key, value) VALUES ($1, $2)
+                ON CONFLICT (key) DO UPDATE SET value = EXCLUDED.value",
+            self.table
+        ))
+        .bind(key)
+        .bind(value)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    async fn set_many(&self, entries: HashMap<String, String>) -> Result<(), StoreError> {
+        let entries: Vec<(String, String)> = entries.into_iter().collect();
+        let mut tx = self.pool.begin().await?;
+
+        for chunk in entries.chunks(SET_MANY_CHUNK_SIZE) {
+            let mut query_builder: QueryBuilder<Postgres> =
+                QueryBuilder::new(format!("INSERT INTO {} (key, value) ", self.table));
+            query_builder.push_values(chunk, |mut b, (key, value)| {
+                b.push_bind(key).push_bind(value);
+            });
+            query_builder.push(" ON CONFLICT (key) DO UPDATE SET value = EXCLUDED.value");
+            query_builder.build().execute(&mut *tx).await?;
+        }
+
+        tx.commit().await?;
+        Ok(())
+    }
+
+    async fn delete(&self, key: &str) -> Result<(), StoreError> {
+        sqlx::query(&format!("DELETE FROM {} WHERE key = $1", self.table))
+            .bind(key)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    async fn delete_many(&self, keys: Vec<&str>) -> Result<(), StoreError> {
+        sqlx::query(&format!("DELETE FROM {} WHERE key = ANY($1)", self.table))
+            .bind(keys)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db;
+    use crate::fossil_mmr::{get_root_hash, new_hasher, tests::block_hash, MmrHasher};
+    use accumulators::mmr::{map_leaf_index_to_element_index, MMR};
+    use accumulators::store::memory::InMemoryStore;
+    use std::sync::Arc;
+
+    /**
+     * Keccak MMR with mmr_id over the store, holding the hashes of blocks 0..leaf_count
+     */
+    async fn test_mmr(store: Arc<dyn Store>, mmr_id: &str, leaf_count: i64) -> MMR {
+        let mut mmr = MMR::new(
+            store,
+            new_hasher(MmrHasher::Keccak),
+            Some(mmr_id.to_string()),
+        );
+        for blocknumber in 0..leaf_count {
+            mmr.append(block_hash(blocknumber)).await.unwrap();
+        }
+        mmr
+    }
+
+    async fn root(mmr: &MMR) -> String {
+        get_root_hash(mmr, mmr.elements_count.get().await.unwrap())
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    #[ignore = "needs Postgres at DB_CONNECTION_STRING"]
+    async fn mmr_over_postgres_matches_in_memory() {
+        let pool = db::tests::test_pool("test_postgres_store").await;
+        let store = Arc::new(PostgresStore::new(pool, "blockheaders_mmr").await.unwrap());
+        let mmr = test_mmr(store.clone(), "blockheaders_mmr", 21).await;
+        let expected = test_mmr(Arc::new(InMemoryStore::new(None)), "blockheaders_mmr", 21).await;
+
+        let elements_count = mmr.elements_count.get().await.unwrap();
+        assert_eq!(elements_count, expected.elements_count.get().await.unwrap());
+        assert_eq!(root(&mmr).await, root(&expected).await);
+        for leaf_index in 0..21 {
+            let element_index = map_leaf_index_to_element_index(leaf_index);
+            let proof = mmr.get_proof(element_index, None).await.unwrap();
+            assert_eq!(
+                proof,
+                expected.get_proof(element_index, None).await.unwrap()
+            );
+            assert!(mmr
+                .verify_proof(proof.clone(), proof.element_hash, None)
+                .await
+                .unwrap());
+        }
+
+        // Two chunks, overwriting an existing entry
+        let entries: HashMap<String, String> = (0..=SET_MANY_CHUNK_SIZE)
+            .map(|i| (format!("test:{i}"), i.to_string()))
+            .chain([("test:0".to_string(), "overwritten".to_string())])
+            .collect();
+        store.set_many(entries.clone()).await.unwrap();
+        let keys: Vec<&str> = entries.keys().map(String::as_str).collect();
+        assert_eq!(store.get_many(keys.clone()).await.unwrap(), entries);
+        assert_eq!(
+            store.get("test:0").await.unwrap().as_deref(),
+            Some("overwritten")
+        );
+
+        store.delete_many(keys).await.unwrap();
+        assert_eq!(store.get("test:0").await.unwrap(), None);
+        assert_eq!(root(&mmr).await, root(&expected).await);
+    }
+
+    #[tokio::test]
+    #[ignore = "needs Postgres at DB_CONNECTION_STRING"]
+    async fn replacing_an_mmr_keeps_the_other_ones() {
+        let pool = db::tests::test_pool("test_replace_mmrs").await;
+        let store = Arc::new(PostgresStore::new(pool.clone(), "live").await.unwrap());
+        let mmr = test_mmr(store.clone(), "blockheaders_mmr", 10).await;
+        let other = test_mmr(store.clone(), "blockheaders_mmr_2", 10).await;
+        let other_root = root(&other).await;
+
+        let scratch = Arc::new(
+            PostgresStore::new_scratch(pool.clone(), "scratch", "mmr_store_scratch")
+                .await
+                .unwrap(),
+        );
+        let rebuilt = test_mmr(scratch.clone(), "blockheaders_mmr", 6).await;
+        let rebuilt_root = root(&rebuilt).await;
+        assert_ne!(root(&mmr).await, rebuilt_root);
+
+        let mut tx = pool.begin().await.unwrap();
+        PostgresStore::replace_mmrs(&mut tx, &scratch, &["blockheaders_mmr".to_string()])
+            .await
+            .unwrap();
+        tx.commit().await.unwrap();
+
+        assert_eq!(root(&mmr).await, rebuilt_root);
+        assert_eq!(mmr.elements_count.get().await.unwrap(), 10);
+        assert_eq!(root(&other).await, other_root);
+        assert_eq!(other.elements_count.get().await.unwrap(), 18);
+    }
+}
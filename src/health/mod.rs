@@ -0,0 +1,125 @@
+use anyhow::Result;
+use chrono::Utc;
+
+use crate::{
+    db, endpoints, fossil_mmr,
+    indexer::Indexer,
+    types::{HealthCheck, HealthReport, UpdaterState},
+};
+
+/**
+ * Fails only when the updater task has failed, as the process then keeps serving without indexing
+ */
+pub fn check_liveness(indexer: &Indexer) -> HealthReport {
+    report(vec![check_updater(indexer)])
+}
+
+/**
+ * Checks the db and MMR store are reachable and that the indexed data is not stale
+ */
+pub async fn check_readiness(indexer: &Indexer) -> HealthReport {
+    let (stored_head, finalized_head, elements_count) = tokio::join!(
+        db::get_last_stored_blocknumber(indexer.pool()),
+        get_finalized_head(indexer),
+        fossil_mmr::get_elements_count(indexer.mmr().primary()),
+    );
+
+    let mut checks = vec![check_updater(indexer)];
+    checks.push(match &stored_head {
+        Ok(stored_head) => healthy("db", format!("stored head {stored_head}")),
+        Err(e) => unhealthy("db", format!("{e:#}")),
+    });
+    checks.push(match elements_count {
+        Ok(elements_count) => healthy("mmr_store", format!("{elements_count} elements")),
+        Err(e) => unhealthy("mmr_store", format!("{e:#}")),
+    });
+    checks.push(check_head_lag(indexer, &stored_head, finalized_head));
+    checks.push(check_mmr_update_age(indexer).await);
+
+    report(checks)
+}
+
+fn check_updater(indexer: &Indexer) -> HealthCheck {
+    let state = indexer.updater_state();
+    let detail = format!("{state:?}").to_lowercase();
+    if state == UpdaterState::Failed {
+        unhealthy("updater", detail)
+    } else {
+        healthy("updater", detail)
+    }
+}
+
+async fn get_finalized_head(indexer: &Indexer) -> Result<Option<i64>> {
+    if indexer.config().health.max_head_lag == 0 {
+        return Ok(None);
+    }
+    let finalized_head = endpoints::get_latest_finalized_blocknumber(
+        indexer.rpc(),
+        Some(indexer.config().health.rpc_timeout),
+    )
+    .await?;
+    Ok(Some(finalized_head))
+}
+
+fn check_head_lag(
+    indexer: &Indexer,
+    stored_head: &Result<i64>,
+    finalized_head: Result<Option<i64>>,
+) -> HealthCheck {
+    let max_head_lag = indexer.config().health.max_head_lag;
+    let (stored_head, finalized_head) = match (stored_head, finalized_head) {
+        (_, Ok(None)) => return healthy("head_lag", "disabled".to_string()),
+        (Ok(stored_head), Ok(Some(finalized_head))) => (*stored_head, finalized_head),
+        (Err(_), _) => return unhealthy("head_lag", "stored head unknown".to_string()),
+        (_, Err(e)) => return unhealthy("head_lag", format!("finalized head unknown: {e:#}")),
+    };
+
+    indexer.metrics().set_heads(stored_head, finalized_head);
+    let lag = (finalized_head - stored_head).max(0);
+    let detail =
+        format!("{lag} blocks behind finalized head {finalized_head} (max {max_head_lag})");
+    if lag > max_head_lag {
+        unhealthy("head_lag", detail)
+    } else {
+        healthy("head_lag", detail)
+    }
+}
+
+async fn check_mmr_update_age(indexer: &Indexer) -> HealthCheck {
+    let max_age = indexer.config().health.max_mmr_update_age;
+    if max_age == 0 {
+        return healthy("mmr_update_age", "disabled".to_string());
+    }
+
+    let last_update = fossil_mmr::get_last_completed_update(indexer.mmr().primary()).await;
+    let age = (Utc::now() - last_update).num_seconds().max(0) as u64;
+    let detail = format!("last completed {age}s ago (max {max_age}s)");
+    if age > max_age {
+        unhealthy("mmr_update_age", detail)
+    } else {
+        healthy("mmr_update_age", detail)
+    }
+}
+
+fn healthy(name: &'static str, detail: String) -> HealthCheck {
+    HealthCheck {
+        name,
+        healthy: true,
+        detail,
+    }
+}
+
+fn unhealthy(name: &'static str, detail: String) -> HealthCheck {
+    HealthCheck {
+        name,
+        healthy: false,
+        detail,
+    }
+}
+
+fn report(checks: Vec<HealthCheck>) -> HealthReport {
+    HealthReport {
+        healthy: checks.iter().all(|check| check.healthy),
+        checks,
+    }
+}
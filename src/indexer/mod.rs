@@ -0,0 +1,693 @@
+use accumulators::store::Store;
+use anyhow::{bail, Result};
+use reqwest::Client;
+use sqlx::{Pool, Postgres};
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::{atomic::AtomicBool, Arc, RwLock};
+
+use crate::{
+    commands,
+    config::{Config, MmrHasher},
+    db,
+    endpoints::RpcClient,
+    fossil_mmr::{self, MmrHandle, MmrSet},
+    health,
+    metrics::Metrics,
+    types::{
+        header_rlp, HealthReport, MmrAuditReport, MmrBatchProof, MmrConsistencyProof, MmrProof,
+        MmrRoot, MmrShardIndex, MmrShardInfo, Page, StoredBlockHeader, StoredTransaction,
+        TransactionDirection, Update, UpdaterState,
+    },
+};
+
+/**
+ * Owns everything an indexer needs (config, db pool, RPC client and MMR), so several can run in one process
+ */
+#[derive(Clone)]
+pub struct Indexer {
+    config: Arc<Config>,
+    pool: Pool<Postgres>,
+    rpc: RpcClient,
+    mmr: Arc<MmrSet>,
+    metrics: Arc<Metrics>,
+    updater_state: Arc<RwLock<UpdaterState>>,
+}
+
+#[derive(Default)]
+pub struct IndexerBuilder {
+    config: Option<Config>,
+    pool: Option<Pool<Postgres>>,
+    http_client: Option<Client>,
+    mmr_store: Option<Arc<dyn Store>>,
+}
+
+impl IndexerBuilder {
+    pub fn config(mut self, config: Config) -> Self {
+        self.config = Some(config);
+        self
+    }
+
+    /**
+     * Use an existing pool instead of connecting with db.connection_string
+     */
+    pub fn pool(mut self, pool: Pool<Postgres>) -> Self {
+        self.pool = Some(pool);
+        self
+    }
+
+    pub fn http_client(mut self, client: Client) -> Self {
+        self.http_client = Some(client);
+        self
+    }
+
+    /**
+     * Use an existing store for the MMR instead of the one selected by mmr.store
+     */
+    pub fn mmr_store(mut self, store: Arc<dyn Store>) -> Self {
+        self.mmr_store = Some(store);
+        self
+    }
+
+    pub async fn build(self) -> Result<Indexer> {
+        let config = self.config.unwrap_or_default();
+        config.validate()?;
+        if config.rpc.connection_string.is_empty() {
+            bail!("rpc.connection_string must be set (or NODE_CONNECTION_STRING)");
+        }
+
+        let pool = match self.pool {
+            Some(pool) => pool,
+            None => {
+                if config.db.connection_string.is_empty() {
+                    bail!("db.connection_string must be set (or DB_CONNECTION_STRING)");
+                }
+                db::connect(&config.db).await?
+            }
+        };
+
+        let metrics = Arc::new(Metrics::new()?);
+        let rpc = RpcClient::new(
+            self.http_client.unwrap_or_default(),
+            &config.rpc,
+            metrics.clone(),
+        );
+
+        let mmr = match self.mmr_store {
+            Some(store) => MmrSet::with_store(&config.mmr, store, metrics.clone()).await?,
+            None => MmrSet::open(&config.mmr, &pool, metrics.clone()).await?,
+        };
+
+        Ok(Indexer {
+            config: Arc::new(config),
+            pool,
+            rpc,
+            mmr: Arc::new(mmr),
+            metrics,
+            updater_state: Arc::new(RwLock::new(UpdaterState::Idle)),
+        })
+    }
+}
+
+impl Indexer {
+    pub fn builder() -> IndexerBuilder {
+        IndexerBuilder::default()
+    }
+
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    pub fn pool(&self) -> &Pool<Postgres> {
+        &self.pool
+    }
+
+    pub fn rpc(&self) -> &RpcClient {
+        &self.rpc
+    }
+
+    pub fn mmr(&self) -> &MmrSet {
+        &self.mmr
+    }
+
+    pub fn metrics(&self) -> &Metrics {
+        &self.metrics
+    }
+
+    pub fn updater_state(&self) -> UpdaterState {
+        *self
+            .updater_state
+            .read()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    fn set_updater_state(&self, state: UpdaterState) {
+        *self
+            .updater_state
+            .write()
+            .unwrap_or_else(|poisoned| poisoned.into_inner()) = state;
+    }
+
+    /**
+     * Runs one of the update commands, recording whether it is still running for the health checks
+     */
+    async fn track_updater(&self, update: impl Future<Output = Result<()>>) -> Result<()> {
+        self.set_updater_state(UpdaterState::Running);
+        let res = update.await;
+        self.set_updater_state(match res {
+            Ok(()) => UpdaterState::Completed,
+            Err(_) => UpdaterState::Failed,
+        });
+        res
+    }
+
+    pub async fn create_tables(&self) -> Result<()> {
+        db::create_tables(&self.pool).await
+    }
+
+    /**
+     * Writes blocks from start (default: after the last stored block) to end (default: follows the chain head)
+     */
+    pub async fn sync(
+        &self,
+        start: Option<i64>,
+        end: Option<i64>,
+        loopsize: u32,
+        should_terminate: Arc<AtomicBool>,
+    ) -> Result<()> {
+        self.track_updater(commands::update_from(
+            self,
+            start,
+            end,
+            loopsize,
+            should_terminate,
+        ))
+        .await
+    }
+
+    /**
+     * Writes blocks by claiming range leases shared with other workers
+     */
+    pub async fn sync_leased(
+        &self,
+        start: Option<i64>,
+        end: Option<i64>,
+        loopsize: u32,
+        worker_id: String,
+        should_terminate: Arc<AtomicBool>,
+    ) -> Result<()> {
+        self.track_updater(commands::update_from_leases(
+            self,
+            start,
+            end,
+            loopsize,
+            worker_id,
+            should_terminate,
+        ))
+        .await
+    }
+
+    /**
+     * Writes blocks missing between start (default: 0) and end (default: last stored block)
+     */
+    pub async fn fix(
+        &self,
+        start: Option<i64>,
+        end: Option<i64>,
+        should_terminate: Arc<AtomicBool>,
+    ) -> Result<()> {
+        self.track_updater(commands::fill_gaps(self, start, end, should_terminate))
+            .await
+    }
+
+    pub async fn update_mmr(&self, should_terminate: &AtomicBool) -> Result<()> {
+        fossil_mmr::update_mmr(&self.mmr, &self.pool, should_terminate).await
+    }
+
+    /**
+     * Rebuilds the MMRs from the stored headers into a fresh store replacing the current one. The MMRs of this
+     * indexer are not reopened, so it is meant to run on its own (mmr-rebuild mode).
+     */
+    pub async fn rebuild_mmr(&self, should_terminate: &AtomicBool) -> Result<i64> {
+        db::create_tables(&self.pool).await?;
+        fossil_mmr::rebuild_mmr(
+            &self.config.mmr,
+            &self.pool,
+            self.metrics.clone(),
+            should_terminate,
+        )
+        .await
+    }
+
+    /**
+     * Rolls the MMRs back to right after block to_block, so the next update appends the following stored blocks
+     * again
+     */
+    pub async fn truncate_mmr(&self, to_block: i64) -> Result<()> {
+        fossil_mmr::truncate_mmr(&self.mmr, &self.pool, to_block).await
+    }
+
+    /**
+     * Checks every leaf of each MMR, shards included, against the stored block hashes
+     */
+    pub async fn audit_mmr(&self) -> Result<Vec<MmrAuditReport>> {
+        let shards = self.mmr.shards().await;
+        let mut reports = Vec::new();
+        for handle in self
+            .mmr
+            .handles()
+            .iter()
+            .chain(shards.iter().flat_map(|shard| shard.handles().iter()))
+        {
+            reports.push(fossil_mmr::audit_mmr(handle, &self.pool).await?);
+        }
+        Ok(reports)
+    }
+
+    /**
+     * MMR built with hasher, failing if it is not one of mmr.hashers
+     */
+    fn mmr_handle(&self, hasher: MmrHasher) -> Result<&MmrHandle> {
+        match self.mmr.get(hasher) {
+            Some(handle) => Ok(handle),
+            None => bail!("No {} MMR is configured", hasher.as_str()),
+        }
+    }
+
+    /**
+     * Size of the MMR proofs are served at
+     */
+    pub fn committed_elements_count(&self, hasher: MmrHasher) -> Result<usize> {
+        Ok(self.mmr_handle(hasher)?.committed_elements_count())
+    }
+
+    /**
+     * Proves a block against the MMR at elements_count elements (Default: current size)
+     */
+    pub async fn get_proof(
+        &self,
+        hasher: MmrHasher,
+        blocknumber: i64,
+        elements_count: Option<usize>,
+    ) -> Result<Option<MmrProof>> {
+        let handle = self.mmr_handle(hasher)?;
+        fossil_mmr::get_proof(handle, &self.pool, blocknumber, elements_count).await
+    }
+
+    pub async fn get_proofs(
+        &self,
+        hasher: MmrHasher,
+        blocknumbers: &[i64],
+        elements_count: Option<usize>,
+    ) -> Result<Option<MmrBatchProof>> {
+        let handle = self.mmr_handle(hasher)?;
+        fossil_mmr::get_proofs(handle, &self.pool, blocknumbers, elements_count).await
+    }
+
+    pub async fn get_consistency_proof(
+        &self,
+        hasher: MmrHasher,
+        from_elements_count: usize,
+        to_elements_count: usize,
+    ) -> Result<Option<MmrConsistencyProof>> {
+        let handle = self.mmr_handle(hasher)?;
+        fossil_mmr::get_consistency_proof(handle, from_elements_count, to_elements_count).await
+    }
+
+    pub async fn get_root_hash(
+        &self,
+        hasher: MmrHasher,
+        elements_count: usize,
+    ) -> Result<Option<String>> {
+        fossil_mmr::get_root_hash_at(self.mmr_handle(hasher)?, elements_count).await
+    }
+
+    /**
+     * Root of the MMR of shard at elements_count elements, None if the shard or that size is not reached yet
+     */
+    pub async fn get_shard_root_hash(
+        &self,
+        hasher: MmrHasher,
+        shard: u64,
+        elements_count: usize,
+    ) -> Result<Option<String>> {
+        let Some(shard) = self.mmr.shard(shard).await else {
+            return Ok(None);
+        };
+        match shard.get(hasher) {
+            Some(handle) => fossil_mmr::get_root_hash_at(handle, elements_count).await,
+            None => bail!("No {} MMR is configured", hasher.as_str()),
+        }
+    }
+
+    pub async fn get_mmr_stats(&self, hasher: MmrHasher) -> Result<Option<Update>> {
+        fossil_mmr::get_mmr_stats(self.mmr_handle(hasher)?, &self.pool).await
+    }
+
+    pub async fn get_mmr_root(
+        &self,
+        hasher: MmrHasher,
+        blocknumber: i64,
+    ) -> Result<Option<MmrRoot>> {
+        fossil_mmr::get_mmr_root(self.mmr_handle(hasher)?, &self.pool, blocknumber).await
+    }
+
+    /**
+     * Shards of the hasher's MMR, None if the MMRs are not sharded
+     */
+    pub async fn get_mmr_shards(&self, hasher: MmrHasher) -> Result<Option<MmrShardIndex>> {
+        self.mmr_handle(hasher)?;
+        if self.mmr.shard_size() == 0 {
+            return Ok(None);
+        }
+        let mut shards = Vec::new();
+        for shard in self.mmr.shards().await {
+            shards.extend(fossil_mmr::get_shard_info(&shard, hasher).await?);
+        }
+        Ok(Some(MmrShardIndex {
+            shard_size: self.mmr.shard_size(),
+            shards,
+        }))
+    }
+
+    pub async fn get_mmr_shard(
+        &self,
+        hasher: MmrHasher,
+        shard: u64,
+    ) -> Result<Option<MmrShardInfo>> {
+        self.mmr_handle(hasher)?;
+        match self.mmr.shard(shard).await {
+            Some(shard) => fossil_mmr::get_shard_info(&shard, hasher).await,
+            None => Ok(None),
+        }
+    }
+
+    /**
+     * Shard holding blocknumber, None if the MMRs are not sharded or the shard is not opened yet
+     */
+    pub async fn get_mmr_shard_of(
+        &self,
+        hasher: MmrHasher,
+        blocknumber: i64,
+    ) -> Result<Option<MmrShardInfo>> {
+        self.mmr_handle(hasher)?;
+        match self.mmr.shard_of(blocknumber).await {
+            Some(shard) => fossil_mmr::get_shard_info(&shard, hasher).await,
+            None => Ok(None),
+        }
+    }
+
+    /**
+     * Proves a block against the MMR of shard at elements_count elements (Default: current size)
+     */
+    pub async fn get_shard_proof(
+        &self,
+        hasher: MmrHasher,
+        shard: u64,
+        blocknumber: i64,
+        elements_count: Option<usize>,
+    ) -> Result<Option<MmrProof>> {
+        let Some(shard) = self.mmr.shard(shard).await else {
+            return Ok(None);
+        };
+        match shard.get(hasher) {
+            Some(handle) => {
+                fossil_mmr::get_proof(handle, &self.pool, blocknumber, elements_count).await
+            }
+            None => bail!("No {} MMR is configured", hasher.as_str()),
+        }
+    }
+
+    /**
+     * Root recorded for the MMR of shard right after block blocknumber was appended
+     */
+    pub async fn get_shard_root(
+        &self,
+        hasher: MmrHasher,
+        shard: u64,
+        blocknumber: i64,
+    ) -> Result<Option<MmrRoot>> {
+        let Some(shard) = self.mmr.shard(shard).await else {
+            return Ok(None);
+        };
+        match shard.get(hasher) {
+            Some(handle) => fossil_mmr::get_mmr_root(handle, &self.pool, blocknumber).await,
+            None => bail!("No {} MMR is configured", hasher.as_str()),
+        }
+    }
+
+    /**
+     * RLP-encoded headers of the stored blocks among blocknumbers, as 0x-prefixed hex. Blocks stored before the
+     * header fields were recorded are left out.
+     */
+    pub async fn get_header_rlps(&self, blocknumbers: &[i64]) -> Result<HashMap<i64, String>> {
+        let mut header_rlps = HashMap::new();
+        for header in db::get_blockheaders_by_numbers(&self.pool, blocknumbers).await? {
+            if let Some(header_rlp) = header_rlp::encode_header(&header)? {
+                header_rlps.insert(header.number, format!("0x{}", hex::encode(header_rlp)));
+            }
+        }
+        Ok(header_rlps)
+    }
+
+    pub async fn get_block(&self, number: i64) -> Result<Option<StoredBlockHeader>> {
+        db::get_blockheader_by_number(&self.pool, number).await
+    }
+
+    pub async fn get_block_by_hash(&self, block_hash: &str) -> Result<Option<StoredBlockHeader>> {
+        db::get_blockheader_by_hash(&self.pool, block_hash).await
+    }
+
+    /**
+     * Lists stored blocks from `from` to `to` (inclusive), starting after the block number given as cursor
+     */
+    pub async fn get_blocks(
+        &self,
+        from: i64,
+        to: i64,
+        cursor: Option<i64>,
+        limit: i64,
+    ) -> Result<Page<StoredBlockHeader>> {
+        let items = db::get_blockheaders_in_range(&self.pool, from, to, cursor, limit + 1).await?;
+        Ok(paginate(items, limit, |block| block.number.to_string()))
+    }
+
+    pub async fn get_transaction(
+        &self,
+        transaction_hash: &str,
+    ) -> Result<Option<StoredTransaction>> {
+        db::get_transaction_by_hash(&self.pool, transaction_hash).await
+    }
+
+    /**
+     * Lists the transactions of a block, starting after the transaction index given as cursor
+     */
+    pub async fn get_block_transactions(
+        &self,
+        block_number: i64,
+        cursor: Option<i32>,
+        limit: i64,
+    ) -> Result<Page<StoredTransaction>> {
+        let items = db::get_block_transactions(&self.pool, block_number, cursor, limit + 1).await?;
+        Ok(paginate(items, limit, |transaction| {
+            transaction.transaction_index.to_string()
+        }))
+    }
+
+    /**
+     * Lists the transactions of an address between two blocks (inclusive), starting after the cursor,
+     * which is the "<block_number>:<transaction_index>" of the last transaction of the previous page
+     */
+    pub async fn get_address_transactions(
+        &self,
+        address: &str,
+        direction: TransactionDirection,
+        from: i64,
+        to: i64,
+        cursor: Option<(i64, i32)>,
+        limit: i64,
+    ) -> Result<Page<StoredTransaction>> {
+        let items = db::get_address_transactions(
+            &self.pool,
+            address,
+            direction,
+            from,
+            to,
+            cursor,
+            limit + 1,
+        )
+        .await?;
+        Ok(paginate(items, limit, |transaction| {
+            format!(
+                "{}:{}",
+                transaction.block_number.unwrap_or_default(),
+                transaction.transaction_index
+            )
+        }))
+    }
+
+    pub fn liveness(&self) -> HealthReport {
+        health::check_liveness(self)
+    }
+
+    pub async fn readiness(&self) -> HealthReport {
+        health::check_readiness(self).await
+    }
+}
+
+/**
+ * Builds a page from up to limit + 1 rows, the extra row telling whether there is a next page
+ */
+fn paginate<T>(mut items: Vec<T>, limit: i64, cursor_of: impl Fn(&T) -> String) -> Page<T> {
+    let next_cursor = if items.len() as i64 > limit {
+        items.truncate(limit as usize);
+        items.last().map(cursor_of)
+    } else {
+        None
+    };
+    Page { items, next_cursor }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use accumulators::{hasher::keccak::KeccakHasher, mmr::MMR, store::memory::InMemoryStore};
+    use sqlx::postgres::PgPoolOptions;
+
+    async fn build(config: Config, store: Arc<dyn Store>) -> Indexer {
+        // Nothing below queries the database
+        let pool = PgPoolOptions::new()
+            .connect_lazy(&config.db.connection_string)
+            .unwrap();
+        Indexer::builder()
+            .config(config)
+            .pool(pool)
+            .mmr_store(store)
+            .build()
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn indexers_in_one_process_are_independent() {
+        let mut config_a = Config::default();
+        config_a.db.connection_string = "postgres://localhost/chain_a".to_string();
+        config_a.rpc.connection_string = "http://127.0.0.1:8545".to_string();
+        config_a.router.max_page_size = 10;
+        config_a.mmr.mmr_id = "chain_a_mmr".to_string();
+
+        let mut config_b = Config::default();
+        config_b.db.connection_string = "postgres://localhost/chain_b".to_string();
+        config_b.rpc.connection_string = "http://127.0.0.1:9545".to_string();
+        config_b.router.max_page_size = 20;
+        config_b.mmr.hashers = vec![MmrHasher::Keccak, MmrHasher::Poseidon];
+
+        // Chain a already has 3 blocks in its MMR
+        let store_a: Arc<dyn Store> = Arc::new(InMemoryStore::new(None));
+        let mut mmr = MMR::new(
+            store_a.clone(),
+            Arc::new(KeccakHasher::new()),
+            Some("chain_a_mmr".to_string()),
+        );
+        for leaf in ["0x01", "0x02", "0x03"] {
+            mmr.append(format!("{leaf:0<66}")).await.unwrap();
+        }
+
+        let indexer_a = build(config_a, store_a).await;
+        let indexer_b = build(config_b, Arc::new(InMemoryStore::new(None))).await;
+
+        assert_eq!(indexer_a.config().router.max_page_size, 10);
+        assert_eq!(indexer_b.config().router.max_page_size, 20);
+        assert_eq!(
+            indexer_a.config().rpc.connection_string,
+            "http://127.0.0.1:8545"
+        );
+        assert_eq!(
+            indexer_b.config().rpc.connection_string,
+            "http://127.0.0.1:9545"
+        );
+        assert!(!std::ptr::eq(indexer_a.metrics(), indexer_b.metrics()));
+
+        assert_eq!(indexer_a.mmr().primary().mmr_id(), "chain_a_mmr");
+        assert_eq!(indexer_b.mmr().primary().mmr_id(), "blockheaders_mmr");
+        assert_eq!(
+            indexer_a
+                .committed_elements_count(MmrHasher::Keccak)
+                .unwrap(),
+            4
+        );
+        assert_eq!(
+            indexer_b
+                .committed_elements_count(MmrHasher::Keccak)
+                .unwrap(),
+            0
+        );
+        assert!(indexer_a
+            .get_root_hash(MmrHasher::Keccak, 4)
+            .await
+            .unwrap()
+            .is_some());
+        assert!(indexer_b
+            .get_root_hash(MmrHasher::Keccak, 4)
+            .await
+            .unwrap()
+            .is_none());
+
+        // Only chain b builds a Poseidon MMR
+        assert!(indexer_a
+            .committed_elements_count(MmrHasher::Poseidon)
+            .is_err());
+        assert_eq!(
+            indexer_b
+                .committed_elements_count(MmrHasher::Poseidon)
+                .unwrap(),
+            0
+        );
+    }
+
+    #[tokio::test]
+    #[ignore = "needs Postgres at DB_CONNECTION_STRING"]
+    async fn audit_covers_the_shards() {
+        let pool = db::tests::test_pool("test_audit_shards").await;
+        let mut config = Config::default();
+        // Nothing below calls the node
+        config.rpc.connection_string = "http://127.0.0.1:8545".to_string();
+        config.mmr.shard_size = 4;
+        let indexer = Indexer::builder()
+            .config(config)
+            .pool(pool.clone())
+            .mmr_store(Arc::new(InMemoryStore::new(None)))
+            .build()
+            .await
+            .unwrap();
+        for header in db::tests::test_chain(6, 0) {
+            db::write_blockheader(&pool, header).await.unwrap();
+        }
+        indexer.update_mmr(&AtomicBool::new(false)).await.unwrap();
+
+        sqlx::query("UPDATE blockheaders SET block_hash = $1 WHERE number = 5")
+            .bind(format!("0x{}", "ab".repeat(32)))
+            .execute(&pool)
+            .await
+            .unwrap();
+        let reports = indexer.audit_mmr().await.unwrap();
+        let summary: Vec<_> = reports
+            .iter()
+            .map(|report| {
+                (
+                    report.shard,
+                    report.leaves_count,
+                    report.first_divergent_leaf,
+                )
+            })
+            .collect();
+        assert_eq!(
+            summary,
+            [
+                (None, 6, Some(5)),
+                (Some(0), 4, None),
+                (Some(1), 2, Some(5))
+            ]
+        );
+    }
+}
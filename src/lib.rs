@@ -1,6 +1,13 @@
 pub mod commands;
+pub mod config;
 pub mod db;
 pub mod endpoints;
 pub mod fossil_mmr;
+pub mod health;
+pub mod indexer;
+pub mod metrics;
 pub mod router;
+pub mod telemetry;
 pub mod types;
+
+pub use indexer::{Indexer, IndexerBuilder};
@@ -1,17 +1,13 @@
-mod commands;
-mod db;
-mod endpoints;
-mod fossil_mmr;
-mod router;
-mod types;
-
-use anyhow::{Context, Result};
+use anyhow::{bail, Context, Result};
 use clap::{Parser, ValueEnum};
 use core::cmp::min;
 use futures::future::join;
-use log::{info, warn};
+use std::path::{Path, PathBuf};
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
+use tracing::{info, warn};
+
+use fossil_headers_db::{config::Config, fossil_mmr, router, telemetry, types::MmrProof, Indexer};
 
 #[derive(Parser)]
 #[command(version, about, long_about = None)]
@@ -28,55 +24,144 @@ struct Cli {
     #[arg(short, long)]
     end: Option<i64>,
 
-    /// Number of threads (Max = 1000)
-    #[arg(short, long, default_value_t = db::DB_MAX_CONNECTIONS)]
-    loopsize: u32,
+    /// Number of threads (Max = db.max_connections)
+    #[arg(short, long)]
+    loopsize: Option<u32>,
+
+    /// Claim block ranges from the shared lease table, so several update workers can run at once
+    #[arg(long)]
+    leased: bool,
+
+    /// Identifier of this worker when running with leases (Default: <hostname>-<pid>)
+    #[arg(long)]
+    worker_id: Option<String>,
+
+    /// Path to the TOML config file (Default: config.toml, if present)
+    #[arg(long)]
+    config: Option<PathBuf>,
+
+    /// Override a config value, e.g. --set mmr.append_chunksize=100 (repeatable)
+    #[arg(long = "set", value_name = "KEY=VALUE")]
+    overrides: Vec<String>,
+
+    /// Proof JSON file to check (verify-proof mode)
+    #[arg(long)]
+    proof: Option<PathBuf>,
+
+    /// Root hash to check the proof against (verify-proof mode)
+    #[arg(long)]
+    root: Option<String>,
+
+    /// Last block kept in the MMR (mmr-truncate mode)
+    #[arg(long, allow_negative_numbers = true)]
+    to_block: Option<i64>,
+
+    /// Also truncate the MMR right before the first mismatched leaf (mmr-audit mode)
+    #[arg(long)]
+    truncate: bool,
 }
 
 #[derive(Copy, Clone, PartialEq, Eq, ValueEnum)]
 enum Mode {
     Fix,
     Update,
+    /// Check a proof file against a root, without connecting to the db or the node
+    VerifyProof,
+    /// Rebuild the MMRs from the stored headers into a fresh store
+    MmrRebuild,
+    /// Check every MMR leaf against the stored block hashes, truncating the MMR before the first mismatch with
+    /// --truncate
+    MmrAudit,
+    /// Remove the MMR leaves after --to-block
+    MmrTruncate,
 }
 
 #[tokio::main]
 async fn main() -> Result<()> {
     dotenvy::dotenv().ok();
-    env_logger::init();
 
     let cli = Cli::parse();
+    if cli.mode == Mode::VerifyProof {
+        let (Some(proof), Some(root)) = (&cli.proof, &cli.root) else {
+            bail!("verify-proof needs --proof and --root");
+        };
+        return verify_proof_file(proof, root);
+    }
+
+    if cli.mode == Mode::MmrTruncate && cli.to_block.is_none() {
+        bail!("mmr-truncate needs --to-block");
+    }
+
+    let config = Config::load(cli.config.as_deref(), &cli.overrides)?;
+    let _telemetry = telemetry::init(&config.telemetry)?;
+    info!("Effective config:\n{}", config.to_redacted_string()?);
+
+    let loopsize = min(
+        cli.loopsize.unwrap_or(config.db.max_connections),
+        config.db.max_connections,
+    );
+    let indexer = Indexer::builder().config(config).build().await?;
     let should_terminate = Arc::new(AtomicBool::new(false));
     let terminate_clone = should_terminate.clone();
 
     setup_ctrlc_handler(Arc::clone(&should_terminate))?;
 
+    match cli.mode {
+        Mode::MmrRebuild => {
+            let last_blocknumber = indexer.rebuild_mmr(&should_terminate).await?;
+            println!("MMR rebuilt up to block {last_blocknumber}");
+            return Ok(());
+        }
+        Mode::MmrAudit => return audit_mmr(&indexer, cli.truncate).await,
+        Mode::MmrTruncate => {
+            let to_block = cli.to_block.unwrap_or_default();
+            indexer.truncate_mmr(to_block).await?;
+            println!("MMR truncated to block {to_block}");
+            return Ok(());
+        }
+        _ => {}
+    }
+
     let router = async {
-        let res = router::initialize_router(should_terminate.clone()).await;
+        let res = router::initialize_router(indexer.clone(), should_terminate.clone()).await;
         match res {
             Ok(()) => info!("Router task completed"),
-            Err(e) => warn!("Router task failed: {:?}", e),
+            Err(e) => warn!(error = ?e, "Router task failed"),
         };
     };
 
     let updater = async {
         let res = match cli.mode {
             Mode::Fix => {
-                commands::fill_gaps(cli.start, cli.end, Arc::clone(&terminate_clone)).await
+                indexer
+                    .fix(cli.start, cli.end, Arc::clone(&terminate_clone))
+                    .await
+            }
+            Mode::Update if cli.leased => {
+                indexer
+                    .sync_leased(
+                        cli.start,
+                        cli.end,
+                        loopsize,
+                        cli.worker_id.clone().unwrap_or_else(default_worker_id),
+                        Arc::clone(&terminate_clone),
+                    )
+                    .await
             }
             Mode::Update => {
-                commands::update_from(
-                    cli.start,
-                    cli.end,
-                    min(cli.loopsize, db::DB_MAX_CONNECTIONS),
-                    Arc::clone(&terminate_clone),
-                )
-                .await
+                indexer
+                    .sync(cli.start, cli.end, loopsize, Arc::clone(&terminate_clone))
+                    .await
+            }
+            Mode::VerifyProof => unreachable!("verify-proof returns before the indexer is built"),
+            Mode::MmrRebuild | Mode::MmrAudit | Mode::MmrTruncate => {
+                unreachable!("MMR maintenance modes return before the updater starts")
             }
         };
 
         match res {
             Ok(()) => info!("Updater task completed"),
-            Err(e) => warn!("Updater task failed: {:?}", e),
+            Err(e) => warn!(error = ?e, "Updater task failed"),
         };
     };
 
@@ -85,6 +170,85 @@ async fn main() -> Result<()> {
     Ok(())
 }
 
+fn verify_proof_file(path: &Path, root: &str) -> Result<()> {
+    let proof = std::fs::read_to_string(path)
+        .with_context(|| format!("Failed to read {}", path.display()))?;
+    let proof: MmrProof = serde_json::from_str(&proof)
+        .with_context(|| format!("Failed to parse proof in {}", path.display()))?;
+
+    if !fossil_mmr::verify_proof(&proof, root)? {
+        bail!(
+            "Proof of block {} does not verify against root {root}",
+            proof.block_number
+        );
+    }
+    println!(
+        "Proof of block {} is valid against root {root} ({} elements)",
+        proof.block_number, proof.elements_count
+    );
+    Ok(())
+}
+
+async fn audit_mmr(indexer: &Indexer, truncate: bool) -> Result<()> {
+    let reports = indexer.audit_mmr().await?;
+    let mut mismatches_count = 0;
+    for report in &reports {
+        let shard = report
+            .shard
+            .map_or(String::new(), |shard| format!(" of shard {shard}"));
+        println!(
+            "{} MMR{}: {} leaves checked, {} mismatches",
+            report.hasher.as_str(),
+            shard,
+            report.leaves_count,
+            report.mismatches_count
+        );
+        if let Some(first_divergent_leaf) = report.first_divergent_leaf {
+            println!("  first divergent leaf: block {first_divergent_leaf}");
+        }
+        for mismatch in &report.mismatches {
+            println!(
+                "  block {}: leaf {}, expected {}",
+                mismatch.block_number,
+                mismatch.leaf.as_deref().unwrap_or("missing"),
+                mismatch
+                    .expected_leaf
+                    .as_deref()
+                    .unwrap_or("missing (block not stored)")
+            );
+        }
+        if report.mismatches.len() < report.mismatches_count {
+            println!(
+                "  ... and {} more",
+                report.mismatches_count - report.mismatches.len()
+            );
+        }
+        mismatches_count += report.mismatches_count;
+    }
+
+    let Some(first_divergent_leaf) = reports
+        .iter()
+        .filter_map(|report| report.first_divergent_leaf)
+        .min()
+    else {
+        return Ok(());
+    };
+    if !truncate {
+        bail!("MMR audit found {mismatches_count} mismatched leaves");
+    }
+    indexer.truncate_mmr(first_divergent_leaf - 1).await?;
+    println!(
+        "MMR truncated to block {}, the next update appends the stored blocks again",
+        first_divergent_leaf - 1
+    );
+    Ok(())
+}
+
+fn default_worker_id() -> String {
+    let hostname = dotenvy::var("HOSTNAME").unwrap_or_else(|_| "worker".to_string());
+    format!("{}-{}", hostname, std::process::id())
+}
+
 fn setup_ctrlc_handler(should_terminate: Arc<AtomicBool>) -> Result<()> {
     ctrlc::set_handler(move || {
         info!("Received Ctrl+C");
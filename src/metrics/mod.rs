@@ -0,0 +1,129 @@
+use anyhow::Result;
+use prometheus::{
+    Encoder, Histogram, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, IntGauge,
+    IntGaugeVec, Opts, Registry, TextEncoder,
+};
+
+const NAMESPACE: &str = "fossil";
+
+/**
+ * Prometheus metrics of one indexer, kept in their own registry so several indexers do not collide
+ */
+pub struct Metrics {
+    registry: Registry,
+    pub blocks_written: IntCounter,
+    pub block_retries: IntCounterVec,
+    pub rpc_request_duration: HistogramVec,
+    pub rpc_errors: IntCounterVec,
+    pub db_write_duration: Histogram,
+    pub mmr_appends: IntCounterVec,
+    pub mmr_elements_count: IntGaugeVec,
+    pub mmr_latest_blocknumber: IntGaugeVec,
+    pub mmr_gap_blocks: IntGauge,
+    pub stored_head_blocknumber: IntGauge,
+    pub finalized_head_blocknumber: IntGauge,
+    pub head_lag_blocks: IntGauge,
+}
+
+impl Metrics {
+    pub fn new() -> Result<Self> {
+        let registry = Registry::new_custom(Some(NAMESPACE.to_string()), None)?;
+
+        let blocks_written = IntCounter::new("blocks_written_total", "Blocks written to the db")?;
+        let block_retries = IntCounterVec::new(
+            Opts::new(
+                "block_retries_total",
+                "Retries when fetching or writing a block",
+            ),
+            &["command"],
+        )?;
+        let rpc_request_duration = HistogramVec::new(
+            HistogramOpts::new("rpc_request_duration_seconds", "Latency of RPC calls"),
+            &["method"],
+        )?;
+        let rpc_errors = IntCounterVec::new(
+            Opts::new("rpc_errors_total", "Failed RPC calls"),
+            &["method"],
+        )?;
+        let db_write_duration = Histogram::with_opts(HistogramOpts::new(
+            "db_write_duration_seconds",
+            "Latency of writing a block and its transactions",
+        ))?;
+        let mmr_appends = IntCounterVec::new(
+            Opts::new("mmr_appends_total", "Block hashes appended to the MMR"),
+            &["hasher"],
+        )?;
+        let mmr_elements_count = IntGaugeVec::new(
+            Opts::new("mmr_elements_count", "Elements in the MMR"),
+            &["hasher"],
+        )?;
+        let mmr_latest_blocknumber = IntGaugeVec::new(
+            Opts::new(
+                "mmr_latest_blocknumber",
+                "Last block number appended to the MMR",
+            ),
+            &["hasher"],
+        )?;
+        let mmr_gap_blocks = IntGauge::new(
+            "mmr_gap_blocks",
+            "Missing stored blocks the MMR update stopped at, 0 if none",
+        )?;
+        let stored_head_blocknumber = IntGauge::new(
+            "stored_head_blocknumber",
+            "Last block number written to the db",
+        )?;
+        let finalized_head_blocknumber = IntGauge::new(
+            "finalized_head_blocknumber",
+            "Latest finalized block number of the chain",
+        )?;
+        let head_lag_blocks = IntGauge::new(
+            "head_lag_blocks",
+            "Blocks between the finalized head and the stored head",
+        )?;
+
+        registry.register(Box::new(blocks_written.clone()))?;
+        registry.register(Box::new(block_retries.clone()))?;
+        registry.register(Box::new(rpc_request_duration.clone()))?;
+        registry.register(Box::new(rpc_errors.clone()))?;
+        registry.register(Box::new(db_write_duration.clone()))?;
+        registry.register(Box::new(mmr_appends.clone()))?;
+        registry.register(Box::new(mmr_elements_count.clone()))?;
+        registry.register(Box::new(mmr_latest_blocknumber.clone()))?;
+        registry.register(Box::new(mmr_gap_blocks.clone()))?;
+        registry.register(Box::new(stored_head_blocknumber.clone()))?;
+        registry.register(Box::new(finalized_head_blocknumber.clone()))?;
+        registry.register(Box::new(head_lag_blocks.clone()))?;
+
+        Ok(Self {
+            registry,
+            blocks_written,
+            block_retries,
+            rpc_request_duration,
+            rpc_errors,
+            db_write_duration,
+            mmr_appends,
+            mmr_elements_count,
+            mmr_latest_blocknumber,
+            mmr_gap_blocks,
+            stored_head_blocknumber,
+            finalized_head_blocknumber,
+            head_lag_blocks,
+        })
+    }
+
+    pub fn set_heads(&self, stored_head: i64, finalized_head: i64) {
+        self.stored_head_blocknumber.set(stored_head);
+        self.finalized_head_blocknumber.set(finalized_head);
+        self.head_lag_blocks
+            .set((finalized_head - stored_head).max(0));
+    }
+
+    /**
+     * Renders all metrics in the Prometheus text format
+     */
+    pub fn encode(&self) -> Result<String> {
+        let mut buffer = Vec::new();
+        TextEncoder::new().encode(&self.registry.gather(), &mut buffer)?;
+        Ok(String::from_utf8(buffer)?)
+    }
+}
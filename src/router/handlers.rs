@@ -1,24 +1,50 @@
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 use axum::{
-    extract::Path,
-    http::StatusCode,
+    extract::{Path, Query, State},
+    http::{header, HeaderMap, StatusCode},
     response::{IntoResponse, Response},
     Json,
 };
-use log::info;
+use serde::Deserialize;
+use tracing::info;
 
-use crate::types::ProofWrapper;
-use crate::{fossil_mmr, types::Update};
+use crate::config::MmrHasher;
+use crate::fossil_mmr;
+use crate::indexer::Indexer;
+use crate::types::{
+    HealthReport, MmrConsistencyProof, MmrProof, MmrRoot, MmrShardIndex, MmrShardInfo, Page,
+    ProofFormat, ProofVerification, StoredBlockHeader, StoredTransaction, TransactionDirection,
+    Update, PROOF_SCHEMA_VERSION,
+};
+
+const DEFAULT_PAGE_SIZE: i64 = 100;
+/// Accept header value selecting the pre-versioning proof shape
+const LEGACY_PROOF_MEDIA_TYPE: &str = "application/vnd.fossil.proof.legacy+json";
+
+pub struct Error {
+    status: StatusCode,
+    error: anyhow::Error,
+}
+
+impl Error {
+    fn not_found(message: String) -> Self {
+        Self {
+            status: StatusCode::NOT_FOUND,
+            error: anyhow!(message),
+        }
+    }
 
-pub struct Error(anyhow::Error);
+    fn bad_request(message: String) -> Self {
+        Self {
+            status: StatusCode::BAD_REQUEST,
+            error: anyhow!(message),
+        }
+    }
+}
 
 impl IntoResponse for Error {
     fn into_response(self) -> Response {
-        (
-            StatusCode::INTERNAL_SERVER_ERROR,
-            format!("Error: {}", self.0),
-        )
-            .into_response()
+        (self.status, format!("Error: {}", self.error)).into_response()
     }
 }
 
@@ -27,20 +53,841 @@ where
     E: Into<anyhow::Error>,
 {
     fn from(err: E) -> Self {
-        Self(err.into())
+        Self {
+            status: StatusCode::INTERNAL_SERVER_ERROR,
+            error: err.into(),
+        }
+    }
+}
+
+/**
+ * Path of the /mmr routes. Without a hasher (/mmr/...) the MMR of the first configured hasher is used
+ */
+#[derive(Deserialize)]
+pub struct MmrPath {
+    hasher: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct MmrBlockPath {
+    hasher: Option<String>,
+    blocknumber: i64,
+}
+
+#[derive(Deserialize)]
+pub struct MmrShardPath {
+    hasher: Option<String>,
+    shard: u64,
+}
+
+#[derive(Deserialize)]
+pub struct MmrShardBlockPath {
+    hasher: Option<String>,
+    shard: u64,
+    blocknumber: i64,
+}
+
+#[derive(Deserialize)]
+pub struct BlockRangeParams {
+    from: Option<i64>,
+    to: Option<i64>,
+    cursor: Option<String>,
+    limit: Option<i64>,
+}
+
+/**
+ * Either a list of block numbers or a range (inclusive)
+ */
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct BatchProofRequest {
+    block_numbers: Option<Vec<i64>>,
+    from: Option<i64>,
+    to: Option<i64>,
+    elements_count: Option<usize>,
+    as_of_block: Option<i64>,
+    #[serde(default)]
+    header_rlp: bool,
+}
+
+/**
+ * MMR size to prove against, given directly or as the block whose append produced it (Default: current size),
+ * and the shape of the response (Default: json)
+ */
+#[derive(Deserialize)]
+pub struct ProofParams {
+    elements_count: Option<usize>,
+    as_of_block: Option<i64>,
+    #[serde(default)]
+    format: ProofFormat,
+    /// Adds the RLP-encoded header of the block
+    #[serde(default)]
+    header_rlp: bool,
+}
+
+#[derive(Deserialize)]
+pub struct ProofFormatParams {
+    #[serde(default)]
+    format: ProofFormat,
+}
+
+/**
+ * Proof as returned by GET /mmr/:blocknumber, checked against root (Default: our root at the proof's size)
+ */
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct VerifyProofRequest {
+    proof: MmrProof,
+    root: Option<String>,
+}
+
+/**
+ * MMR sizes (elements counts) to prove consistency between
+ */
+#[derive(Deserialize)]
+pub struct ConsistencyParams {
+    from: usize,
+    to: usize,
+}
+
+#[derive(Deserialize)]
+pub struct PageParams {
+    cursor: Option<String>,
+    limit: Option<i64>,
+}
+
+#[derive(Deserialize)]
+pub struct AddressTransactionsParams {
+    #[serde(default)]
+    direction: TransactionDirection,
+    from_block: Option<i64>,
+    to_block: Option<i64>,
+    cursor: Option<String>,
+    limit: Option<i64>,
+}
+
+pub async fn get_mmr_latest(
+    State(indexer): State<Indexer>,
+    Path(path): Path<MmrPath>,
+) -> Result<Json<Update>, Error> {
+    let hasher = parse_hasher(&indexer, path.hasher)?;
+    info!("Received request for latest {} mmr", hasher.as_str());
+
+    match indexer.get_mmr_stats(hasher).await? {
+        Some(update) => Ok(Json(update)),
+        None => Err(Error::not_found(
+            "Nothing appended to the MMR yet".to_string(),
+        )),
+    }
+}
+
+pub async fn get_mmr_root(
+    State(indexer): State<Indexer>,
+    Path(path): Path<MmrBlockPath>,
+) -> Result<Json<MmrRoot>, Error> {
+    let hasher = parse_hasher(&indexer, path.hasher)?;
+    let blocknumber = path.blocknumber;
+    match indexer.get_mmr_root(hasher, blocknumber).await? {
+        Some(root) => Ok(Json(root)),
+        None => Err(Error::not_found(format!(
+            "No root recorded for block {blocknumber}"
+        ))),
     }
 }
 
-pub async fn get_mmr_latest() -> Result<Json<Update>, Error> {
-    info!("Received request for latest mmr");
+/**
+ * /mmr/:hasher is either a hasher (its latest MMR information) or, from before hashers were configurable, a block
+ * number (its proof in the default MMR)
+ */
+pub async fn get_mmr_latest_or_proof(
+    state: State<Indexer>,
+    Path(segment): Path<String>,
+    query: Query<ProofParams>,
+    headers: HeaderMap,
+) -> Result<Response, Error> {
+    match segment.parse::<i64>() {
+        Ok(blocknumber) => {
+            let path = MmrBlockPath {
+                hasher: None,
+                blocknumber,
+            };
+            get_mmr_proof(state, Path(path), query, headers).await
+        }
+        Err(_) => {
+            let path = MmrPath {
+                hasher: Some(segment),
+            };
+            Ok(get_mmr_latest(state, Path(path)).await?.into_response())
+        }
+    }
+}
+
+pub async fn get_mmr_proof(
+    State(indexer): State<Indexer>,
+    Path(path): Path<MmrBlockPath>,
+    Query(params): Query<ProofParams>,
+    headers: HeaderMap,
+) -> Result<Response, Error> {
+    let hasher = parse_hasher(&indexer, path.hasher)?;
+    let blocknumber = path.blocknumber;
+    info!(
+        "Received request for {} proof for block {blocknumber}",
+        hasher.as_str()
+    );
+
+    let elements_count = parse_elements_count(
+        params.elements_count,
+        params.as_of_block,
+        0,
+        indexer.committed_elements_count(hasher)?,
+    )?;
+    let mut proof = indexer
+        .get_proof(hasher, blocknumber, elements_count)
+        .await?
+        .ok_or_else(|| not_in_mmr(blocknumber, elements_count))?;
+    if params.header_rlp {
+        proof.header_rlp = Some(get_header_rlp(&indexer, blocknumber).await?);
+    }
+    proof_response(proof, params.format, &headers)
+}
+
+/**
+ * Proofs of several blocks: one batch proof as json, or the calldata of each proof in block order
+ */
+pub async fn post_mmr_proofs(
+    State(indexer): State<Indexer>,
+    Path(path): Path<MmrPath>,
+    Query(params): Query<ProofFormatParams>,
+    Json(request): Json<BatchProofRequest>,
+) -> Result<Response, Error> {
+    let hasher = parse_hasher(&indexer, path.hasher)?;
+    check_proof_format(hasher, params.format)?;
+    let max_batch_proofs = indexer.config().router.max_batch_proofs;
+    let elements_count = parse_elements_count(
+        request.elements_count,
+        request.as_of_block,
+        0,
+        indexer.committed_elements_count(hasher)?,
+    )?;
+    let blocknumbers = match (request.block_numbers, request.from, request.to) {
+        (Some(block_numbers), None, None) => block_numbers,
+        (None, Some(from), Some(to)) if 0 <= from && from <= to => {
+            if (to - from) as u64 >= max_batch_proofs as u64 {
+                return Err(Error::bad_request(format!(
+                    "At most {max_batch_proofs} blocks per request"
+                )));
+            }
+            (from..=to).collect()
+        }
+        _ => {
+            return Err(Error::bad_request(
+                "Expected either block_numbers or 0 <= from <= to".to_string(),
+            ))
+        }
+    };
+    if blocknumbers.is_empty() || blocknumbers.len() > max_batch_proofs {
+        return Err(Error::bad_request(format!(
+            "Expected 1 to {max_batch_proofs} blocks"
+        )));
+    }
+    if let Some(blocknumber) = blocknumbers.iter().find(|&&blocknumber| blocknumber < 0) {
+        return Err(Error::bad_request(format!(
+            "Invalid block number {blocknumber}"
+        )));
+    }
+    info!("Received request for {} proofs", blocknumbers.len());
+
+    let Some(mut batch_proof) = indexer
+        .get_proofs(hasher, &blocknumbers, elements_count)
+        .await?
+    else {
+        // The MMR holds a prefix of the chain, so the highest block is one of the missing ones
+        let highest = blocknumbers.iter().max().copied().unwrap_or_default();
+        return Err(not_in_mmr(highest, elements_count));
+    };
+    if request.header_rlp {
+        let header_rlps = indexer.get_header_rlps(&blocknumbers).await?;
+        for item in &mut batch_proof.proofs {
+            let header_rlp = header_rlps
+                .get(&item.block_number)
+                .ok_or_else(|| no_header_rlp(item.block_number))?;
+            item.header_rlp = Some(header_rlp.clone());
+        }
+    }
+
+    if params.format == ProofFormat::Json {
+        return Ok(Json(batch_proof).into_response());
+    }
+    let calldata = batch_proof
+        .to_proofs()
+        .iter()
+        .map(|proof| fossil_mmr::encode_proof(proof, params.format))
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    Ok(Json(calldata).into_response())
+}
+
+pub async fn post_mmr_verify(
+    State(indexer): State<Indexer>,
+    Path(path): Path<MmrPath>,
+    Json(request): Json<VerifyProofRequest>,
+) -> Result<Json<ProofVerification>, Error> {
+    let hasher = parse_hasher(&indexer, path.hasher)?;
+    let VerifyProofRequest { proof, root } = request;
+    info!(
+        "Received request to verify proof for block {}",
+        proof.block_number
+    );
 
-    let res = fossil_mmr::get_mmr_stats().await?;
-    Ok(Json(res))
+    if proof.version != PROOF_SCHEMA_VERSION {
+        return Err(Error::bad_request(format!(
+            "Unsupported proof version {}, expected {PROOF_SCHEMA_VERSION}",
+            proof.version
+        )));
+    }
+    if proof.hasher != hasher {
+        return Err(Error::bad_request(format!(
+            "Proof is for the {} MMR, not the {} one",
+            proof.hasher.as_str(),
+            hasher.as_str()
+        )));
+    }
+    // Our root is only the shard's if the proof's shard starts where ours does
+    if let (Some(shard), None) = (proof.shard, &root) {
+        let shard_size = indexer.config().mmr.shard_size;
+        if shard_size == 0 || shard.first_block != i64::try_from(shard.index)? * shard_size {
+            return Err(Error::bad_request(format!(
+                "Shard {} does not start at block {}",
+                shard.index, shard.first_block
+            )));
+        }
+    }
+    let root = match root {
+        Some(root) => parse_hash(&root)?,
+        None if fossil_mmr::is_valid_elements_count(proof.elements_count) => match proof.shard {
+            Some(shard) => {
+                indexer
+                    .get_shard_root_hash(hasher, shard.index, proof.elements_count)
+                    .await?
+            }
+            None => indexer.get_root_hash(hasher, proof.elements_count).await?,
+        }
+        .ok_or_else(|| {
+            Error::not_found(format!(
+                "The MMR has not reached {} elements yet",
+                proof.elements_count
+            ))
+        })?,
+        None => {
+            return Err(Error::bad_request(format!(
+                "Invalid elements_count {}, not a possible MMR size",
+                proof.elements_count
+            )))
+        }
+    };
+
+    let valid = fossil_mmr::verify_proof(&proof, &root)?;
+    Ok(Json(ProofVerification { valid, root }))
 }
 
-pub async fn get_mmr_proof(Path(blocknumber): Path<i64>) -> Result<Json<ProofWrapper>, Error> {
-    info!("Received request for proof for block {blocknumber}");
+pub async fn get_mmr_consistency(
+    State(indexer): State<Indexer>,
+    Path(path): Path<MmrPath>,
+    Query(params): Query<ConsistencyParams>,
+) -> Result<Json<MmrConsistencyProof>, Error> {
+    let hasher = parse_hasher(&indexer, path.hasher)?;
+    let ConsistencyParams { from, to } = params;
+    info!("Received request for consistency proof {from} -> {to}");
 
-    let res = fossil_mmr::get_proof(blocknumber).await?;
-    Ok(Json(ProofWrapper { proof: res }))
+    if !fossil_mmr::is_valid_elements_count(from) || !fossil_mmr::is_valid_elements_count(to) {
+        return Err(Error::bad_request(format!(
+            "Invalid sizes {from} -> {to}, not possible MMR sizes"
+        )));
+    }
+    if from > to {
+        return Err(Error::bad_request(format!(
+            "Expected from <= to, got {from} -> {to}"
+        )));
+    }
+    match indexer.get_consistency_proof(hasher, from, to).await? {
+        Some(proof) => Ok(Json(proof)),
+        None => Err(Error::not_found(format!(
+            "The MMR has not reached {to} elements yet"
+        ))),
+    }
+}
+
+pub async fn get_mmr_shards(
+    State(indexer): State<Indexer>,
+    Path(path): Path<MmrPath>,
+) -> Result<Json<MmrShardIndex>, Error> {
+    let hasher = parse_hasher(&indexer, path.hasher)?;
+    match indexer.get_mmr_shards(hasher).await? {
+        Some(index) => Ok(Json(index)),
+        None => Err(not_sharded()),
+    }
+}
+
+pub async fn get_mmr_shard(
+    State(indexer): State<Indexer>,
+    Path(path): Path<MmrShardPath>,
+) -> Result<Json<MmrShardInfo>, Error> {
+    let hasher = parse_hasher(&indexer, path.hasher)?;
+    Ok(Json(find_shard(&indexer, hasher, path.shard).await?))
+}
+
+/**
+ * Shard holding a block, as given by the shard index
+ */
+pub async fn get_mmr_shard_of_block(
+    State(indexer): State<Indexer>,
+    Path(path): Path<MmrBlockPath>,
+) -> Result<Json<MmrShardInfo>, Error> {
+    let hasher = parse_hasher(&indexer, path.hasher)?;
+    let blocknumber = path.blocknumber;
+    if indexer.config().mmr.shard_size == 0 {
+        return Err(not_sharded());
+    }
+    match indexer.get_mmr_shard_of(hasher, blocknumber).await? {
+        Some(shard) => Ok(Json(shard)),
+        None => Err(Error::not_found(format!(
+            "No shard holds block {blocknumber} yet"
+        ))),
+    }
+}
+
+pub async fn get_mmr_shard_root(
+    State(indexer): State<Indexer>,
+    Path(path): Path<MmrShardBlockPath>,
+) -> Result<Json<MmrRoot>, Error> {
+    let hasher = parse_hasher(&indexer, path.hasher)?;
+    let MmrShardBlockPath {
+        shard, blocknumber, ..
+    } = path;
+    find_shard(&indexer, hasher, shard).await?;
+    match indexer.get_shard_root(hasher, shard, blocknumber).await? {
+        Some(root) => Ok(Json(root)),
+        None => Err(Error::not_found(format!(
+            "No root recorded in shard {shard} for block {blocknumber}"
+        ))),
+    }
+}
+
+/**
+ * Proof of a block in the MMR of its shard. as_of_block past the end of the shard is its complete MMR.
+ */
+pub async fn get_mmr_shard_proof(
+    State(indexer): State<Indexer>,
+    Path(path): Path<MmrShardBlockPath>,
+    Query(params): Query<ProofParams>,
+    headers: HeaderMap,
+) -> Result<Response, Error> {
+    let hasher = parse_hasher(&indexer, path.hasher)?;
+    let MmrShardBlockPath {
+        shard, blocknumber, ..
+    } = path;
+    info!(
+        "Received request for {} proof for block {blocknumber} in shard {shard}",
+        hasher.as_str()
+    );
+
+    let shard_info = find_shard(&indexer, hasher, shard).await?;
+    let as_of_block = params
+        .as_of_block
+        .map(|as_of_block| as_of_block.min(shard_info.last_block));
+    let elements_count = parse_elements_count(
+        params.elements_count,
+        as_of_block,
+        shard_info.first_block,
+        shard_info.elements_count,
+    )?;
+    let mut proof = indexer
+        .get_shard_proof(hasher, shard, blocknumber, elements_count)
+        .await?
+        .ok_or_else(|| not_in_mmr(blocknumber, elements_count))?;
+    if params.header_rlp {
+        proof.header_rlp = Some(get_header_rlp(&indexer, blocknumber).await?);
+    }
+    proof_response(proof, params.format, &headers)
+}
+
+pub async fn get_block(
+    State(indexer): State<Indexer>,
+    Path(number): Path<i64>,
+) -> Result<Json<StoredBlockHeader>, Error> {
+    match indexer.get_block(number).await? {
+        Some(block) => Ok(Json(block)),
+        None => Err(Error::not_found(format!("Block {number} not stored"))),
+    }
+}
+
+pub async fn get_block_by_hash(
+    State(indexer): State<Indexer>,
+    Path(block_hash): Path<String>,
+) -> Result<Json<StoredBlockHeader>, Error> {
+    let block_hash = parse_hash(&block_hash)?;
+    match indexer.get_block_by_hash(&block_hash).await? {
+        Some(block) => Ok(Json(block)),
+        None => Err(Error::not_found(format!("Block {block_hash} not stored"))),
+    }
+}
+
+pub async fn get_blocks(
+    State(indexer): State<Indexer>,
+    Query(params): Query<BlockRangeParams>,
+) -> Result<Json<Page<StoredBlockHeader>>, Error> {
+    let (from, to) = parse_block_range(params.from, params.to)?;
+    let cursor = parse_cursor(params.cursor.as_deref(), |cursor| cursor.parse().ok())?;
+    let limit = parse_limit(&indexer, params.limit)?;
+
+    let page = indexer.get_blocks(from, to, cursor, limit).await?;
+    Ok(Json(page))
+}
+
+pub async fn get_transaction(
+    State(indexer): State<Indexer>,
+    Path(transaction_hash): Path<String>,
+) -> Result<Json<StoredTransaction>, Error> {
+    let transaction_hash = parse_hash(&transaction_hash)?;
+    match indexer.get_transaction(&transaction_hash).await? {
+        Some(transaction) => Ok(Json(transaction)),
+        None => Err(Error::not_found(format!(
+            "Transaction {transaction_hash} not stored"
+        ))),
+    }
+}
+
+pub async fn get_block_transactions(
+    State(indexer): State<Indexer>,
+    Path(number): Path<i64>,
+    Query(params): Query<PageParams>,
+) -> Result<Json<Page<StoredTransaction>>, Error> {
+    let cursor = parse_cursor(params.cursor.as_deref(), |cursor| cursor.parse().ok())?;
+    let limit = parse_limit(&indexer, params.limit)?;
+
+    let page = indexer
+        .get_block_transactions(number, cursor, limit)
+        .await?;
+    // An empty first page is either a block without transactions or one that is not stored
+    if page.items.is_empty() && cursor.is_none() && indexer.get_block(number).await?.is_none() {
+        return Err(Error::not_found(format!("Block {number} not stored")));
+    }
+    Ok(Json(page))
+}
+
+pub async fn get_address_transactions(
+    State(indexer): State<Indexer>,
+    Path(address): Path<String>,
+    Query(params): Query<AddressTransactionsParams>,
+) -> Result<Json<Page<StoredTransaction>>, Error> {
+    let address = parse_address(&address)?;
+    let (from, to) = parse_block_range(params.from_block, params.to_block)?;
+    let cursor = parse_cursor(params.cursor.as_deref(), |cursor| {
+        let (block_number, transaction_index) = cursor.split_once(':')?;
+        Some((block_number.parse().ok()?, transaction_index.parse().ok()?))
+    })?;
+    let limit = parse_limit(&indexer, params.limit)?;
+
+    let page = indexer
+        .get_address_transactions(&address, params.direction, from, to, cursor, limit)
+        .await?;
+    Ok(Json(page))
+}
+
+pub async fn get_metrics(State(indexer): State<Indexer>) -> Result<Response, Error> {
+    let body = indexer.metrics().encode()?;
+    Ok(([(header::CONTENT_TYPE, "text/plain; version=0.0.4")], body).into_response())
+}
+
+pub async fn get_health_live(State(indexer): State<Indexer>) -> (StatusCode, Json<HealthReport>) {
+    health_response(indexer.liveness())
+}
+
+pub async fn get_health_ready(State(indexer): State<Indexer>) -> (StatusCode, Json<HealthReport>) {
+    health_response(indexer.readiness().await)
+}
+
+fn health_response(report: HealthReport) -> (StatusCode, Json<HealthReport>) {
+    let status = if report.healthy {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    (status, Json(report))
+}
+
+/**
+ * Hasher of an /mmr/:hasher route, which must be one of mmr.hashers (Default: the first of them)
+ */
+fn parse_hasher(indexer: &Indexer, hasher: Option<String>) -> Result<MmrHasher, Error> {
+    let hashers = &indexer.config().mmr.hashers;
+    let Some(hasher) = hasher else {
+        return Ok(hashers[0]);
+    };
+    match MmrHasher::parse(&hasher) {
+        Some(hasher) if hashers.contains(&hasher) => Ok(hasher),
+        _ => Err(Error::not_found(format!("No {hasher} MMR"))),
+    }
+}
+
+/**
+ * Size to prove against in an MMR whose first leaf is block first_block, and which has committed_elements_count
+ * elements
+ */
+fn parse_elements_count(
+    elements_count: Option<usize>,
+    as_of_block: Option<i64>,
+    first_block: i64,
+    committed_elements_count: usize,
+) -> Result<Option<usize>, Error> {
+    let elements_count = match (elements_count, as_of_block) {
+        (None, None) => return Ok(None),
+        (Some(elements_count), None) if elements_count > committed_elements_count => {
+            return Err(past_committed_size(committed_elements_count))
+        }
+        (Some(elements_count), None) if fossil_mmr::is_valid_elements_count(elements_count) => {
+            elements_count
+        }
+        (Some(elements_count), None) => {
+            return Err(Error::bad_request(format!(
+                "Invalid elements_count {elements_count}, not a possible MMR size"
+            )))
+        }
+        (None, Some(as_of_block)) if as_of_block >= first_block => {
+            // An MMR has at least as many elements as leaves, which bounds the block before the arithmetic
+            let leaf_index = as_of_block - first_block;
+            if leaf_index as u64 >= committed_elements_count as u64 {
+                return Err(past_committed_size(committed_elements_count));
+            }
+            fossil_mmr::elements_count_after_block(leaf_index)?
+        }
+        (None, Some(as_of_block)) => {
+            return Err(Error::bad_request(format!(
+                "Invalid as_of_block {as_of_block}"
+            )))
+        }
+        (Some(_), Some(_)) => {
+            return Err(Error::bad_request(
+                "Expected either elements_count or as_of_block".to_string(),
+            ))
+        }
+    };
+    if elements_count > committed_elements_count {
+        return Err(past_committed_size(committed_elements_count));
+    }
+    Ok(Some(elements_count))
+}
+
+fn past_committed_size(committed_elements_count: usize) -> Error {
+    Error::bad_request(format!(
+        "The MMR has {committed_elements_count} elements, cannot prove against a larger size"
+    ))
+}
+
+async fn find_shard(
+    indexer: &Indexer,
+    hasher: MmrHasher,
+    shard: u64,
+) -> Result<MmrShardInfo, Error> {
+    if indexer.config().mmr.shard_size == 0 {
+        return Err(not_sharded());
+    }
+    indexer
+        .get_mmr_shard(hasher, shard)
+        .await?
+        .ok_or_else(|| Error::not_found(format!("No shard {shard} yet")))
+}
+
+/**
+ * RLP-encoded header of a stored block, as 0x-prefixed hex
+ */
+async fn get_header_rlp(indexer: &Indexer, blocknumber: i64) -> Result<String, Error> {
+    indexer
+        .get_header_rlps(&[blocknumber])
+        .await?
+        .remove(&blocknumber)
+        .ok_or_else(|| no_header_rlp(blocknumber))
+}
+
+fn no_header_rlp(blocknumber: i64) -> Error {
+    Error::not_found(format!(
+        "No header RLP for block {blocknumber}, stored before its header fields were recorded"
+    ))
+}
+
+fn not_sharded() -> Error {
+    Error::not_found("The MMRs are not sharded (mmr.shard_size is 0)".to_string())
+}
+
+fn not_in_mmr(blocknumber: i64, elements_count: Option<usize>) -> Error {
+    match elements_count {
+        Some(elements_count) => Error::not_found(format!(
+            "Block {blocknumber} not in the MMR at {elements_count} elements, or the MMR is not that large yet"
+        )),
+        None => Error::not_found(format!("Block {blocknumber} not in the MMR")),
+    }
+}
+
+/**
+ * A proof in the requested format. The legacy Accept header only applies to json.
+ */
+fn proof_response(
+    proof: MmrProof,
+    format: ProofFormat,
+    headers: &HeaderMap,
+) -> Result<Response, Error> {
+    check_proof_format(proof.hasher, format)?;
+    match format {
+        ProofFormat::Json if accepts_legacy_proof(headers) => {
+            Ok(Json(proof.to_legacy()).into_response())
+        }
+        ProofFormat::Json => Ok(Json(proof).into_response()),
+        _ => Ok(Json(fossil_mmr::encode_proof(&proof, format)?).into_response()),
+    }
+}
+
+/**
+ * The Solidity verifier only checks keccak MMRs
+ */
+fn check_proof_format(hasher: MmrHasher, format: ProofFormat) -> Result<(), Error> {
+    if format == ProofFormat::EvmAbi && hasher != MmrHasher::Keccak {
+        return Err(Error::bad_request(format!(
+            "No {} proofs for the {} MMR, only for keccak",
+            format.as_str(),
+            hasher.as_str()
+        )));
+    }
+    Ok(())
+}
+
+fn accepts_legacy_proof(headers: &HeaderMap) -> bool {
+    headers
+        .get_all(header::ACCEPT)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|media_type| {
+            media_type
+                .split(';')
+                .next()
+                .is_some_and(|media_type| media_type.trim() == LEGACY_PROOF_MEDIA_TYPE)
+        })
+}
+
+fn parse_block_range(from: Option<i64>, to: Option<i64>) -> Result<(i64, i64), Error> {
+    let from = from.unwrap_or(0);
+    let to = to.unwrap_or(i64::MAX);
+    if from > to {
+        return Err(Error::bad_request(format!(
+            "Start of range ({from}) must not be greater than its end ({to})"
+        )));
+    }
+    Ok((from, to))
+}
+
+fn parse_cursor<T>(
+    cursor: Option<&str>,
+    parse: impl Fn(&str) -> Option<T>,
+) -> Result<Option<T>, Error> {
+    match cursor {
+        Some(cursor) => parse(cursor)
+            .map(Some)
+            .ok_or_else(|| Error::bad_request(format!("Invalid cursor {cursor}"))),
+        None => Ok(None),
+    }
+}
+
+fn parse_limit(indexer: &Indexer, limit: Option<i64>) -> Result<i64, Error> {
+    let max_page_size = indexer.config().router.max_page_size;
+    match limit {
+        None => Ok(DEFAULT_PAGE_SIZE.min(max_page_size)),
+        Some(limit) if (1..=max_page_size).contains(&limit) => Ok(limit),
+        Some(limit) => Err(Error::bad_request(format!(
+            "Invalid limit {limit}, expected 1 to {max_page_size}"
+        ))),
+    }
+}
+
+/**
+ * Checks a 0x-prefixed 32 byte hex hash, returning it lowercased as stored
+ */
+fn parse_hash(hash: &str) -> Result<String, Error> {
+    let is_valid = hash.len() == 66
+        && hash.starts_with("0x")
+        && hash[2..].chars().all(|c| c.is_ascii_hexdigit());
+    if !is_valid {
+        return Err(Error::bad_request(format!("Invalid hash {hash}")));
+    }
+    Ok(hash.to_lowercase())
+}
+
+/**
+ * Checks a 0x-prefixed 20 byte hex address, returning it lowercased as stored
+ */
+fn parse_address(address: &str) -> Result<String, Error> {
+    let is_valid = address.len() == 42
+        && address.starts_with("0x")
+        && address[2..].chars().all(|c| c.is_ascii_hexdigit());
+    if !is_valid {
+        return Err(Error::bad_request(format!("Invalid address {address}")));
+    }
+    Ok(address.to_lowercase())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn status(result: Result<Option<usize>, Error>) -> StatusCode {
+        match result {
+            Ok(_) => StatusCode::OK,
+            Err(error) => error.status,
+        }
+    }
+
+    #[test]
+    fn parse_elements_count_resolves_sizes() {
+        assert_eq!(parse_elements_count(None, None, 0, 39).ok(), Some(None));
+        assert_eq!(
+            parse_elements_count(Some(3), None, 0, 39).ok(),
+            Some(Some(3))
+        );
+        assert_eq!(
+            parse_elements_count(None, Some(1), 0, 39).ok(),
+            Some(Some(3))
+        );
+        assert_eq!(
+            parse_elements_count(None, Some(20), 0, 39).ok(),
+            Some(Some(39))
+        );
+        // Leaf 2 of an MMR starting at block 8
+        assert_eq!(
+            parse_elements_count(None, Some(10), 8, 4).ok(),
+            Some(Some(4))
+        );
+    }
+
+    #[test]
+    fn parse_elements_count_rejects_sizes_past_the_mmr() {
+        for (elements_count, as_of_block, first_block) in [
+            (Some(40), None, 0),
+            (Some(u64::MAX as usize), None, 0),
+            (None, Some(21), 0),
+            (None, Some(i64::MAX), 0),
+            (None, Some(i64::MAX), 8),
+            (Some(5), None, 0),
+            (None, Some(-1), 0),
+            (Some(3), Some(1), 0),
+        ] {
+            assert_eq!(
+                status(parse_elements_count(
+                    elements_count,
+                    as_of_block,
+                    first_block,
+                    39
+                )),
+                StatusCode::BAD_REQUEST,
+                "{elements_count:?} {as_of_block:?}"
+            );
+        }
+    }
 }
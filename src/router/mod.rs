@@ -1,25 +1,81 @@
-use crate::router::handlers::{get_mmr_latest, get_mmr_proof};
+use crate::indexer::Indexer;
+use crate::router::handlers::{
+    get_address_transactions, get_block, get_block_by_hash, get_block_transactions, get_blocks,
+    get_health_live, get_health_ready, get_metrics, get_mmr_consistency, get_mmr_latest,
+    get_mmr_latest_or_proof, get_mmr_proof, get_mmr_root, get_mmr_shard, get_mmr_shard_of_block,
+    get_mmr_shard_proof, get_mmr_shard_root, get_mmr_shards, get_transaction, post_mmr_proofs,
+    post_mmr_verify,
+};
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::{sync::Arc, time::Duration};
 
 use anyhow::Result;
-use axum::{routing::get, Router};
+use axum::{
+    routing::{get, post},
+    Router,
+};
 
-use log::info;
+use tracing::info;
 
 use tokio::{net::TcpListener, time::sleep};
 
 mod handlers;
 
-pub async fn initialize_router(should_terminate: Arc<AtomicBool>) -> Result<()> {
+pub async fn initialize_router(indexer: Indexer, should_terminate: Arc<AtomicBool>) -> Result<()> {
     let app = Router::new()
-        .route("/", get(|| async { "Healthy" }))
+        .route("/", get(get_health_live))
+        .route("/health/live", get(get_health_live))
+        .route("/health/ready", get(get_health_ready))
+        .route("/metrics", get(get_metrics))
+        .route("/blocks", get(get_blocks))
+        .route("/blocks/:number", get(get_block))
+        .route("/blocks/hash/:hash", get(get_block_by_hash))
+        .route("/blocks/:number/transactions", get(get_block_transactions))
+        .route("/transactions/:hash", get(get_transaction))
+        .route(
+            "/addresses/:address/transactions",
+            get(get_address_transactions),
+        )
         .route("/mmr", get(get_mmr_latest))
-        .route("/mmr/:blocknumber", get(get_mmr_proof));
+        .route("/mmr/proofs", post(post_mmr_proofs))
+        .route("/mmr/consistency", get(get_mmr_consistency))
+        .route("/mmr/verify", post(post_mmr_verify))
+        .route("/mmr/roots/:blocknumber", get(get_mmr_root))
+        .route("/mmr/shards", get(get_mmr_shards))
+        .route(
+            "/mmr/shards/block/:blocknumber",
+            get(get_mmr_shard_of_block),
+        )
+        .route("/mmr/shards/:shard", get(get_mmr_shard))
+        .route(
+            "/mmr/shards/:shard/roots/:blocknumber",
+            get(get_mmr_shard_root),
+        )
+        .route("/mmr/shards/:shard/:blocknumber", get(get_mmr_shard_proof))
+        // Either a hasher or, for the routes from before hashers were configurable, a block number
+        .route("/mmr/:hasher", get(get_mmr_latest_or_proof))
+        .route("/mmr/:hasher/proofs", post(post_mmr_proofs))
+        .route("/mmr/:hasher/consistency", get(get_mmr_consistency))
+        .route("/mmr/:hasher/verify", post(post_mmr_verify))
+        .route("/mmr/:hasher/roots/:blocknumber", get(get_mmr_root))
+        .route("/mmr/:hasher/shards", get(get_mmr_shards))
+        .route(
+            "/mmr/:hasher/shards/block/:blocknumber",
+            get(get_mmr_shard_of_block),
+        )
+        .route("/mmr/:hasher/shards/:shard", get(get_mmr_shard))
+        .route(
+            "/mmr/:hasher/shards/:shard/roots/:blocknumber",
+            get(get_mmr_shard_root),
+        )
+        .route(
+            "/mmr/:hasher/shards/:shard/:blocknumber",
+            get(get_mmr_shard_proof),
+        )
+        .route("/mmr/:hasher/:blocknumber", get(get_mmr_proof))
+        .with_state(indexer.clone());
 
-    let listener: TcpListener =
-        TcpListener::bind(dotenvy::var("ROUTER_ENDPOINT").expect("ROUTER_ENDPOINT must be set"))
-            .await?;
+    let listener: TcpListener = TcpListener::bind(&indexer.config().router.endpoint).await?;
 
     info!("->> LISTENING on {}\n", listener.local_addr().unwrap());
     axum::serve(listener, app.into_make_service())
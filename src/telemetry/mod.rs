@@ -0,0 +1,75 @@
+use anyhow::{Context, Result};
+use opentelemetry::trace::TracerProvider as _;
+use opentelemetry_otlp::{SpanExporter, WithExportConfig};
+use opentelemetry_sdk::{trace::SdkTracerProvider, Resource};
+use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt, EnvFilter, Layer};
+
+use crate::config::{LogFormat, TelemetryConfig};
+
+/**
+ * Keeps the span exporter alive. Dropping it flushes the spans that are still buffered
+ */
+pub struct TelemetryGuard {
+    tracer_provider: Option<SdkTracerProvider>,
+}
+
+impl Drop for TelemetryGuard {
+    fn drop(&mut self) {
+        if let Some(provider) = self.tracer_provider.take() {
+            if let Err(e) = provider.shutdown() {
+                eprintln!("Failed to flush spans: {e}");
+            }
+        }
+    }
+}
+
+/**
+ * Installs the global subscriber: text or JSON logs on stdout, plus OTLP span export when an endpoint is set.
+ * Records of the `log` crate (e.g. sqlx slow statements) are forwarded to the same subscriber.
+ */
+pub fn init(config: &TelemetryConfig) -> Result<TelemetryGuard> {
+    let filter = EnvFilter::try_from_default_env()
+        .or_else(|_| EnvFilter::try_new(&config.filter))
+        .context("Invalid telemetry.filter")?;
+
+    let fmt_layer = match config.format {
+        LogFormat::Text => tracing_subscriber::fmt::layer().boxed(),
+        LogFormat::Json => tracing_subscriber::fmt::layer()
+            .json()
+            .with_current_span(true)
+            .with_span_list(true)
+            .boxed(),
+    };
+
+    let tracer_provider = if config.otlp_endpoint.is_empty() {
+        None
+    } else {
+        let exporter = SpanExporter::builder()
+            .with_http()
+            .with_endpoint(&config.otlp_endpoint)
+            .build()
+            .context("Failed to build OTLP span exporter")?;
+        Some(
+            SdkTracerProvider::builder()
+                .with_batch_exporter(exporter)
+                .with_resource(
+                    Resource::builder()
+                        .with_service_name(config.service_name.clone())
+                        .build(),
+                )
+                .build(),
+        )
+    };
+    let otel_layer = tracer_provider.as_ref().map(|provider| {
+        tracing_opentelemetry::layer().with_tracer(provider.tracer(config.service_name.clone()))
+    });
+
+    tracing_subscriber::registry()
+        .with(filter)
+        .with(fmt_layer)
+        .with(otel_layer)
+        .try_init()
+        .context("Failed to install tracing subscriber")?;
+
+    Ok(TelemetryGuard { tracer_provider })
+}
@@ -0,0 +1,397 @@
+use anyhow::{bail, Context, Result};
+use rlp::RlpStream;
+use tiny_keccak::{Hasher, Keccak};
+
+use super::StoredBlockHeader;
+
+enum Field<'a> {
+    /// Hex string encoded as is: hashes, addresses, bloom, extra data, nonce
+    Bytes(&'a str),
+    /// Hex quantity encoded as a big-endian integer without leading zeros
+    Quantity(&'a str),
+    Integer(i64),
+}
+
+/**
+ * RLP encoding of a stored header, whose keccak256 is the block hash. The fields a fork added are appended if the
+ * block has them, in fork order: base fee (London), withdrawals root (Shanghai), blob gas used, excess blob gas
+ * and parent beacon block root (Cancun), requests hash (Prague).
+ *
+ * @Returns None if the block was stored before the header fields were recorded
+ */
+pub fn encode_header(header: &StoredBlockHeader) -> Result<Option<Vec<u8>>> {
+    let Some(encoded) = encode_fields(header)? else {
+        return Ok(None);
+    };
+
+    let block_hash = format!("0x{}", hex::encode(keccak256(&encoded)));
+    if header.block_hash.as_deref() != Some(block_hash.as_str()) {
+        bail!(
+            "Header RLP of block {} hashes to {block_hash}, not to the stored block hash",
+            header.number
+        );
+    }
+    Ok(Some(encoded))
+}
+
+/**
+ * RLP encoding of the header fields, whatever the block hash
+ */
+pub(crate) fn encode_fields(header: &StoredBlockHeader) -> Result<Option<Vec<u8>>> {
+    let Some(fields) = header_fields(header) else {
+        return Ok(None);
+    };
+
+    let fork_fields = [
+        header.base_fee_per_gas.as_deref().map(Field::Quantity),
+        header.withdrawals_root.as_deref().map(Field::Bytes),
+        header.blob_gas_used.as_deref().map(Field::Quantity),
+        header.excess_blob_gas.as_deref().map(Field::Quantity),
+        header.parent_beacon_block_root.as_deref().map(Field::Bytes),
+        header.requests_hash.as_deref().map(Field::Bytes),
+    ];
+    let fork_fields_count = fork_fields
+        .iter()
+        .take_while(|field| field.is_some())
+        .count();
+    if fork_fields[fork_fields_count..]
+        .iter()
+        .any(|field| field.is_some())
+    {
+        bail!(
+            "Block {} has fork fields without those of the forks before",
+            header.number
+        );
+    }
+
+    let mut stream = RlpStream::new_list(fields.len() + fork_fields_count);
+    for field in fields.iter().chain(fork_fields.iter().flatten()) {
+        match field {
+            Field::Bytes(value) => stream.append(&hex_bytes(value)?),
+            Field::Quantity(value) => stream.append(&quantity_bytes(value)?),
+            Field::Integer(value) => stream.append(&u64::try_from(*value)?),
+        };
+    }
+    Ok(Some(stream.out().to_vec()))
+}
+
+/**
+ * Fields every header has, in encoding order
+ */
+fn header_fields(header: &StoredBlockHeader) -> Option<Vec<Field<'_>>> {
+    Some(vec![
+        Field::Bytes(header.parent_hash.as_deref()?),
+        Field::Bytes(header.sha3_uncles.as_deref()?),
+        Field::Bytes(header.miner.as_deref()?),
+        Field::Bytes(header.state_root.as_deref()?),
+        Field::Bytes(header.transaction_root.as_deref()?),
+        Field::Bytes(header.receipts_root.as_deref()?),
+        Field::Bytes(header.logs_bloom.as_deref()?),
+        Field::Quantity(header.difficulty.as_deref()?),
+        Field::Integer(header.number),
+        Field::Integer(header.gas_limit),
+        Field::Integer(header.gas_used),
+        Field::Integer(header.timestamp?),
+        Field::Bytes(header.extra_data.as_deref()?),
+        Field::Bytes(header.mix_hash.as_deref()?),
+        Field::Bytes(&header.nonce),
+    ])
+}
+
+pub fn keccak256(bytes: &[u8]) -> [u8; 32] {
+    let mut keccak = Keccak::v256();
+    keccak.update(bytes);
+    let mut output = [0u8; 32];
+    keccak.finalize(&mut output);
+    output
+}
+
+fn hex_bytes(value: &str) -> Result<Vec<u8>> {
+    let digits = value.strip_prefix("0x").unwrap_or(value);
+    hex::decode(digits).with_context(|| format!("Invalid hex {value}"))
+}
+
+fn quantity_bytes(value: &str) -> Result<Vec<u8>> {
+    let digits = value.strip_prefix("0x").unwrap_or(value);
+    let padded = if digits.len() % 2 == 1 {
+        format!("0{digits}")
+    } else {
+        digits.to_string()
+    };
+    let bytes = hex::decode(&padded).with_context(|| format!("Invalid quantity {value}"))?;
+    let leading_zeros = bytes.iter().take_while(|&&byte| byte == 0).count();
+    Ok(bytes[leading_zeros..].to_vec())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const EMPTY_UNCLES: &str = "0x1dcc4de8dec75d7aab85b567b6ccd41ad312451b948a7413f0a142fd40d49347";
+    const EMPTY_TRIE: &str = "0x56e81f171bcc55a6ff8345e692c0f86e5b48e01b996cadc001622fb5e363b421";
+
+    fn repeated(byte: &str, count: usize) -> String {
+        format!("0x{}", byte.repeat(count))
+    }
+
+    /**
+     * Mainnet genesis
+     */
+    fn genesis() -> StoredBlockHeader {
+        StoredBlockHeader {
+            block_hash: Some(
+                "0xd4e56740f876aef8c010b86a40d5f56745a118d0906a34e69aec8c0db1cb8fa3".to_string(),
+            ),
+            number: 0,
+            gas_limit: 5000,
+            gas_used: 0,
+            base_fee_per_gas: None,
+            nonce: "0x0000000000000042".to_string(),
+            transaction_root: Some(EMPTY_TRIE.to_string()),
+            receipts_root: Some(EMPTY_TRIE.to_string()),
+            state_root: Some(
+                "0xd7f8974fb5ac78d9ac099b9ad5018bedc2ce0a72dad1827a1709da30580f0544".to_string(),
+            ),
+            parent_hash: Some(repeated("00", 32)),
+            mmr_leaf_index: None,
+            sha3_uncles: Some(EMPTY_UNCLES.to_string()),
+            miner: Some(repeated("00", 20)),
+            logs_bloom: Some(repeated("00", 256)),
+            difficulty: Some("0x400000000".to_string()),
+            timestamp: Some(0),
+            extra_data: Some(
+                "0x11bbe8db4e347b4e8c937c1c8370e4b5ed33adb3db69cbdb7a38e1e50b1b82fa".to_string(),
+            ),
+            mix_hash: Some(repeated("00", 32)),
+            withdrawals_root: None,
+            blob_gas_used: None,
+            excess_blob_gas: None,
+            parent_beacon_block_root: None,
+            requests_hash: None,
+        }
+    }
+
+    /**
+     * Mainnet block 1
+     */
+    fn block_1() -> StoredBlockHeader {
+        StoredBlockHeader {
+            block_hash: Some(
+                "0x88e96d4537bea4d9c05d12549907b32561d3bf31f45aae734cdc119f13406cb6".to_string(),
+            ),
+            number: 1,
+            nonce: "0x539bd4979fef1ec4".to_string(),
+            state_root: Some(
+                "0xd67e4d450343046425ae4271474353857ab860dbc0a1dde64b41b5cd3a532bf3".to_string(),
+            ),
+            parent_hash: genesis().block_hash,
+            miner: Some("0x05a56e2d52c817161883f50c441c3228cfe54d9f".to_string()),
+            difficulty: Some("0x3ff800000".to_string()),
+            timestamp: Some(1438269988),
+            extra_data: Some("0x476574682f76312e302e302f6c696e75782f676f312e342e32".to_string()),
+            mix_hash: Some(
+                "0x969b900de27b6ac6a67742365dd65f55a0526c41fd18e1b16f1a1215c2e66f59".to_string(),
+            ),
+            ..genesis()
+        }
+    }
+
+    /**
+     * Block of a local chain with proof of stake fields, which turns on the forks one after the other. Its hashes
+     * come from a separate RLP encoder and keccak256 implementation.
+     */
+    fn post_merge_block(number: i64, block_hash: &str, parent_hash: &str) -> StoredBlockHeader {
+        let (state_root, mix_hash) = match number {
+            5 => (
+                "0x3b96fc064fa874a80a132bda60bebf54efbc780a358fdcae4fbbd7e12b66b630",
+                "0xb5f2031eb62e37c6d38287b38f83afeaed2665f0424eb3c29c1a38a596a13d57",
+            ),
+            9 => (
+                "0xe72d310dbb213f4c2e34da28935b38905332ee3628a04df2dd13859fd769c6c5",
+                "0xb3af560ffaf071918d8d64d6201533061cc1f9a76c6a2264b670fc1f0032f0d6",
+            ),
+            11 => (
+                "0x568db421693629b25e9eb5597365e4e862638d29dcc0bb03f4085ebf2d5afd6b",
+                "0xbb85e451e092b31aedb250d1883a6dd8f7f8de1e302fa7167ab7f9044b0f8040",
+            ),
+            _ => (
+                "0x40ba25ddfe4dad6bdf0898a0da4490a30b945da8f15ed6df7a9b51d2519bc69f",
+                "0xe3011a6950ece5ea44c0419cd907bf241224df0da3b4ad5d0129b9b09f18a429",
+            ),
+        };
+        StoredBlockHeader {
+            block_hash: Some(block_hash.to_string()),
+            number,
+            gas_limit: 30_000_000,
+            gas_used: 21_000 * (number % 3),
+            base_fee_per_gas: Some(format!("{:#x}", 7 + number)),
+            nonce: "0x0000000000000000".to_string(),
+            transaction_root: Some(repeated("33", 32)),
+            receipts_root: Some(repeated("11", 32)),
+            state_root: Some(state_root.to_string()),
+            parent_hash: Some(parent_hash.to_string()),
+            mmr_leaf_index: None,
+            sha3_uncles: Some(EMPTY_UNCLES.to_string()),
+            miner: Some(format!("0x{:040x}", number * 7919)),
+            logs_bloom: Some(repeated(&format!("{number:02x}"), 256)),
+            difficulty: Some("0x0".to_string()),
+            timestamp: Some(1_700_000_000 + 12 * number),
+            extra_data: Some(repeated("ab", 3 * number as usize)),
+            mix_hash: Some(mix_hash.to_string()),
+            withdrawals_root: None,
+            blob_gas_used: None,
+            excess_blob_gas: None,
+            parent_beacon_block_root: None,
+            requests_hash: None,
+        }
+    }
+
+    fn london() -> StoredBlockHeader {
+        post_merge_block(
+            5,
+            "0x7d76088da7122daf929300033c92e0b99bfa2947b603af71c3809cf0b94f3387",
+            "0xb3a0906dbd1ce67836f5989d7a0c693e4bb9f110d5fe8174c64fb20959f94c7a",
+        )
+    }
+
+    fn shanghai() -> StoredBlockHeader {
+        StoredBlockHeader {
+            withdrawals_root: Some(repeated("66", 32)),
+            ..post_merge_block(
+                9,
+                "0x172e3a1c365301cfe57c3a04c5347a99c4976bc03e6dd4883215d53a1901e923",
+                "0x0180b1624401b202b9d916360c14e50ae6315dbe8a8855bbeba20946497a100a",
+            )
+        }
+    }
+
+    fn cancun() -> StoredBlockHeader {
+        StoredBlockHeader {
+            withdrawals_root: Some(repeated("66", 32)),
+            blob_gas_used: Some("0x20000".to_string()),
+            excess_blob_gas: Some("0x0".to_string()),
+            parent_beacon_block_root: Some(
+                "0x8101d16893f3425fbb4ff727e5502764784208aadf2df3acfab20162f7c74fc7".to_string(),
+            ),
+            ..post_merge_block(
+                11,
+                "0xaf744afc270e2a0fa4eb7673c71bba8c3b1c4ed15b850d0a2b3cdd7730eec887",
+                "0x2edae3e66de9043738a75a41589b47e834319df45f0d311a982da754f2035435",
+            )
+        }
+    }
+
+    fn prague() -> StoredBlockHeader {
+        StoredBlockHeader {
+            withdrawals_root: Some(repeated("66", 32)),
+            blob_gas_used: Some("0x20000".to_string()),
+            excess_blob_gas: Some("0x0".to_string()),
+            parent_beacon_block_root: Some(
+                "0x40d674998c07527b12a31e2963b0603a34bb46ba1dbfdd51220a010f3b364d94".to_string(),
+            ),
+            requests_hash: Some(
+                "0xe3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855".to_string(),
+            ),
+            ..post_merge_block(
+                15,
+                "0xc64ed7ff32fa12f5c0a9dd06423d268dc2e717b63d5cb424642993d560f9f56b",
+                "0xbec17699f68db115d3a523eff3028e5d899ef890db3098e0414be2f155c01db2",
+            )
+        }
+    }
+
+    fn encoded_hash(header: &StoredBlockHeader) -> String {
+        let encoded = encode_header(header).unwrap().unwrap();
+        format!("0x{}", hex::encode(keccak256(&encoded)))
+    }
+
+    #[test]
+    fn keccak256_of_nothing() {
+        assert_eq!(
+            hex::encode(keccak256(&[])),
+            "c5d2460186f7233c927e7db2dcc703c0e500b653ca82273b7bfad8045d85a470"
+        );
+    }
+
+    #[test]
+    fn encodes_headers_of_every_fork() {
+        for header in [
+            genesis(),
+            block_1(),
+            london(),
+            shanghai(),
+            cancun(),
+            prague(),
+        ] {
+            assert_eq!(
+                Some(encoded_hash(&header)),
+                header.block_hash,
+                "block {}",
+                header.number
+            );
+        }
+    }
+
+    #[test]
+    fn encodes_fork_fields_in_order() {
+        // The list header, then the fork fields as the last items
+        let encoded = encode_header(&prague()).unwrap().unwrap();
+        let tail = hex::encode(&encoded[encoded.len() - 33 * 2 - 4 - 1..]);
+        assert!(tail.starts_with("8302000080a0"), "{tail}");
+        assert!(tail.ends_with("e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"));
+        assert_eq!(rlp::Rlp::new(&encoded).item_count().unwrap(), 21);
+        assert_eq!(
+            rlp::Rlp::new(&encode_header(&genesis()).unwrap().unwrap())
+                .item_count()
+                .unwrap(),
+            15
+        );
+    }
+
+    #[test]
+    fn rejects_a_missing_middle_fork_field() {
+        let mut missing_withdrawals_root = cancun();
+        missing_withdrawals_root.withdrawals_root = None;
+        let mut missing_excess_blob_gas = prague();
+        missing_excess_blob_gas.excess_blob_gas = None;
+        let mut missing_base_fee = shanghai();
+        missing_base_fee.base_fee_per_gas = None;
+        for header in [
+            missing_withdrawals_root,
+            missing_excess_blob_gas,
+            missing_base_fee,
+        ] {
+            assert!(encode_header(&header).is_err(), "block {}", header.number);
+        }
+    }
+
+    #[test]
+    fn rejects_headers_not_hashing_to_the_block_hash() {
+        // A missing trailing fork field encodes a shorter list, with another hash
+        let mut missing_requests_hash = prague();
+        missing_requests_hash.requests_hash = None;
+        let mut other_timestamp = london();
+        other_timestamp.timestamp = Some(1);
+        let mut other_block_hash = block_1();
+        other_block_hash.block_hash = genesis().block_hash;
+        for header in [missing_requests_hash, other_timestamp, other_block_hash] {
+            assert!(encode_header(&header).is_err(), "block {}", header.number);
+        }
+    }
+
+    #[test]
+    fn headers_stored_without_their_fields_have_no_encoding() {
+        let mut legacy = block_1();
+        legacy.logs_bloom = None;
+        legacy.timestamp = None;
+        assert!(encode_header(&legacy).unwrap().is_none());
+    }
+
+    #[test]
+    fn quantities_drop_leading_zeros() {
+        assert_eq!(quantity_bytes("0x0").unwrap(), Vec::<u8>::new());
+        assert_eq!(quantity_bytes("0x20000").unwrap(), vec![0x02, 0x00, 0x00]);
+        assert_eq!(quantity_bytes("0x0010").unwrap(), vec![0x10]);
+        assert!(quantity_bytes("0xzz").is_err());
+    }
+}
@@ -1,3 +1,4 @@
+pub mod header_rlp;
 pub mod type_utils;
 
 use std::collections::HashMap;
@@ -6,6 +7,9 @@ use accumulators::mmr::Proof;
 use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize, Serializer};
 
+use crate::config::MmrHasher;
+use type_utils::convert_hex_string_to_i64;
+
 #[derive(Debug, Deserialize)]
 pub struct Transaction {
     pub hash: String,
@@ -39,12 +43,40 @@ pub struct BlockHeaderWithEmptyTransaction {
     pub hash: String,
     pub nonce: Option<String>,
     pub number: String,
+    #[serde(rename(deserialize = "parentHash"))]
+    pub parent_hash: String,
     #[serde(rename(deserialize = "receiptsRoot"))]
     pub receipts_root: String,
     #[serde(rename(deserialize = "stateRoot"))]
     pub state_root: String,
     #[serde(rename(deserialize = "transactionsRoot"))]
     pub transactions_root: String,
+    #[serde(rename(deserialize = "sha3Uncles"))]
+    pub sha3_uncles: String,
+    pub miner: String,
+    #[serde(rename(deserialize = "logsBloom"))]
+    pub logs_bloom: String,
+    pub difficulty: String,
+    pub timestamp: String,
+    #[serde(rename(deserialize = "extraData"))]
+    pub extra_data: String,
+    #[serde(rename(deserialize = "mixHash"))]
+    pub mix_hash: String,
+    /// From Shanghai
+    #[serde(rename(deserialize = "withdrawalsRoot"))]
+    pub withdrawals_root: Option<String>,
+    /// From Cancun
+    #[serde(rename(deserialize = "blobGasUsed"))]
+    pub blob_gas_used: Option<String>,
+    /// From Cancun
+    #[serde(rename(deserialize = "excessBlobGas"))]
+    pub excess_blob_gas: Option<String>,
+    /// From Cancun
+    #[serde(rename(deserialize = "parentBeaconBlockRoot"))]
+    pub parent_beacon_block_root: Option<String>,
+    /// From Prague
+    #[serde(rename(deserialize = "requestsHash"))]
+    pub requests_hash: Option<String>,
 }
 
 #[derive(Debug, Deserialize)]
@@ -58,12 +90,40 @@ pub struct BlockHeaderWithFullTransaction {
     pub hash: String,
     pub nonce: Option<String>,
     pub number: String,
+    #[serde(rename(deserialize = "parentHash"))]
+    pub parent_hash: String,
     #[serde(rename(deserialize = "receiptsRoot"))]
     pub receipts_root: String,
     #[serde(rename(deserialize = "stateRoot"))]
     pub state_root: String,
     #[serde(rename(deserialize = "transactionsRoot"))]
     pub transactions_root: String,
+    #[serde(rename(deserialize = "sha3Uncles"))]
+    pub sha3_uncles: String,
+    pub miner: String,
+    #[serde(rename(deserialize = "logsBloom"))]
+    pub logs_bloom: String,
+    pub difficulty: String,
+    pub timestamp: String,
+    #[serde(rename(deserialize = "extraData"))]
+    pub extra_data: String,
+    #[serde(rename(deserialize = "mixHash"))]
+    pub mix_hash: String,
+    /// From Shanghai
+    #[serde(rename(deserialize = "withdrawalsRoot"))]
+    pub withdrawals_root: Option<String>,
+    /// From Cancun
+    #[serde(rename(deserialize = "blobGasUsed"))]
+    pub blob_gas_used: Option<String>,
+    /// From Cancun
+    #[serde(rename(deserialize = "excessBlobGas"))]
+    pub excess_blob_gas: Option<String>,
+    /// From Cancun
+    #[serde(rename(deserialize = "parentBeaconBlockRoot"))]
+    pub parent_beacon_block_root: Option<String>,
+    /// From Prague
+    #[serde(rename(deserialize = "requestsHash"))]
+    pub requests_hash: Option<String>,
     pub transactions: Vec<Transaction>,
 }
 
@@ -71,6 +131,129 @@ pub struct BlockHeaderWithFullTransaction {
 pub struct BlockDetails {
     pub block_hash: String,
     pub number: i64,
+    /// None for blocks stored before parent hashes were recorded
+    pub parent_hash: Option<String>,
+    pub verified: bool,
+}
+
+/**
+ * Blockheader as stored in the db, returned by the /blocks endpoints
+ */
+#[derive(Clone, Debug, Serialize, sqlx::FromRow)]
+pub struct StoredBlockHeader {
+    pub block_hash: Option<String>,
+    pub number: i64,
+    pub gas_limit: i64,
+    pub gas_used: i64,
+    pub base_fee_per_gas: Option<String>,
+    pub nonce: String,
+    pub transaction_root: Option<String>,
+    pub receipts_root: Option<String>,
+    pub state_root: Option<String>,
+    pub parent_hash: Option<String>,
+    /// Index of the block's leaf in the MMR, None until it is appended
+    pub mmr_leaf_index: Option<i64>,
+    /// The fields below are None for blocks stored before they were recorded. Fork fields are also None for blocks
+    /// before their fork.
+    pub sha3_uncles: Option<String>,
+    pub miner: Option<String>,
+    pub logs_bloom: Option<String>,
+    pub difficulty: Option<String>,
+    pub timestamp: Option<i64>,
+    pub extra_data: Option<String>,
+    pub mix_hash: Option<String>,
+    pub withdrawals_root: Option<String>,
+    pub blob_gas_used: Option<String>,
+    pub excess_blob_gas: Option<String>,
+    pub parent_beacon_block_root: Option<String>,
+    pub requests_hash: Option<String>,
+}
+
+/**
+ * Header as it is stored once written
+ */
+impl From<&BlockHeaderWithFullTransaction> for StoredBlockHeader {
+    fn from(header: &BlockHeaderWithFullTransaction) -> Self {
+        Self {
+            block_hash: Some(header.hash.clone()),
+            number: convert_hex_string_to_i64(&header.number),
+            gas_limit: convert_hex_string_to_i64(&header.gas_limit),
+            gas_used: convert_hex_string_to_i64(&header.gas_used),
+            base_fee_per_gas: header.base_fee_per_gas.clone(),
+            nonce: header.nonce.clone().unwrap_or_default(),
+            transaction_root: Some(header.transactions_root.clone()),
+            receipts_root: Some(header.receipts_root.clone()),
+            state_root: Some(header.state_root.clone()),
+            parent_hash: Some(header.parent_hash.clone()),
+            mmr_leaf_index: None,
+            sha3_uncles: Some(header.sha3_uncles.clone()),
+            miner: Some(header.miner.clone()),
+            logs_bloom: Some(header.logs_bloom.clone()),
+            difficulty: Some(header.difficulty.clone()),
+            timestamp: Some(convert_hex_string_to_i64(&header.timestamp)),
+            extra_data: Some(header.extra_data.clone()),
+            mix_hash: Some(header.mix_hash.clone()),
+            withdrawals_root: header.withdrawals_root.clone(),
+            blob_gas_used: header.blob_gas_used.clone(),
+            excess_blob_gas: header.excess_blob_gas.clone(),
+            parent_beacon_block_root: header.parent_beacon_block_root.clone(),
+            requests_hash: header.requests_hash.clone(),
+        }
+    }
+}
+
+/**
+ * Outcome of verifying stored blocks
+ */
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct BlockVerification {
+    pub verified_count: u64,
+    /// Blocks whose header does not hash to their block hash
+    pub invalid: Vec<i64>,
+    /// Stored parents that the next stored block does not link to, unverified since either of them is wrong
+    pub unlinked_parents: Vec<i64>,
+}
+
+/**
+ * Transaction as stored in the db, returned by the /transactions endpoints
+ */
+#[derive(Clone, Debug, Serialize, sqlx::FromRow)]
+pub struct StoredTransaction {
+    pub block_number: Option<i64>,
+    pub transaction_hash: String,
+    pub transaction_index: i32,
+    pub from_addr: Option<String>,
+    pub to_addr: Option<String>,
+    pub value: String,
+    pub gas_price: String,
+    pub max_priority_fee_per_gas: Option<String>,
+    pub max_fee_per_gas: Option<String>,
+    pub gas: String,
+    pub chain_id: Option<String>,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TransactionDirection {
+    From,
+    To,
+    #[default]
+    Any,
+}
+
+/**
+ * One page of a listing. next_cursor is passed back as the cursor parameter to get the next page
+ */
+#[derive(Serialize)]
+pub struct Page<T> {
+    pub items: Vec<T>,
+    pub next_cursor: Option<String>,
+}
+
+#[derive(Clone, Debug, sqlx::FromRow)]
+pub struct RangeLease {
+    pub range_start: i64,
+    pub range_end: i64,
 }
 
 #[derive(Clone, Serialize)]
@@ -80,6 +263,266 @@ pub struct Update {
     pub update_timestamp: DateTime<Utc>,
 }
 
+/**
+ * Root of the MMR right after block_number was appended
+ */
+#[derive(Clone, Debug, Serialize, sqlx::FromRow)]
+pub struct MmrRoot {
+    pub block_number: i64,
+    pub elements_count: i64,
+    pub root_hash: String,
+    pub created_at: DateTime<Utc>,
+}
+
+/**
+ * Blocks per shard and the shards opened so far, in shard order: block n is in shard n / shard_size
+ */
+#[derive(Clone, Debug, Serialize)]
+pub struct MmrShardIndex {
+    pub shard_size: i64,
+    pub shards: Vec<MmrShardInfo>,
+}
+
+/**
+ * The MMR of one shard, over blocks first_block..=last_block. Its leaf i is block first_block + i.
+ */
+#[derive(Clone, Debug, Serialize)]
+pub struct MmrShardInfo {
+    pub shard: u64,
+    pub first_block: i64,
+    pub last_block: i64,
+    pub mmr_id: String,
+    pub hasher: MmrHasher,
+    pub elements_count: usize,
+    /// None while nothing is appended to the shard
+    pub root_hash: Option<String>,
+    /// Whether every block of the shard is appended, after which its root no longer changes
+    pub complete: bool,
+}
+
+/**
+ * Result of checking every leaf of an MMR against the stored block hashes
+ */
+#[derive(Debug, Serialize)]
+pub struct MmrAuditReport {
+    pub hasher: MmrHasher,
+    /// Index of the shard for a shard MMR, None for the MMRs over the whole chain
+    pub shard: Option<u64>,
+    pub leaves_count: usize,
+    pub mismatches_count: usize,
+    pub first_divergent_leaf: Option<i64>,
+    /// The first mismatches found, in block order
+    pub mismatches: Vec<LeafMismatch>,
+}
+
+/**
+ * A leaf that is not the leaf hash of the stored block: leaf is None if it is missing from the MMR store,
+ * expected_leaf is None if the block is not stored
+ */
+#[derive(Debug, Serialize)]
+pub struct LeafMismatch {
+    pub block_number: i64,
+    pub leaf: Option<String>,
+    pub expected_leaf: Option<String>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum UpdaterState {
+    Idle,
+    Running,
+    Completed,
+    Failed,
+}
+
+#[derive(Serialize)]
+pub struct HealthCheck {
+    pub name: &'static str,
+    pub healthy: bool,
+    pub detail: String,
+}
+
+#[derive(Serialize)]
+pub struct HealthReport {
+    pub healthy: bool,
+    pub checks: Vec<HealthCheck>,
+}
+
+pub const PROOF_SCHEMA_VERSION: u32 = 1;
+
+/**
+ * Inclusion proof of a block hash in the MMR, as returned by the proof endpoints
+ */
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct MmrProof {
+    pub version: u32,
+    /// Proofs from before hashers were configurable have no hasher and are keccak
+    #[serde(default)]
+    pub hasher: MmrHasher,
+    pub block_number: i64,
+    pub block_hash: String,
+    pub leaf_index: usize,
+    pub element_index: usize,
+    pub element_hash: String,
+    pub siblings_hashes: Vec<String>,
+    pub peaks_hashes: Vec<String>,
+    pub elements_count: usize,
+    pub root: String,
+    /// Set for proofs in the MMR of a shard, whose leaf_index counts from the shard's first block
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub shard: Option<ProofShard>,
+    /// RLP-encoded header of the block, if requested: its keccak256 is block_hash
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub header_rlp: Option<String>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub struct ProofShard {
+    pub index: u64,
+    pub first_block: i64,
+}
+
+impl MmrProof {
+    /**
+     * Converts to the pre-versioning response shape, kept for older clients
+     */
+    pub fn to_legacy(&self) -> ProofWrapper {
+        ProofWrapper {
+            proof: Proof {
+                element_index: self.element_index,
+                element_hash: self.element_hash.clone(),
+                siblings_hashes: self.siblings_hashes.clone(),
+                peaks_hashes: self.peaks_hashes.clone(),
+                elements_count: self.elements_count,
+            },
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct ProofVerification {
+    pub valid: bool,
+    /// Root the proof was checked against
+    pub root: String,
+}
+
+/**
+ * Inclusion proofs of several blocks against one MMR size, with the peaks shared by all of them
+ */
+#[derive(Clone, Debug, Serialize)]
+pub struct MmrBatchProof {
+    pub version: u32,
+    pub hasher: MmrHasher,
+    pub elements_count: usize,
+    pub root: String,
+    pub peaks_hashes: Vec<String>,
+    pub proofs: Vec<MmrBatchProofItem>,
+}
+
+impl MmrBatchProof {
+    /**
+     * Splits into the proof of each block, as returned by the single proof endpoints
+     */
+    pub fn to_proofs(&self) -> Vec<MmrProof> {
+        self.proofs
+            .iter()
+            .map(|item| MmrProof {
+                version: self.version,
+                hasher: self.hasher,
+                block_number: item.block_number,
+                block_hash: item.block_hash.clone(),
+                leaf_index: item.leaf_index,
+                element_index: item.element_index,
+                element_hash: item.element_hash.clone(),
+                siblings_hashes: item.siblings_hashes.clone(),
+                peaks_hashes: self.peaks_hashes.clone(),
+                elements_count: self.elements_count,
+                root: self.root.clone(),
+                shard: None,
+                header_rlp: item.header_rlp.clone(),
+            })
+            .collect()
+    }
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct MmrBatchProofItem {
+    pub block_number: i64,
+    pub block_hash: String,
+    pub leaf_index: usize,
+    pub element_index: usize,
+    pub element_hash: String,
+    pub siblings_hashes: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub header_rlp: Option<String>,
+}
+
+/**
+ * Proof that the MMR at to_elements_count extends the MMR at from_elements_count. paths[i] holds the siblings
+ * hashing from_peaks_hashes[i] up to the peak containing it at to_elements_count.
+ */
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct MmrConsistencyProof {
+    pub version: u32,
+    /// Proofs from before hashers were configurable have no hasher and are keccak
+    #[serde(default)]
+    pub hasher: MmrHasher,
+    pub from_elements_count: usize,
+    pub to_elements_count: usize,
+    pub from_root: String,
+    pub to_root: String,
+    pub from_peaks_hashes: Vec<String>,
+    pub to_peaks_hashes: Vec<String>,
+    pub paths: Vec<Vec<String>>,
+}
+
+/**
+ * Shape proofs are returned in: the proof JSON, or the calldata of our verifier contracts
+ */
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ProofFormat {
+    #[default]
+    Json,
+    StarknetCalldata,
+    EvmAbi,
+}
+
+impl ProofFormat {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ProofFormat::Json => "json",
+            ProofFormat::StarknetCalldata => "starknet-calldata",
+            ProofFormat::EvmAbi => "evm-abi",
+        }
+    }
+}
+
+/**
+ * A proof encoded as verifier contract calldata
+ */
+#[derive(Clone, Debug, Serialize)]
+pub struct ProofCalldata {
+    pub format: ProofFormat,
+    pub hasher: MmrHasher,
+    pub block_number: i64,
+    pub elements_count: usize,
+    pub root: String,
+    pub calldata: Calldata,
+    /// RLP-encoded header of the block, if requested
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub header_rlp: Option<String>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+#[serde(untagged)]
+pub enum Calldata {
+    /// Felts of the Cairo verifier's arguments
+    Felts(Vec<String>),
+    /// Selector and ABI-encoded arguments of the Solidity verifier call
+    Bytes(String),
+}
+
 pub struct ProofWrapper {
     pub proof: Proof,
 }
@@ -110,3 +553,78 @@ impl Serialize for ProofWrapper {
         map.serialize(serializer)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn proof() -> MmrProof {
+        MmrProof {
+            version: PROOF_SCHEMA_VERSION,
+            hasher: MmrHasher::Keccak,
+            block_number: 3,
+            block_hash: "0x03".to_string(),
+            leaf_index: 3,
+            element_index: 5,
+            element_hash: "0x03".to_string(),
+            siblings_hashes: vec!["0x02".to_string(), "0x0a".to_string()],
+            peaks_hashes: vec!["0x07".to_string(), "0x26".to_string()],
+            elements_count: 39,
+            root: "0x27".to_string(),
+            shard: None,
+            header_rlp: None,
+        }
+    }
+
+    #[test]
+    fn proof_serializes_as_documented() {
+        let json = serde_json::to_string(&proof()).unwrap();
+        assert_eq!(
+            json,
+            concat!(
+                r#"{"version":1,"hasher":"keccak","block_number":3,"block_hash":"0x03","leaf_index":3,"#,
+                r#""element_index":5,"element_hash":"0x03","siblings_hashes":["0x02","0x0a"],"#,
+                r#""peaks_hashes":["0x07","0x26"],"elements_count":39,"root":"0x27"}"#
+            )
+        );
+
+        let proof = MmrProof {
+            shard: Some(ProofShard {
+                index: 1,
+                first_block: 16,
+            }),
+            header_rlp: Some("0xc0".to_string()),
+            ..proof()
+        };
+        let json = serde_json::to_string(&proof).unwrap();
+        assert!(json.ends_with(
+            r#""root":"0x27","shard":{"index":1,"first_block":16},"header_rlp":"0xc0"}"#
+        ));
+        let parsed: MmrProof = serde_json::from_str(&json).unwrap();
+        assert_eq!(parsed.shard, proof.shard);
+        assert_eq!(parsed.header_rlp, proof.header_rlp);
+    }
+
+    #[test]
+    fn legacy_proof_stringifies_numbers_and_arrays() {
+        let json = serde_json::to_value(proof().to_legacy()).unwrap();
+        assert_eq!(
+            json,
+            serde_json::json!({
+                "element_index": "5",
+                "element_hash": "0x03",
+                "sibling_hashes": r#"["0x02","0x0a"]"#,
+                "peaks_hashes": r#"["0x07","0x26"]"#,
+                "elements_count": "39",
+            })
+        );
+    }
+
+    #[test]
+    fn proofs_without_hasher_are_keccak() {
+        let mut json = serde_json::to_value(proof()).unwrap();
+        json.as_object_mut().unwrap().remove("hasher");
+        let parsed: MmrProof = serde_json::from_value(json).unwrap();
+        assert_eq!(parsed.hasher, MmrHasher::Keccak);
+    }
+}